@@ -0,0 +1,142 @@
+use std::cell::Cell;
+
+use fxhash::FxHashSet;
+
+use super::instruction::{Compare, Instr, Program, Type};
+
+/// Replaces the sequences of instructions that run the most with a single
+/// instruction doing the work of all of them, and drops the `Noop`s left by
+/// the peephole pass, so the VM dispatches less. Returns where `start` ended
+/// up.
+pub fn fuse(program: &mut Program, start: usize) -> usize {
+    let code = std::mem::take(&mut program.code);
+
+    // nothing may jump into the middle of a fused sequence
+    let mut targets: FxHashSet<usize> = code.iter().filter_map(Instr::jump_target).collect();
+    targets.extend(
+        program
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                Type::FuncPtr(start) => Some(*start),
+                _ => None,
+            }),
+    );
+
+    // where every instruction moved to, a dropped one moves to whatever
+    // comes after it
+    let mut map = vec![0; code.len() + 1];
+    let mut i = 0;
+    while i < code.len() {
+        map[i] = program.code.len();
+
+        if code[i] == Instr::Noop {
+            i += 1;
+            continue;
+        }
+
+        match superinstruction(&code[i..]) {
+            Some((fused, len)) if (i + 1..i + len).all(|j| !targets.contains(&j)) => {
+                map[i..i + len].fill(program.code.len());
+                program.code.push(fused);
+                i += len;
+            }
+            _ => {
+                program.code.push(code[i].clone());
+                i += 1;
+            }
+        }
+    }
+    map[code.len()] = program.code.len();
+
+    for instruction in program.code.iter_mut() {
+        match instruction {
+            Instr::Jump(to)
+            | Instr::JumpIf(to)
+            | Instr::JumpIfNot(to)
+            | Instr::JumpIfOrPop(to)
+            | Instr::JumpIfNotOrPop(to)
+            | Instr::JumpIfNotNoneOrPop(to)
+            | Instr::JumpIfNone(to) => *to = map[*to],
+            Instr::CompareJumpLocalConst { to, .. } => *to = map[*to as usize] as u32,
+            Instr::Function { end, .. } => *end = map[*end as usize] as u32,
+            _ => {}
+        }
+    }
+    program.constants.relocate(&map);
+
+    map[start]
+}
+
+/// The instruction that can replace the sequence `code` starts with, and how
+/// long that sequence is.
+fn superinstruction(code: &[Instr]) -> Option<(Instr, usize)> {
+    Some(match code {
+        [Instr::LoadLocal(local), Instr::PushConst(constant), compare, jump, ..] => (
+            Instr::CompareJumpLocalConst {
+                local: *local as u32,
+                constant: *constant,
+                op: Compare::of(compare)?,
+                jump_if: matches!(jump, Instr::JumpIf(_)),
+                to: match jump {
+                    Instr::JumpIf(to) | Instr::JumpIfNot(to) => *to as u32,
+                    _ => return None,
+                },
+            },
+            4,
+        ),
+        [Instr::LoadGlobal(id), Instr::Call(argc), ..] => (
+            Instr::CallGlobal {
+                global: *id as u32,
+                argc: u16::try_from(*argc).ok()?,
+                version: Cell::new(0),
+                to: Cell::new(0),
+            },
+            2,
+        ),
+        [Instr::PushConst(constant), Instr::Add, ..] => (Instr::AddConst(*constant), 2),
+        [Instr::PushConst(constant), Instr::Sub, ..] => (Instr::SubConst(*constant), 2),
+        [Instr::LoadLocal(a), Instr::LoadLocal(b), ..] => {
+            (Instr::LoadLocal2(*a as u32, *b as u32), 2)
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::backend::{module::Loader, vm::VM};
+    use crate::frontend::parser;
+
+    const LOOP: &str = "fn sum() {\nlet i = 0\nlet total = 0\nwhile (i < 10) { total += i\ni += 1 }\nreturn total\n}\nlet result = sum()";
+
+    fn run(fuse: bool) -> (Vec<Instr>, Type) {
+        let mut loader = Loader::default();
+        loader.fuse = fuse;
+        let ast = parser::parse_code(LOOP).unwrap();
+        let (program, _) = Instr::compile_with(ast, Path::new("<main>"), &mut loader).unwrap();
+
+        let mut vm = VM::new();
+        vm.load(program);
+        vm.run();
+        let result = vm.heap.get(vm.heap.len() - 1).clone();
+        (vm.program, result)
+    }
+
+    #[test]
+    fn fuses_without_changing_results() {
+        let fused = |code: &[Instr]| {
+            code.iter()
+                .any(|instruction| matches!(instruction, Instr::CompareJumpLocalConst { .. }))
+        };
+        let (plain, expected) = run(false);
+        let (code, actual) = run(true);
+        assert!(!fused(&plain) && fused(&code));
+        assert!(code.len() < plain.len());
+        assert_eq!(expected, Type::Int(45));
+        assert_eq!(actual, expected);
+    }
+}
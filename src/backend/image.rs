@@ -0,0 +1,642 @@
+use std::{
+    cell::Cell,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use fxhash::FxHashMap;
+use num_bigint::BigInt;
+
+use crate::{
+    frontend::{Align, FormatKind, FormatSpec},
+    stdlib::NativeFunction,
+};
+
+use super::{
+    instruction::{Compare, EnumDef, Instr, Program, Type},
+    module::Module,
+};
+
+// A compiled program as bytes. `glass build --standalone` appends one to a
+// copy of the runner, followed by its length and `MAGIC`, which the runner
+// looks for when it starts.
+const MAGIC: &[u8; 8] = b"glassimg";
+// images are only ever read by the binary that wrote them, but a stale one
+// should still be noticed
+const VERSION: u8 = 4;
+
+// instructions without operands, numbered by where they are in here
+const SIMPLE: [Instr; 28] = [
+    Instr::Noop,
+    Instr::Halt,
+    Instr::Pop,
+    Instr::Return,
+    Instr::Add,
+    Instr::Sub,
+    Instr::Mul,
+    Instr::Div,
+    Instr::FloorDiv,
+    Instr::Mod,
+    Instr::Pow,
+    Instr::Neg,
+    Instr::Not,
+    Instr::Lt,
+    Instr::Gt,
+    Instr::Lte,
+    Instr::Gte,
+    Instr::Eq,
+    Instr::Neq,
+    Instr::BitAnd,
+    Instr::BitOr,
+    Instr::BitXor,
+    Instr::BitNot,
+    Instr::Shl,
+    Instr::Shr,
+    Instr::Index,
+    Instr::IndexStore,
+    Instr::Join,
+];
+
+const COMPARES: [Compare; 6] = [
+    Compare::Eq,
+    Compare::Neq,
+    Compare::Lt,
+    Compare::Gt,
+    Compare::Lte,
+    Compare::Gte,
+];
+
+/// The bytes of `program`.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes.extend(MAGIC);
+    w.u8(VERSION);
+
+    w.usize(program.constants.len());
+    for constant in program.constants.iter() {
+        w.value(constant);
+    }
+    w.usize(program.code.len());
+    for instruction in &program.code {
+        w.instruction(instruction);
+    }
+    w.bytes
+}
+
+/// The program `encode` turned into `bytes`, `None` if they aren't one.
+pub fn decode(bytes: &[u8]) -> Option<Program> {
+    let mut r = Reader {
+        bytes,
+        defs: vec![],
+    };
+    if r.take(MAGIC.len())? != MAGIC || r.u8()? != VERSION {
+        return None;
+    }
+
+    let constants = (0..r.usize()?)
+        .map(|_| r.value())
+        .collect::<Option<Vec<_>>>()?;
+    let code = (0..r.usize()?)
+        .map(|_| r.instruction())
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(Program {
+        code,
+        constants: constants.into(),
+    })
+}
+
+/// Writes a copy of the `runner` binary with `image` appended to `out`.
+pub fn embed(runner: &Path, image: &[u8], out: &Path) -> io::Result<()> {
+    let mut binary = fs::read(runner)?;
+    binary.extend(image);
+    binary.extend((image.len() as u64).to_le_bytes());
+    binary.extend(MAGIC);
+
+    fs::write(out, binary)?;
+    fs::set_permissions(out, fs::metadata(runner)?.permissions())
+}
+
+/// The image appended to `binary`, if there is one.
+pub fn embedded(binary: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = fs::File::open(binary)?;
+    let Some(len) = embedded_len(&mut file)? else {
+        return Ok(None);
+    };
+
+    let mut image = vec![0; len];
+    file.seek(SeekFrom::End(-16 - len as i64))?;
+    file.read_exact(&mut image)?;
+    Ok(Some(image))
+}
+
+fn embedded_len(binary: &mut (impl Read + Seek)) -> io::Result<Option<usize>> {
+    let size = binary.seek(SeekFrom::End(0))?;
+    if size < 16 {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; 16];
+    binary.seek(SeekFrom::End(-16))?;
+    binary.read_exact(&mut trailer)?;
+    if &trailer[8..] != MAGIC {
+        return Ok(None);
+    }
+
+    let len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    Ok((len <= size - 16).then_some(len as usize))
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    // enums are compared by identity, so each one is written once and
+    // referred to by its index after
+    defs: Vec<Rc<EnumDef>>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend(n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.bytes.extend(n.to_le_bytes());
+    }
+
+    fn usize(&mut self, n: usize) {
+        self.u64(n as u64);
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.u8(b as u8);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes.extend(s.as_bytes());
+    }
+
+    fn def(&mut self, def: &Rc<EnumDef>) {
+        if let Some(i) = self.defs.iter().position(|d| Rc::ptr_eq(d, def)) {
+            self.bool(true);
+            self.usize(i);
+            return;
+        }
+        self.defs.push(def.clone());
+
+        self.bool(false);
+        self.str(&def.name);
+        self.usize(def.variants.len());
+        for (name, arity) in &def.variants {
+            self.str(name);
+            self.usize(*arity);
+        }
+    }
+
+    fn value(&mut self, value: &Type) {
+        match value {
+            Type::Int(i) => {
+                self.u8(0);
+                self.u64(*i as u64);
+            }
+            Type::BigInt(i) => {
+                self.u8(1);
+                let bytes = i.to_signed_bytes_le();
+                self.usize(bytes.len());
+                self.bytes.extend(bytes);
+            }
+            Type::Number(n) => {
+                self.u8(2);
+                self.u64(n.to_bits());
+            }
+            Type::String(s) => {
+                self.u8(3);
+                self.str(s);
+            }
+            Type::Bool(b) => {
+                self.u8(4);
+                self.bool(*b);
+            }
+            Type::None => self.u8(5),
+            Type::Null => self.u8(6),
+            Type::Addr(addr) => {
+                self.u8(7);
+                self.usize(*addr);
+            }
+            Type::FuncPtr(start) => {
+                self.u8(8);
+                self.usize(*start);
+            }
+            Type::Enum(def) => {
+                self.u8(9);
+                self.def(def);
+            }
+            Type::Constructor(def, tag) => {
+                self.u8(10);
+                self.def(def);
+                self.u32(*tag);
+            }
+            // variants live on a VM's heap, the compiler never makes one
+            Type::Variant(_) => unreachable!("Variants are never constants"),
+            Type::Module(module) => {
+                self.u8(12);
+                self.str(&module.name);
+                self.str(&module.path.to_string_lossy());
+                self.usize(module.exports.len());
+                for (name, addr) in &module.exports {
+                    self.str(name);
+                    self.usize(*addr);
+                }
+            }
+            Type::Error(e) => {
+                self.u8(13);
+                self.str(e);
+            }
+        }
+    }
+
+    fn spec(&mut self, spec: &FormatSpec) {
+        self.u32(spec.fill as u32);
+        self.u8(match spec.align {
+            None => 0,
+            Some(Align::Left) => 1,
+            Some(Align::Right) => 2,
+            Some(Align::Center) => 3,
+        });
+        self.bool(spec.sign);
+        self.bool(spec.alternate);
+        self.bool(spec.zero);
+        self.usize(spec.width);
+        self.bool(spec.precision.is_some());
+        self.usize(spec.precision.unwrap_or(0));
+        self.u8(match spec.kind {
+            FormatKind::Display => 0,
+            FormatKind::Debug => 1,
+            FormatKind::LowerHex => 2,
+            FormatKind::UpperHex => 3,
+            FormatKind::Binary => 4,
+            FormatKind::Octal => 5,
+        });
+    }
+
+    fn instruction(&mut self, instruction: &Instr) {
+        if let Some(i) = SIMPLE.iter().position(|simple| simple == instruction) {
+            self.u8(i as u8);
+            return;
+        }
+
+        match instruction {
+            Instr::Reserve(n) => {
+                self.u8(100);
+                self.usize(*n);
+            }
+            Instr::LoadAddr(n) => {
+                self.u8(101);
+                self.usize(*n);
+            }
+            Instr::LoadLocal(n) => {
+                self.u8(102);
+                self.usize(*n);
+            }
+            Instr::LoadGlobal(n) => {
+                self.u8(103);
+                self.usize(*n);
+            }
+            Instr::StoreAddr(n) => {
+                self.u8(104);
+                self.usize(*n);
+            }
+            Instr::StoreLocal(n) => {
+                self.u8(105);
+                self.usize(*n);
+            }
+            Instr::StoreGlobal(n) => {
+                self.u8(106);
+                self.usize(*n);
+            }
+            Instr::PushConst(c) => {
+                self.u8(107);
+                self.u32(*c);
+            }
+            Instr::Jump(to) => {
+                self.u8(108);
+                self.usize(*to);
+            }
+            Instr::JumpIf(to) => {
+                self.u8(109);
+                self.usize(*to);
+            }
+            Instr::JumpIfNot(to) => {
+                self.u8(110);
+                self.usize(*to);
+            }
+            Instr::JumpIfOrPop(to) => {
+                self.u8(111);
+                self.usize(*to);
+            }
+            Instr::JumpIfNotOrPop(to) => {
+                self.u8(112);
+                self.usize(*to);
+            }
+            Instr::JumpIfNotNoneOrPop(to) => {
+                self.u8(113);
+                self.usize(*to);
+            }
+            Instr::JumpIfNone(to) => {
+                self.u8(114);
+                self.usize(*to);
+            }
+            Instr::NativeCall(function) => {
+                self.u8(115);
+                self.str(function.name());
+            }
+            Instr::JoinMany(n) => {
+                self.u8(116);
+                self.usize(*n);
+            }
+            Instr::Format(spec) => {
+                self.u8(117);
+                self.spec(spec);
+            }
+            Instr::Member(name) => {
+                self.u8(118);
+                self.u32(*name);
+            }
+            Instr::IsVariant(name) => {
+                self.u8(119);
+                self.u32(*name);
+            }
+            Instr::Field(i) => {
+                self.u8(120);
+                self.usize(*i);
+            }
+            Instr::AddConst(c) => {
+                self.u8(121);
+                self.u32(*c);
+            }
+            Instr::SubConst(c) => {
+                self.u8(122);
+                self.u32(*c);
+            }
+            Instr::CompareJumpLocalConst {
+                op,
+                jump_if,
+                local,
+                constant,
+                to,
+            } => {
+                self.u8(123);
+                self.u8(COMPARES.iter().position(|c| c == op).unwrap() as u8);
+                self.bool(*jump_if);
+                self.u32(*local);
+                self.u32(*constant);
+                self.u32(*to);
+            }
+            // the cache starts out empty again
+            Instr::CallGlobal { global, argc, .. } => {
+                self.u8(124);
+                self.u32(*global);
+                self.u32(*argc as u32);
+            }
+            Instr::LoadLocal2(a, b) => {
+                self.u8(125);
+                self.u32(*a);
+                self.u32(*b);
+            }
+            Instr::Call(argc) => {
+                self.u8(126);
+                self.usize(*argc);
+            }
+            Instr::Function { end, arity } => {
+                self.u8(127);
+                self.u32(*end);
+                self.u32(*arity);
+            }
+            simple => unreachable!("{:?} is missing from SIMPLE", simple),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    defs: Vec<Rc<EnumDef>>,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        if n > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?).ok()
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        Some(self.u8()? != 0)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn def(&mut self) -> Option<Rc<EnumDef>> {
+        if self.bool()? {
+            let i = self.usize()?;
+            return self.defs.get(i).cloned();
+        }
+
+        let name = self.string()?;
+        let variants = (0..self.usize()?)
+            .map(|_| Some((self.string()?, self.usize()?)))
+            .collect::<Option<_>>()?;
+        let def = Rc::new(EnumDef { name, variants });
+        self.defs.push(def.clone());
+        Some(def)
+    }
+
+    fn value(&mut self) -> Option<Type> {
+        Some(match self.u8()? {
+            0 => Type::Int(self.u64()? as i64),
+            1 => {
+                let len = self.usize()?;
+                Type::BigInt(Rc::new(BigInt::from_signed_bytes_le(self.take(len)?)))
+            }
+            2 => Type::Number(f64::from_bits(self.u64()?)),
+            3 => Type::String(Rc::new(self.string()?)),
+            4 => Type::Bool(self.bool()?),
+            5 => Type::None,
+            6 => Type::Null,
+            7 => Type::Addr(self.usize()?),
+            8 => Type::FuncPtr(self.usize()?),
+            9 => Type::Enum(self.def()?),
+            10 => Type::Constructor(self.def()?, self.u32()?),
+            12 => {
+                let name = self.string()?;
+                let path = PathBuf::from(self.string()?);
+                let exports = (0..self.usize()?)
+                    .map(|_| Some((self.string()?, self.usize()?)))
+                    .collect::<Option<FxHashMap<_, _>>>()?;
+                Type::Module(Rc::new(Module {
+                    name,
+                    path,
+                    exports,
+                }))
+            }
+            13 => Type::Error(Rc::new(self.string()?)),
+            _ => return None,
+        })
+    }
+
+    fn spec(&mut self) -> Option<FormatSpec> {
+        Some(FormatSpec {
+            fill: char::from_u32(self.u32()?)?,
+            align: match self.u8()? {
+                0 => None,
+                1 => Some(Align::Left),
+                2 => Some(Align::Right),
+                3 => Some(Align::Center),
+                _ => return None,
+            },
+            sign: self.bool()?,
+            alternate: self.bool()?,
+            zero: self.bool()?,
+            width: self.usize()?,
+            precision: {
+                let some = self.bool()?;
+                let precision = self.usize()?;
+                some.then_some(precision)
+            },
+            kind: match self.u8()? {
+                0 => FormatKind::Display,
+                1 => FormatKind::Debug,
+                2 => FormatKind::LowerHex,
+                3 => FormatKind::UpperHex,
+                4 => FormatKind::Binary,
+                5 => FormatKind::Octal,
+                _ => return None,
+            },
+        })
+    }
+
+    fn instruction(&mut self) -> Option<Instr> {
+        let tag = self.u8()?;
+        if let Some(simple) = SIMPLE.get(tag as usize) {
+            return Some(simple.clone());
+        }
+
+        Some(match tag {
+            100 => Instr::Reserve(self.usize()?),
+            101 => Instr::LoadAddr(self.usize()?),
+            102 => Instr::LoadLocal(self.usize()?),
+            103 => Instr::LoadGlobal(self.usize()?),
+            104 => Instr::StoreAddr(self.usize()?),
+            105 => Instr::StoreLocal(self.usize()?),
+            106 => Instr::StoreGlobal(self.usize()?),
+            107 => Instr::PushConst(self.u32()?),
+            108 => Instr::Jump(self.usize()?),
+            109 => Instr::JumpIf(self.usize()?),
+            110 => Instr::JumpIfNot(self.usize()?),
+            111 => Instr::JumpIfOrPop(self.usize()?),
+            112 => Instr::JumpIfNotOrPop(self.usize()?),
+            113 => Instr::JumpIfNotNoneOrPop(self.usize()?),
+            114 => Instr::JumpIfNone(self.usize()?),
+            115 => Instr::NativeCall(NativeFunction::from(&self.string()?)?),
+            116 => Instr::JoinMany(self.usize()?),
+            117 => Instr::Format(Box::new(self.spec()?)),
+            118 => Instr::Member(self.u32()?),
+            119 => Instr::IsVariant(self.u32()?),
+            120 => Instr::Field(self.usize()?),
+            121 => Instr::AddConst(self.u32()?),
+            122 => Instr::SubConst(self.u32()?),
+            123 => Instr::CompareJumpLocalConst {
+                op: *COMPARES.get(self.u8()? as usize)?,
+                jump_if: self.bool()?,
+                local: self.u32()?,
+                constant: self.u32()?,
+                to: self.u32()?,
+            },
+            124 => Instr::CallGlobal {
+                global: self.u32()?,
+                argc: self.u32()?.try_into().ok()?,
+                version: Cell::new(0),
+                to: Cell::new(0),
+            },
+            125 => Instr::LoadLocal2(self.u32()?, self.u32()?),
+            126 => Instr::Call(self.usize()?),
+            127 => Instr::Function {
+                end: self.u32()?,
+                arity: self.u32()?,
+            },
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::parser;
+
+    fn program(code: &str) -> Program {
+        Instr::compile(parser::parse_code(code).unwrap()).0
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = program(
+            r#"
+            enum Shape { Circle(r), Empty }
+            fn area(s) {
+                match s {
+                    Shape.Circle(r) => { return 3 * r * r }
+                    _ => { return 0 }
+                }
+            }
+            print(f"{area(Shape.Circle(2 ** 70)):>8} {1.5} {"s"}")
+            "#,
+        );
+
+        let decoded = decode(&encode(&program)).expect("could not decode");
+        assert!(decoded.code == program.code);
+        assert_eq!(decoded.constants.len(), program.constants.len());
+        for (decoded, constant) in decoded.constants.iter().zip(program.constants.iter()) {
+            assert_eq!(decoded.repr(), constant.repr());
+        }
+    }
+
+    #[test]
+    fn rejects_other_bytes() {
+        let image = encode(&program("print(1)"));
+        assert!(decode(b"not an image").is_none());
+        assert!(decode(&image[..image.len() - 1]).is_none());
+
+        let mut newer = image.clone();
+        newer[MAGIC.len()] += 1;
+        assert!(decode(&newer).is_none());
+    }
+}
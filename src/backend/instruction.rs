@@ -1,17 +1,36 @@
 use crate::{
-    frontend::{Expr, Op, AST},
+    frontend::{Expr, FormatSpec, Op, Pattern, AST},
     stdlib::{add_std, NativeFunction},
 };
 // use hashbrown::HashMap;
-use fxhash::FxHashMap;
-use std::fmt;
+use fxhash::{FxHashMap, FxHashSet};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::{
+    cell::Cell,
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+    ops::{Deref, DerefMut},
+    path::Path,
+    rc::Rc,
+};
 
-use super::{memory::addr, stack::StackValue};
+use super::{
+    fuse::fuse,
+    memory::{addr, Gc},
+    module::{Loader, Module, ModuleError},
+};
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone)]
 pub enum Type {
+    Int(i64),
+    // only for integers that don't fit in an `Int`
+    BigInt(Rc<BigInt>),
     Number(f64),
-    String(String),
+    // shared, so passing a string around never copies it. An `Rc<String>`
+    // rather than an `Rc<str>` keeps the handle to one word
+    String(Rc<String>),
     Bool(bool),
     None,
 
@@ -20,34 +39,249 @@ pub enum Type {
     Addr(usize),
     FuncPtr(usize),
 
-    Error(String),
+    Enum(Rc<EnumDef>),
+    Constructor(Rc<EnumDef>, u32),
+    // on the heap, see `Memory::alloc`
+    Variant(Gc<Variant>),
+    Module(Rc<Module>),
+
+    Error(Rc<String>),
+}
+
+// every payload is a single word, so a value is two words wide
+const _: () = assert!(std::mem::size_of::<Type>() == 16);
+
+#[derive(Debug, PartialEq)]
+pub struct EnumDef {
+    pub name: String,
+    // (name, arity)
+    pub variants: Vec<(String, usize)>,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+    pub def: Rc<EnumDef>,
+    pub tag: usize,
+    pub fields: Vec<Type>,
+    // set while the collector marks what is reachable
+    pub marked: Cell<bool>,
+}
+
+impl Variant {
+    pub fn new(def: Rc<EnumDef>, tag: usize, fields: Vec<Type>) -> Variant {
+        Variant {
+            def,
+            tag,
+            fields,
+            marked: Cell::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.def.variants[self.tag].0
+    }
+
+    pub fn is(&self, def: &Rc<EnumDef>, name: &str) -> bool {
+        Rc::ptr_eq(&self.def, def) && self.name() == name
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.def.name, self.name())?;
+        if self.fields.is_empty() {
+            return Ok(());
+        }
+
+        write!(f, "(")?;
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", field.repr())?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int(i) => write!(f, "{}", i),
+            Type::BigInt(i) => write!(f, "{}", i),
+            // floats always show a fractional part, so `3.0` never reads as `3`
+            Type::Number(n) => write!(f, "{:?}", n),
+            Type::String(s) => write!(f, "{}", s),
+            Type::Bool(b) => write!(f, "{}", b),
+            Type::None => write!(f, "none"),
+            Type::Null => write!(f, "null"),
+            Type::Addr(a) => write!(f, "<addr={}>", a),
+            Type::FuncPtr(addr) => write!(f, "<function at={}>", addr),
+            Type::Enum(def) => write!(f, "<enum {}>", def.name),
+            Type::Constructor(def, tag) => {
+                write!(
+                    f,
+                    "<constructor {}.{}>",
+                    def.name, def.variants[*tag as usize].0
+                )
+            }
+            Type::Variant(v) => write!(f, "{}", v.get()),
+            Type::Module(m) => write!(f, "<module {}>", m.name),
+            Type::Error(e) => write!(f, "Error({})", e),
+        }
+    }
 }
 
 impl Type {
-    pub fn to_string(&self) -> String {
+    /// none, false, zero and the empty string are falsy, everything else is
+    /// truthy.
+    pub fn is_truthy(&self) -> bool {
         match self {
-            Type::Number(n) => n.to_string(),
-            Type::String(s) => s.clone(),
-            Type::Bool(b) => b.to_string(),
-            Type::None => "none".to_string(),
-            Type::Null => "null".to_string(),
-            Type::Addr(a) => format!("<addr={}>", a),
-            Type::FuncPtr(f) => format!("<function at={}>", f),
-            Type::Error(e) => format!("Error({})", e),
+            Type::None | Type::Null => false,
+            Type::Bool(b) => *b,
+            Type::Int(i) => *i != 0,
+            Type::Number(n) => *n != 0.0,
+            Type::String(s) => !s.is_empty(),
+            _ => true,
+        }
+    }
+    /// The name of the value's type, as error messages show it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Type::Int(_) | Type::BigInt(_) => "int",
+            Type::Number(_) => "float",
+            Type::String(_) => "string",
+            Type::Bool(_) => "bool",
+            Type::None => "none",
+            Type::Null => "null",
+            Type::Addr(_) => "address",
+            Type::FuncPtr(_) => "function",
+            Type::Enum(_) => "enum",
+            Type::Constructor(..) => "constructor",
+            Type::Variant(_) => "variant",
+            Type::Module(_) => "module",
+            Type::Error(_) => "error",
+        }
+    }
+
+    /// How the value reads in source, strings are quoted and escaped.
+    pub fn repr(&self) -> String {
+        match self {
+            Type::String(s) => format!("{:?}", s),
+            value => value.to_string(),
+        }
+    }
+}
+
+/// The one definition of equality, shared by `==`, `!=`, `match` and
+/// anything keyed by values. Numbers are equal when their values are, across
+/// ints and floats, and NaN is equal to itself so every value equals itself.
+/// Functions, enums and modules are equal only to themselves.
+impl PartialEq for Type {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Type::Int(lhs), Type::Int(rhs)) => lhs == rhs,
+            (Type::BigInt(lhs), Type::BigInt(rhs)) => lhs == rhs,
+            // both are normalized, a `BigInt` never fits in an `Int`
+            (Type::Int(_), Type::BigInt(_)) | (Type::BigInt(_), Type::Int(_)) => false,
+            (Type::Number(lhs), Type::Number(rhs)) => lhs == rhs || lhs.is_nan() && rhs.is_nan(),
+            (int @ (Type::Int(_) | Type::BigInt(_)), Type::Number(float))
+            | (Type::Number(float), int @ (Type::Int(_) | Type::BigInt(_))) => {
+                integral(*float).is_some_and(|float| float == *int)
+            }
+            (Type::String(lhs), Type::String(rhs)) => lhs == rhs,
+            (Type::Bool(lhs), Type::Bool(rhs)) => lhs == rhs,
+            (Type::None, Type::None) | (Type::Null, Type::Null) => true,
+            (Type::Addr(lhs), Type::Addr(rhs)) => lhs == rhs,
+            (Type::FuncPtr(lhs), Type::FuncPtr(rhs)) => lhs == rhs,
+            (Type::Enum(lhs), Type::Enum(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Type::Constructor(lhs, l), Type::Constructor(rhs, r)) => {
+                Rc::ptr_eq(lhs, rhs) && l == r
+            }
+            (Type::Variant(lhs), Type::Variant(rhs)) => lhs == rhs,
+            (Type::Module(lhs), Type::Module(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Type::Error(lhs), Type::Error(rhs)) => lhs == rhs,
+            _ => false,
         }
     }
 }
 
+impl Eq for Type {}
+
+/// Hashes agree with equality, so `1`, `1.0` and `-0.0 + 1` hash the same.
+impl Hash for Type {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // ints and floats share a hash whenever they are equal
+        if !matches!(self, Type::Int(_) | Type::BigInt(_) | Type::Number(_)) {
+            mem::discriminant(self).hash(state);
+        }
+
+        match self {
+            Type::Number(n) => match integral(*n) {
+                Some(int) => int.hash(state),
+                None if n.is_nan() => 0u8.hash(state),
+                None => n.to_bits().hash(state),
+            },
+            Type::Int(i) => i.hash(state),
+            Type::BigInt(i) => i.hash(state),
+            Type::String(s) => s.hash(state),
+            Type::Bool(b) => b.hash(state),
+            Type::None | Type::Null => {}
+            Type::Addr(addr) | Type::FuncPtr(addr) => addr.hash(state),
+            Type::Enum(def) => Rc::as_ptr(def).hash(state),
+            Type::Constructor(def, tag) => {
+                Rc::as_ptr(def).hash(state);
+                tag.hash(state);
+            }
+            Type::Variant(v) => {
+                let v = v.get();
+                Rc::as_ptr(&v.def).hash(state);
+                v.tag.hash(state);
+                v.fields.hash(state);
+            }
+            Type::Module(m) => Rc::as_ptr(m).hash(state),
+            Type::Error(e) => e.hash(state),
+        }
+    }
+}
+
+impl PartialEq for Variant {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.def, &other.def) && self.tag == other.tag && self.fields == other.fields
+    }
+}
+
+/// The integer a float stands for, when it has no fractional part.
+fn integral(float: f64) -> Option<Type> {
+    if float.fract() != 0.0 || !float.is_finite() {
+        return None;
+    }
+
+    let int = BigInt::from_f64(float)?;
+    match int.to_i64() {
+        Some(int) => Some(Type::Int(int)),
+        None => Some(Type::BigInt(Rc::new(int))),
+    }
+}
+
 impl fmt::Debug for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Type::Number(n) => write!(f, "num({})", n),
+            Type::Int(i) => write!(f, "int({})", i),
+            Type::BigInt(i) => write!(f, "bigint({})", i),
+            Type::Number(n) => write!(f, "num({:?})", n),
             Type::String(s) => write!(f, "str({:?})", s),
             Type::Bool(b) => write!(f, "bool({})", b),
             Type::None => write!(f, "none"),
             Type::Null => write!(f, "null"),
             Type::Addr(addr) => write!(f, "#{}", addr),
             Type::FuncPtr(addr) => write!(f, "fn(@{})", addr),
+            Type::Enum(def) => write!(f, "enum({})", def.name),
+            Type::Constructor(def, tag) => {
+                write!(f, "ctor({}.{})", def.name, def.variants[*tag as usize].0)
+            }
+            Type::Variant(v) => write!(f, "variant({})", v.get()),
+            Type::Module(m) => write!(f, "module({})", m.name),
             Type::Error(s) => write!(f, "Error({})", s),
         }
     }
@@ -61,6 +295,7 @@ pub enum Instr {
     Noop,
 
     Halt,
+    Reserve(usize),
 
     // Load(offset),
     // LoadDeref(offset),
@@ -74,13 +309,27 @@ pub enum Instr {
     StoreGlobal(offset),
 
     // Register(offset, addr),
-    Push(StackValue),
+    // pushes a value from the constant pool
+    PushConst(u32),
     Pop,
 
     Jump(usize),
+    // heads a function's code, which it jumps over to `end`. Calls read
+    // `arity` from it instead of counting the arguments the code stores
+    Function {
+        end: u32,
+        arity: u32,
+    },
     JumpIf(usize),
     JumpIfNot(usize),
-    Call,
+    // jump keeping the value on the stack, otherwise pop it
+    JumpIfOrPop(usize),
+    JumpIfNotOrPop(usize),
+    JumpIfNotNoneOrPop(usize),
+    // jump keeping the value on the stack, otherwise leave it be
+    JumpIfNone(usize),
+    // calls the value on top of the stack with the arguments under it
+    Call(usize),
     NativeCall(NativeFunction),
     Return,
 
@@ -88,43 +337,281 @@ pub enum Instr {
     Sub,
     Mul,
     Div,
+    FloorDiv,
     Mod,
     Pow,
     Neg,
     Not,
-    And,
-    Or,
     Lt,
     Gt,
     Lte,
     Gte,
     Eq,
     Neq,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
 
     Index,
     IndexStore,
     Join,
     JoinMany(usize),
+    Format(Box<FormatSpec>),
+
+    // names are string constants
+    Member(u32),
+    // checks the value on top against the enum below it
+    IsVariant(u32),
+    Field(usize),
+
+    // fused by `fuse` from the sequences that run most, see `VM::profile`
+    // PushConst; Add
+    AddConst(u32),
+    // PushConst; Sub
+    SubConst(u32),
+    // LoadLocal; PushConst; <compare>; JumpIf or JumpIfNot
+    CompareJumpLocalConst {
+        op: Compare,
+        jump_if: bool,
+        local: u32,
+        constant: u32,
+        to: u32,
+    },
+    // LoadGlobal; Call, caching the function the global held the last time
+    // along with the global's version then, see `VM::versions`. `argc` is
+    // only a u16 to keep instructions 16 bytes
+    CallGlobal {
+        global: u32,
+        argc: u16,
+        version: Cell<u32>,
+        to: Cell<u32>,
+    },
+    // LoadLocal; LoadLocal
+    LoadLocal2(u32, u32),
+}
+
+/// The comparison a `CompareJumpLocalConst` makes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compare {
+    Eq,
+    Neq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+}
+
+impl Compare {
+    pub fn of(instr: &Instr) -> Option<Compare> {
+        Some(match instr {
+            Instr::Eq => Compare::Eq,
+            Instr::Neq => Compare::Neq,
+            Instr::Lt => Compare::Lt,
+            Instr::Gt => Compare::Gt,
+            Instr::Lte => Compare::Lte,
+            Instr::Gte => Compare::Gte,
+            _ => return None,
+        })
+    }
+}
+
+const _: () = assert!(std::mem::size_of::<Instr>() == 16);
+
+/// The bytecode of a program along with the constants it pushes. Derefs to
+/// its instructions, so code generation can treat it as a list of them.
+#[derive(Default)]
+pub struct Program {
+    pub code: Vec<Instr>,
+    pub constants: Pool,
+}
+
+/// The constants of a program, each stored once.
+#[derive(Default)]
+pub struct Pool {
+    values: Vec<Type>,
+    pooled: FxHashMap<Constant, u32>,
+}
+
+/// Constants are pooled by what they are exactly, `1` and `1.0` or `0.0` and
+/// `-0.0` are equal but still different constants.
+#[derive(PartialEq, Eq, Hash)]
+enum Constant {
+    Number(u64),
+    Other(mem::Discriminant<Type>, Type),
+}
+
+impl Constant {
+    fn of(value: &Type) -> Constant {
+        match value {
+            Type::Number(n) => Constant::Number(n.to_bits()),
+            value => Constant::Other(mem::discriminant(value), value.clone()),
+        }
+    }
+}
+
+impl Pool {
+    /// The index of `value`, adding it if an identical constant isn't there
+    /// yet.
+    pub fn add(&mut self, value: Type) -> u32 {
+        *self.pooled.entry(Constant::of(&value)).or_insert_with(|| {
+            self.values.push(value);
+            (self.values.len() - 1) as u32
+        })
+    }
+
+    /// Points every function at `map[start]`, once the code was rearranged.
+    pub fn relocate(&mut self, map: &[usize]) {
+        for value in &mut self.values {
+            if let Type::FuncPtr(start) = value {
+                *start = map[*start];
+            }
+        }
+        self.repool();
+    }
+
+    fn repool(&mut self) {
+        self.pooled = (self.values.iter())
+            .enumerate()
+            .map(|(i, value)| (Constant::of(value), i as u32))
+            .collect();
+    }
+
+    pub fn into_values(self) -> Vec<Type> {
+        self.values
+    }
+
+    /// A table of every constant and its index.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+
+        out += "const#\tvalue\n";
+        out += "-------------------------\n";
+        for (i, constant) in self.values.iter().enumerate() {
+            out += &format!("#{}:\t{:?}\n", i, constant);
+        }
+        out += "-------------------------\n";
+        out
+    }
+}
+
+impl From<Vec<Type>> for Pool {
+    fn from(values: Vec<Type>) -> Pool {
+        let mut pool = Pool {
+            values,
+            pooled: FxHashMap::default(),
+        };
+        pool.repool();
+        pool
+    }
+}
+
+impl Deref for Pool {
+    type Target = [Type];
+
+    fn deref(&self) -> &[Type] {
+        &self.values
+    }
+}
+
+impl Program {
+    /// The index of `value` in the constant pool.
+    pub fn constant(&mut self, value: Type) -> u32 {
+        self.constants.add(value)
+    }
+
+    pub fn push_constant(&mut self, value: Type) {
+        let index = self.constant(value);
+        self.code.push(Instr::PushConst(index));
+    }
+
+    /// The constant pool, then the instructions from `start` on, with the
+    /// constant each one refers to.
+    pub fn disassemble(&self, start: usize) -> String {
+        let mut out = self.constants.disassemble();
+        out += "\n";
+
+        out += "ln#\topcode    \toffset/value\n";
+        out += "-------------------------\n";
+        for (i, instruction) in self.code.iter().enumerate().skip(start) {
+            out += &format!("{}:\t{:?}", i - start, instruction);
+            match instruction {
+                Instr::PushConst(c)
+                | Instr::Member(c)
+                | Instr::IsVariant(c)
+                | Instr::AddConst(c)
+                | Instr::SubConst(c)
+                | Instr::CompareJumpLocalConst { constant: c, .. } => {
+                    out += &format!("\t; {:?}", self.constants[*c as usize])
+                }
+                _ => {}
+            }
+            out += "\n";
+        }
+        out += "-------------------------\n";
+        out
+    }
+}
+
+impl Deref for Program {
+    type Target = Vec<Instr>;
+
+    fn deref(&self) -> &Vec<Instr> {
+        &self.code
+    }
+}
+
+impl DerefMut for Program {
+    fn deref_mut(&mut self) -> &mut Vec<Instr> {
+        &mut self.code
+    }
 }
 
 pub type State = FxHashMap<String, (usize, usize)>;
 
 impl Instr {
-    pub fn compile(ast: AST) -> (Vec<Instr>, usize) {
-        let mut program = vec![];
+    pub fn compile(ast: AST) -> (Program, usize) {
+        Self::compile_with(ast, Path::new("<main>"), &mut Loader::default())
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn compile_file(path: &Path, loader: &mut Loader) -> Result<(Program, usize), ModuleError> {
+        let ast = loader.parse(path)?;
+        Self::compile_with(ast, path, loader)
+    }
+
+    pub fn compile_with(
+        ast: AST,
+        path: &Path,
+        loader: &mut Loader,
+    ) -> Result<(Program, usize), ModuleError> {
+        let mut program = Program::default();
+        program.push(Instr::Noop); // placeholder for Reserve
         let mut state = FxHashMap::default();
         let mut next = 0;
 
         // add_std(&mut program, &mut state, 0, &mut next);
         add_std(&mut program, &mut state, 0, &mut next);
+        loader.prelude = state.clone();
+
         let prog_start = program.len();
-        Self::iter_build(&mut program, ast, &mut state, 0, &mut next);
+        loader.enter(path);
+        Self::build_module(&mut program, ast, path, &mut state, &mut next, loader)?;
+        loader.exit();
+
+        // every module shares the global address space
+        program[0] = Instr::Reserve(next);
+
+        // a value jumped over to a `Pop` is still popped there
+        let targets: FxHashSet<usize> = program.iter().filter_map(Instr::jump_target).collect();
 
         let mut last = None;
-        for (i, op) in program.clone().into_iter().enumerate() {
-            if let Some(l) = last.clone() {
+        for (i, op) in program.code.clone().into_iter().enumerate() {
+            if let Some(l) = last.clone().filter(|_| !targets.contains(&i)) {
                 match (l, op.clone()) {
-                    (Instr::Push(_), Instr::Pop) => {
+                    (Instr::PushConst(_), Instr::Pop) => {
                         program[i - 1] = Instr::Noop;
                         program[i] = Instr::Noop;
                     }
@@ -151,42 +638,195 @@ impl Instr {
             last = Some(op);
         }
 
-        (program, prog_start)
+        let prog_start = if loader.fuse {
+            fuse(&mut program, prog_start)
+        } else {
+            prog_start
+        };
+        Ok((program, prog_start))
+    }
+
+    /// Builds the top level of a module, where imports are allowed.
+    pub(crate) fn build_module(
+        ins: &mut Program,
+        code: AST,
+        path: &Path,
+        state: &mut State,
+        next: &mut usize,
+        loader: &mut Loader,
+    ) -> Result<(), ModuleError> {
+        for expr in loader.check(code, path, state)? {
+            match expr {
+                Expr::Import {
+                    path: import,
+                    alias,
+                    ..
+                } => {
+                    let module = loader.load(ins, path, &import, next)?;
+                    let name = alias.unwrap_or_else(|| module.name.clone());
+
+                    ins.push_constant(Type::Module(module));
+                    let id = *next;
+                    *next += 1;
+                    state.insert(name, (id, 0));
+                    ins.push(Self::StoreGlobal(id));
+                }
+                Expr::FromImport {
+                    path: import,
+                    names,
+                    ..
+                } => {
+                    let module = loader.load(ins, path, &import, next)?;
+
+                    for name in names {
+                        let id = *module.exports.get(&name).ok_or_else(|| {
+                            ModuleError::MissingExport {
+                                module: module.path.clone(),
+                                name: name.clone(),
+                            }
+                        })?;
+                        state.insert(name, (id, 0));
+                    }
+                }
+                expr => Self::iter_build(ins, vec![expr], state, 0, next),
+            }
+        }
+
+        Ok(())
     }
 
     pub fn iter_build(
-        ins: &mut Vec<Instr>,
+        ins: &mut Program,
         code: Vec<Expr>,
         state: &mut State,
         depth: usize,
         next: &mut usize,
     ) {
         for expr in code {
-            Self::build(ins, expr.clone(), state, depth, next);
-
-            if let Some(op) = ins.last() {
-                match expr {
-                    Expr::If {
-                        condition: _,
-                        then: _,
-                        otherwise: _,
-                    } => {}
-                    _ => {
-                        if op.pushes_to_stack() {
-                            ins.push(Instr::Pop);
-                        }
+            match expr {
+                // built for their effects only, leaving nothing on the stack
+                Expr::If { .. } | Expr::Match { .. } | Expr::Block(_) => {
+                    Self::build_expr(ins, expr, state, depth, next, false)
+                }
+                Expr::While { .. } => Self::build(ins, expr, state, depth, next),
+                expr => {
+                    Self::build(ins, expr, state, depth, next);
+
+                    if ins.last().is_some_and(Instr::pushes_to_stack) {
+                        ins.push(Instr::Pop);
                     }
                 }
             }
         }
     }
 
-    pub fn build(
-        ins: &mut Vec<Instr>,
+    /// Builds `code` leaving exactly one value on the stack: that of its last
+    /// expression, or none if it doesn't have one.
+    fn value_iter_build(
+        ins: &mut Program,
+        mut code: Vec<Expr>,
+        state: &mut State,
+        depth: usize,
+        next: &mut usize,
+    ) {
+        let last = code.pop();
+        Self::iter_build(ins, code, state, depth, next);
+
+        match last {
+            Some(expr) if Self::yields_value(&expr) => Self::build(ins, expr, state, depth, next),
+            Some(expr) => {
+                Self::iter_build(ins, vec![expr], state, depth, next);
+                ins.push_constant(Type::None);
+            }
+            None => ins.push_constant(Type::None),
+        }
+    }
+
+    pub(crate) fn yields_value(expr: &Expr) -> bool {
+        !matches!(
+            expr,
+            Expr::Declaration(..)
+                | Expr::Const(..)
+                | Expr::Assignment(..)
+                | Expr::Function { .. }
+                | Expr::Enum { .. }
+                | Expr::Import { .. }
+                | Expr::FromImport { .. }
+                | Expr::While { .. }
+                | Expr::Return(_)
+        )
+    }
+
+    /// Builds `code` in its own lexical scope: names declared inside are
+    /// dropped afterwards, restoring any outer binding they shadowed.
+    pub fn block_build(
+        ins: &mut Program,
+        code: Vec<Expr>,
+        state: &mut State,
+        depth: usize,
+        next: &mut usize,
+    ) {
+        let scope = Self::open_scope(state, *next);
+        Self::iter_build(ins, code, state, depth, next);
+        Self::close_scope(state, next, depth, scope);
+    }
+
+    /// `block_build`, leaving the value of the block on the stack.
+    pub fn block_value_build(
+        ins: &mut Program,
+        code: Vec<Expr>,
+        state: &mut State,
+        depth: usize,
+        next: &mut usize,
+    ) {
+        let scope = Self::open_scope(state, *next);
+        Self::value_iter_build(ins, code, state, depth, next);
+        Self::close_scope(state, next, depth, scope);
+    }
+
+    fn branch_build(
+        ins: &mut Program,
+        code: Vec<Expr>,
+        state: &mut State,
+        depth: usize,
+        next: &mut usize,
+        value: bool,
+    ) {
+        if value {
+            Self::block_value_build(ins, code, state, depth, next)
+        } else {
+            Self::block_build(ins, code, state, depth, next)
+        }
+    }
+
+    fn open_scope(state: &State, next: usize) -> (State, usize) {
+        (state.clone(), next)
+    }
+
+    fn close_scope(state: &mut State, next: &mut usize, depth: usize, scope: (State, usize)) {
+        let (outer, outer_next) = scope;
+        *state = outer;
+
+        // local slots of a finished scope are free to be reused, global slots
+        // are not since functions may still refer to them
+        if depth > 0 {
+            *next = outer_next;
+        }
+    }
+
+    pub fn build(ins: &mut Program, expr: Expr, state: &mut State, depth: usize, next: &mut usize) {
+        Self::build_expr(ins, expr, state, depth, next, true)
+    }
+
+    /// `value` decides whether an `if`, `match` or block leaves its value on
+    /// the stack, every other expression always does when it has one.
+    fn build_expr(
+        ins: &mut Program,
         expr: Expr,
         state: &mut State,
         depth: usize,
         next: &mut usize,
+        value: bool,
     ) {
         macro_rules! ins {
             ($op:expr) => {
@@ -195,7 +835,7 @@ impl Instr {
         }
         macro_rules! push_literal {
             ($val:expr) => {
-                ins.push(Self::Push(StackValue::Literal($val)))
+                ins.push_constant($val)
             };
         }
         macro_rules! build {
@@ -214,7 +854,7 @@ impl Instr {
                 } else if $d == depth {
                     ins.push(Self::LoadLocal($id))
                 } else {
-                    ins.push(Self::LoadAddr($id + $d))
+                    panic!("Cannot capture locals of an enclosing function")
                 }
             };
         }
@@ -226,41 +866,48 @@ impl Instr {
                 } else if $d == depth {
                     ins.push(Self::StoreLocal($id))
                 } else {
-                    ins.push(Self::StoreAddr($id + $d))
+                    panic!("Cannot capture locals of an enclosing function")
                 }
             };
         }
 
         macro_rules! assign {
             ($id:expr, $d:expr) => {
-                // if $d < depth {
-                //     return;
-                // }
-
                 if $d == 0 {
                     ins.push(Self::StoreGlobal($id))
                 } else if $d == depth {
                     ins.push(Self::StoreLocal($id))
                 } else {
-                    ins.push(Self::StoreAddr($id + $d))
+                    panic!("Cannot capture locals of an enclosing function")
                 }
             };
         }
 
         match expr {
+            Expr::Int(int) => {
+                push_literal!(Type::Int(int));
+            }
+            Expr::BigInt(int) => {
+                push_literal!(Type::BigInt(Rc::new(int)));
+            }
             Expr::Number(num) => {
                 push_literal!(Type::Number(num));
             }
             Expr::String(str) => {
-                push_literal!(Type::String(str));
+                push_literal!(Type::String(str.into()));
             }
             Expr::FormatString(string) => {
                 let n = string.len();
 
                 if n == 0 {
-                    push_literal!(Type::String("".to_string()));
+                    push_literal!(Type::String(Rc::new(String::new())));
                 } else if n == 1 {
+                    // a lone interpolation still has to become a string
+                    let plain = !matches!(string[0], Expr::String(_) | Expr::Formatted(..));
                     build!(string[0].clone());
+                    if plain {
+                        ins!(Self::Format(Box::default()));
+                    }
                 // } else if n == 2 {
                 // build!(string[0].clone());
                 // build!(string[1].clone());
@@ -272,6 +919,10 @@ impl Instr {
                     ins.push(Self::JoinMany(n));
                 }
             }
+            Expr::Formatted(value, spec) => {
+                build!(*value);
+                ins!(Self::Format(Box::new(spec)));
+            }
             Expr::Join(lhs, rhs) => {
                 build!(*lhs);
                 build!(*rhs);
@@ -298,7 +949,7 @@ impl Instr {
                 build!(*item);
                 ins!(Self::Index);
             }
-            Expr::Declaration(name, value) => {
+            Expr::Declaration(name, value) | Expr::Const(name, value) => {
                 build!(*value);
                 // Self::build(ins, *value, state, depth + 1, next, stack);
                 // let id = get_id(&name) + depth;
@@ -307,31 +958,29 @@ impl Instr {
                 //     if *dep == depth {
                 //         declare!(*id, *dep);
                 //     } else {
-                //         let id = depth + *next;
+                //         let id = *next;
 
                 //         *next += 1;
-                //         state.insert(name, (id.clone(), depth));
+                //         state.insert(name, (id, depth));
                 //         // op!(Self::Store(id));
                 //         declare!(id, depth);
                 //     }
                 // } else {
-                let id = depth + *next;
+                let id = *next;
 
                 *next += 1;
-                state.insert(name, (id.clone(), depth));
+                state.insert(name, (id, depth));
                 // op!(Self::Store(id));
                 declare!(id, depth);
                 // }
             }
-            Expr::Assignment(assignee, value) => {
+            Expr::Assignment(assignee, value, _) => {
                 match *assignee {
-                    Expr::Identifier(name) => {
-                        let (id, dep) = state
+                    Expr::Identifier(name, _) => {
+                        let (id, dep) = *state
                             .get(&name)
-                            .expect(&format!("Variable not found {}", name))
-                            .clone();
+                            .unwrap_or_else(|| panic!("Variable not found {}", name));
                         // let id = get_id(&name);
-                        *next += 1;
                         build!(*value);
                         // Self::build(ins, *value, state, depth + 1, next, stack);
                         // op!(Self::Store(id));
@@ -349,6 +998,7 @@ impl Instr {
                     //     // }
                     // }
                     // Expr::Call(_, _) => {}
+                    // the resolver rejects every other assignee
                     _ => panic!("cannot assign"),
                 }
             }
@@ -360,19 +1010,18 @@ impl Instr {
             //         // op!(Self::Store(*id));
             //         store!(*id, *dep);
             //     } else {
-            //         let id = depth + *next;
+            //         let id = *next;
 
             //         *next += 1;
-            //         state.insert(name, (id.clone(), depth));
+            //         state.insert(name, (id, depth));
             //         // op!(Self::Store(id));
             //         store!(id, depth);
             //     }
             // }
-            Expr::Identifier(name) => {
-                let (id, dep) = state
+            Expr::Identifier(name, _) => {
+                let (id, dep) = *state
                     .get(&name)
-                    .expect(&format!("Variable not found {}", name))
-                    .clone();
+                    .unwrap_or_else(|| panic!("Variable not found {}", name));
                 // let id = get_id(&name);
                 // op!(Self::Load(id));
                 // load!(id, d);
@@ -391,20 +1040,18 @@ impl Instr {
             }
             Expr::Lambda(args, code) => {
                 let top = ins.len();
-                ins.push(Instr::Noop); // placeholder for return address
+                ins.push(Instr::Noop); // placeholder for Function
 
-                // let depth = depth + 1;
-                let mut new_next = *next;
-                // let id: usize = depth + *next;
-                *next += 1;
-                // state.insert(name, (id.clone(), depth));
+                // locals are numbered from the start of the function's frame
+                let mut new_next = 0;
 
                 let mut fn_state = state.clone();
 
-                for arg in args {
-                    let id = depth + 1 + new_next;
+                let arity = args.len() as u32;
+                for (arg, _) in args {
+                    let id = new_next;
                     new_next += 1;
-                    fn_state.insert(arg, (id.clone(), depth + 1));
+                    fn_state.insert(arg, (id, depth + 1));
 
                     ins!(Self::StoreLocal(id));
                 }
@@ -414,7 +1061,10 @@ impl Instr {
                 push_literal!(Type::None);
                 ins!(Self::Return);
 
-                ins[top] = Self::Jump(ins.len());
+                ins[top] = Self::Function {
+                    end: ins.len() as u32,
+                    arity,
+                };
 
                 push_literal!(Type::FuncPtr(top + 1));
                 // declare!(id, depth);
@@ -423,37 +1073,28 @@ impl Instr {
                 name,
                 args,
                 body: code,
+                ..
             } => {
                 let top = ins.len();
-                ins.push(Instr::Noop); // placeholder for return address
-
-                // let depth = depth + 1;
-                let mut new_next = *next;
-                let id: usize = if let Some((id, _dep)) = state.get(&name) {
-                    // if depth == *dep {
-                    *id
-                    // } else {
-                    //     depth + *next
-                    // }
-                } else {
-                    depth + *next
-                };
+                ins.push(Instr::Noop); // placeholder for Function
 
-                // let id =
-                // let id = get_id(&name) + depth - 1;
+                // locals are numbered from the start of the function's frame
+                let mut new_next = 0;
+                let id = *next;
                 *next += 1;
-                state.insert(name, (id.clone(), depth));
+                state.insert(name, (id, depth));
 
                 let mut fn_state = state.clone();
 
                 // println!("{} {:?}", depth, fn_state);
 
                 // let mut arg_ids = vec![];
-                for arg in args {
-                    let id = depth + 1 + new_next;
+                let arity = args.len() as u32;
+                for (arg, _) in args {
+                    let id = new_next;
                     // let id = get_id(&arg) + depth;
                     new_next += 1;
-                    fn_state.insert(arg, (id.clone(), depth + 1));
+                    fn_state.insert(arg, (id, depth + 1));
 
                     // arg_ids.push(id);
 
@@ -476,7 +1117,10 @@ impl Instr {
                 push_literal!(Type::None);
                 ins!(Self::Return);
 
-                ins[top] = Self::Jump(ins.len());
+                ins[top] = Self::Function {
+                    end: ins.len() as u32,
+                    arity,
+                };
 
                 push_literal!(Type::FuncPtr(top + 1));
                 // op!(Self::Store(id));
@@ -484,6 +1128,201 @@ impl Instr {
 
                 // op!(Self::Register(id, top + 1));
             }
+            Expr::Enum { name, variants } => {
+                let def = EnumDef {
+                    name: name.clone(),
+                    variants: variants
+                        .into_iter()
+                        .map(|(variant, fields)| (variant, fields.len()))
+                        .collect(),
+                };
+                push_literal!(Type::Enum(Rc::new(def)));
+
+                let id = *next;
+                *next += 1;
+                state.insert(name, (id, depth));
+                declare!(id, depth);
+            }
+            Expr::Member(item, name) => {
+                build!(*item);
+                let name = ins.constant(Type::String(Rc::new(name)));
+                ins!(Self::Member(name));
+            }
+            Expr::OptionalMember(item, name) => {
+                build!(*item);
+                let jump_idx = ins.len();
+                ins.push(Self::Noop); // placeholder for JumpIfNone
+
+                let name = ins.constant(Type::String(Rc::new(name)));
+                ins!(Self::Member(name));
+                ins[jump_idx] = Self::JumpIfNone(ins.len());
+            }
+            Expr::Call(callee, args) if matches!(*callee, Expr::OptionalMember(..)) => {
+                let Expr::OptionalMember(item, name) = *callee else {
+                    unreachable!()
+                };
+
+                // `item?.name(args)` skips the whole call when `item` is none,
+                // arguments included, so `item` is kept aside until the
+                // arguments are on the stack
+                build!(*item);
+                let jump_idx = ins.len();
+                ins.push(Self::Noop); // placeholder for JumpIfNone
+
+                let scope = Self::open_scope(state, *next);
+                let id = *next;
+                *next += 1;
+                declare!(id, depth);
+
+                let argc = args.len();
+                for arg in args.into_iter().rev() {
+                    build!(arg);
+                }
+                load!(id, depth);
+                let name = ins.constant(Type::String(Rc::new(name)));
+                ins!(Self::Member(name));
+                ins!(Self::Call(argc));
+                Self::close_scope(state, next, depth, scope);
+
+                ins[jump_idx] = Self::JumpIfNone(ins.len());
+            }
+            Expr::Op(op @ (Op::And | Op::Or | Op::Coalesce), lhs, rhs) => {
+                // the result is whichever operand decided it, `rhs` is only
+                // evaluated when `lhs` doesn't
+                build!(*lhs);
+                let jump_idx = ins.len();
+                ins.push(Self::Noop); // placeholder for the jump past `rhs`
+
+                build!(*rhs);
+                ins[jump_idx] = match op {
+                    Op::And => Self::JumpIfNotOrPop(ins.len()),
+                    Op::Or => Self::JumpIfOrPop(ins.len()),
+                    _ => Self::JumpIfNotNoneOrPop(ins.len()),
+                };
+            }
+            Expr::Chain(first, rest) => {
+                // `a < b < c` is `a < b && b < c` with `b` evaluated once. The
+                // inner operands take turns in one hidden slot, a load copies
+                // the previous one out before the next overwrites it
+                build!(*first);
+
+                let scope = Self::open_scope(state, *next);
+                let id = *next;
+                *next += 1;
+                let last = rest.len() - 1;
+                let mut jumps = vec![];
+                for (i, (op, operand)) in rest.into_iter().enumerate() {
+                    if i > 0 {
+                        load!(id, depth);
+                    }
+                    build!(operand);
+                    if i != last {
+                        declare!(id, depth);
+                        load!(id, depth);
+                    }
+                    ins!(match op {
+                        Op::Eq => Self::Eq,
+                        Op::Neq => Self::Neq,
+                        Op::Lt => Self::Lt,
+                        Op::Gt => Self::Gt,
+                        Op::Lte => Self::Lte,
+                        Op::Gte => Self::Gte,
+                        op => unreachable!("{:?} does not chain", op),
+                    });
+                    if i != last {
+                        jumps.push(ins.len());
+                        ins.push(Self::Noop); // placeholder for JumpIfNotOrPop
+                    }
+                }
+                Self::close_scope(state, next, depth, scope);
+
+                for jump in jumps {
+                    ins[jump] = Self::JumpIfNotOrPop(ins.len());
+                }
+            }
+            Expr::Import { .. } | Expr::FromImport { .. } => {
+                panic!("Imports are only allowed at the top level of a module");
+            }
+            Expr::Match {
+                value: matched,
+                arms,
+            } => {
+                build!(*matched);
+
+                // the subject lives in a hidden slot so every arm can re-load it
+                let scope = Self::open_scope(state, *next);
+                let id = *next;
+                *next += 1;
+                declare!(id, depth);
+                let subject = [if depth == 0 {
+                    Self::LoadGlobal(id)
+                } else {
+                    Self::LoadLocal(id)
+                }];
+
+                let mut end_jumps = vec![];
+                for (pattern, body) in arms {
+                    let mut fails = vec![];
+                    let mut bindings = vec![];
+                    Self::build_pattern(
+                        ins,
+                        &pattern,
+                        &subject,
+                        state,
+                        depth,
+                        &mut fails,
+                        &mut bindings,
+                    );
+
+                    let arm_scope = Self::open_scope(state, *next);
+                    for (name, path) in bindings {
+                        ins.extend(path);
+                        let id = *next;
+                        *next += 1;
+                        state.insert(name, (id, depth));
+                        declare!(id, depth);
+                    }
+
+                    if value {
+                        Self::value_iter_build(ins, body, state, depth, next);
+                    } else {
+                        Self::iter_build(ins, body, state, depth, next);
+                    }
+                    Self::close_scope(state, next, depth, arm_scope);
+
+                    end_jumps.push(ins.len());
+                    ins.push(Self::Noop); // placeholder for Jump
+
+                    for fail in fails {
+                        ins[fail] = Self::JumpIfNot(ins.len());
+                    }
+                }
+
+                // no arm matched
+                if value {
+                    push_literal!(Type::None);
+                }
+
+                for jump in end_jumps {
+                    ins[jump] = Self::Jump(ins.len());
+                }
+                Self::close_scope(state, next, depth, scope);
+            }
+            Expr::Block(code) => {
+                Self::branch_build(ins, code, state, depth, next, value);
+            }
+            Expr::While { condition, body } => {
+                let start = ins.len();
+                build!(*condition);
+
+                let jump_if_not_idx = ins.len();
+                ins.push(Self::Noop); // placeholder for JumpIfNot
+
+                Self::block_build(ins, body, state, depth, next);
+                ins!(Self::Jump(start));
+
+                ins[jump_if_not_idx] = Self::JumpIfNot(ins.len());
+            }
             Expr::If {
                 condition,
                 then,
@@ -494,13 +1333,13 @@ impl Instr {
                         // for expr in then {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
-                        Self::iter_build(ins, then, state, depth, next);
+                        Self::branch_build(ins, then, state, depth, next, value);
                     }
                     Expr::Bool(false) => {
                         // for expr in otherwise {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
-                        Self::iter_build(ins, otherwise, state, depth, next);
+                        Self::branch_build(ins, otherwise, state, depth, next, value);
                     }
                     Expr::Op(Op::Or, lhs, rhs) => {
                         Self::build(ins, *lhs, state, depth, next);
@@ -515,7 +1354,7 @@ impl Instr {
                         // for expr in then {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
-                        Self::iter_build(ins, then, state, depth, next);
+                        Self::branch_build(ins, then, state, depth, next, value);
 
                         let jump_idx = ins.len();
                         ins.push(Self::Noop); // placeholder for Jump
@@ -525,7 +1364,7 @@ impl Instr {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
 
-                        Self::iter_build(ins, otherwise, state, depth, next);
+                        Self::branch_build(ins, otherwise, state, depth, next, value);
 
                         ins[jump_if_idx] = Self::JumpIf(then_jump_to);
                         ins[jump_if_not_idx] = Self::JumpIfNot(jump_to);
@@ -540,11 +1379,10 @@ impl Instr {
                         let jump_if_not_idx = ins.len();
                         ins.push(Self::Noop);
 
-                        let then_jump_to = ins.len();
                         // for expr in then {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
-                        Self::iter_build(ins, then, state, depth, next);
+                        Self::branch_build(ins, then, state, depth, next, value);
 
                         let jump_idx = ins.len();
                         ins.push(Self::Noop); // placeholder for Jump
@@ -553,9 +1391,10 @@ impl Instr {
                         // for expr in otherwise {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
-                        Self::iter_build(ins, otherwise, state, depth, next);
+                        Self::branch_build(ins, otherwise, state, depth, next, value);
 
-                        ins[jump_if_idx] = Self::JumpIfNot(then_jump_to);
+                        // either side being falsy skips the then branch
+                        ins[jump_if_idx] = Self::JumpIfNot(jump_to);
                         ins[jump_if_not_idx] = Self::JumpIfNot(jump_to);
                         ins[jump_idx] = Self::Jump(ins.len());
                     }
@@ -568,7 +1407,7 @@ impl Instr {
                         // for expr in then {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
-                        Self::iter_build(ins, then, state, depth, next);
+                        Self::branch_build(ins, then, state, depth, next, value);
 
                         let jump_idx = ins.len();
                         ins.push(Self::Noop); // placeholder for Jump
@@ -577,7 +1416,7 @@ impl Instr {
                         // for expr in otherwise {
                         //     Self::build(ins, expr, state, depth, next);
                         // }
-                        Self::iter_build(ins, otherwise, state, depth, next);
+                        Self::branch_build(ins, otherwise, state, depth, next, value);
 
                         ins[jump_if_not_idx] = Self::JumpIfNot(jump_to);
                         ins[jump_idx] = Self::Jump(ins.len());
@@ -601,12 +1440,13 @@ impl Instr {
                 //     // load!(id, dep);
                 //     ins!(Self::Call);
                 // } else {
+                let argc = args.len();
                 for arg in args.into_iter().rev() {
                     build!(arg);
                 }
 
                 build!(*name);
-                ins!(Self::Call);
+                ins!(Self::Call(argc));
                 // }
             }
             Expr::NativeCall(name, args) => {
@@ -628,12 +1468,16 @@ impl Instr {
             }
             Expr::Op(op, lhs, rhs) => {
                 Self::build(ins, *lhs, state, depth, next);
-                Self::build(ins, *rhs, state, depth, next);
+                // unary operators carry a placeholder operand
+                if !matches!(op, Op::Neg | Op::Not | Op::BitNot) {
+                    Self::build(ins, *rhs, state, depth, next);
+                }
                 match op {
                     Op::Add => ins!(Self::Add),
                     Op::Sub => ins!(Self::Sub),
                     Op::Mul => ins!(Self::Mul),
                     Op::Div => ins!(Self::Div),
+                    Op::FloorDiv => ins!(Self::FloorDiv),
                     Op::Mod => ins!(Self::Mod),
                     Op::Eq => ins!(Self::Eq),
                     Op::Neq => ins!(Self::Neq),
@@ -641,11 +1485,16 @@ impl Instr {
                     Op::Gt => ins!(Self::Gt),
                     Op::Lte => ins!(Self::Lte),
                     Op::Gte => ins!(Self::Gte),
-                    Op::Or => ins!(Self::Or),
-                    Op::And => ins!(Self::And),
+                    Op::Or | Op::And | Op::Coalesce => unreachable!(),
                     Op::Not => ins!(Self::Not),
                     Op::Neg => ins!(Self::Neg),
                     Op::Pow => ins!(Self::Pow),
+                    Op::BitAnd => ins!(Self::BitAnd),
+                    Op::BitOr => ins!(Self::BitOr),
+                    Op::BitXor => ins!(Self::BitXor),
+                    Op::BitNot => ins!(Self::BitNot),
+                    Op::Shl => ins!(Self::Shl),
+                    Op::Shr => ins!(Self::Shr),
                 }
             } // match (*lhs, *rhs) {
             //     (Expr::Number(lhs), Expr::Number(rhs)) => match op {
@@ -700,10 +1549,93 @@ impl Instr {
         }
     }
 
+    /// The value of a literal pattern.
+    pub(crate) fn literal(value: &Expr) -> Type {
+        match value {
+            Expr::Int(i) => Type::Int(*i),
+            Expr::BigInt(i) => Type::BigInt(Rc::new(i.clone())),
+            Expr::Number(n) => Type::Number(*n),
+            Expr::String(s) => Type::String(Rc::new(s.clone())),
+            Expr::Bool(b) => Type::Bool(*b),
+            _ => Type::None,
+        }
+    }
+
+    /// Emits the checks for `pattern` against the value produced by `path`.
+    /// Each failing check is left as a placeholder in `fails`, and every
+    /// binding is recorded with the path that loads its value.
+    fn build_pattern(
+        ins: &mut Program,
+        pattern: &Pattern,
+        path: &[Instr],
+        state: &State,
+        depth: usize,
+        fails: &mut Vec<usize>,
+        bindings: &mut Vec<(String, Vec<Instr>)>,
+    ) {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Binding(name) => bindings.push((name.clone(), path.to_vec())),
+            Pattern::Literal(value) => {
+                ins.extend_from_slice(path);
+                ins.push_constant(Self::literal(value));
+                ins.push(Self::Eq);
+                fails.push(ins.len());
+                ins.push(Self::Noop); // placeholder for JumpIfNot
+            }
+            Pattern::Variant {
+                enum_name,
+                name,
+                fields,
+                ..
+            } => {
+                // the enum the name resolves to here, not any enum called that
+                let (id, dep) = *state
+                    .get(enum_name)
+                    .unwrap_or_else(|| panic!("Variable not found {}", enum_name));
+                ins.push(if dep == 0 {
+                    Self::LoadGlobal(id)
+                } else if dep == depth {
+                    Self::LoadLocal(id)
+                } else {
+                    panic!("Cannot capture locals of an enclosing function")
+                });
+                ins.extend_from_slice(path);
+                let name = ins.constant(Type::String(Rc::new(name.clone())));
+                ins.push(Self::IsVariant(name));
+                fails.push(ins.len());
+                ins.push(Self::Noop); // placeholder for JumpIfNot
+
+                for (i, field) in fields.iter().enumerate() {
+                    let mut field_path = path.to_vec();
+                    field_path.push(Self::Field(i));
+                    Self::build_pattern(ins, field, &field_path, state, depth, fails, bindings);
+                }
+            }
+        }
+    }
+
+    pub fn jump_target(&self) -> Option<usize> {
+        match self {
+            Self::Jump(to)
+            | Self::JumpIf(to)
+            | Self::JumpIfNot(to)
+            | Self::JumpIfOrPop(to)
+            | Self::JumpIfNotOrPop(to)
+            | Self::JumpIfNotNoneOrPop(to)
+            | Self::JumpIfNone(to) => Some(*to),
+            Self::CompareJumpLocalConst { to, .. } => Some(*to as usize),
+            Self::Function { end, .. } => Some(*end as usize),
+            _ => None,
+        }
+    }
+
     pub fn pushes_to_stack(&self) -> bool {
         match self {
             Self::Halt => false,
+            Self::Reserve(_) => false,
             Self::Jump(_) => false,
+            Self::Function { .. } => false,
             Self::Pop => false,
             // Self::Store(_) => false,
             Self::StoreAddr(_) => false,
@@ -715,6 +1647,7 @@ impl Instr {
             Self::Return => false,
             Self::JumpIfNot(_) => false,
             Self::JumpIf(_) => false,
+            Self::CompareJumpLocalConst { .. } => false,
             Self::Noop => false,
 
             _ => true,
@@ -727,6 +1660,7 @@ impl fmt::Debug for Instr {
         match self {
             Self::Noop => write!(f, "Noop"),
             Self::Halt => write!(f, "Halt"),
+            Self::Reserve(amnt) => write!(f, "Reserve \t{}", amnt),
 
             // Self::Load(id) => write!(f, "Load       \t{}", id),
             // Self::LoadDeref(id) => write!(f, "LoadDeref\t{}", id),
@@ -740,24 +1674,35 @@ impl fmt::Debug for Instr {
             Self::StoreGlobal(id) => write!(f, "StoreGlobal\t{}", id),
 
             // Self::Register(id, addr) => write!(f, "Register\t{} {addr}", id),
-            Self::Push(arg) => write!(f, "Push    \t{:?}", arg),
+            Self::PushConst(c) => write!(f, "PushConst\t#{}", c),
             Self::Pop => write!(f, "Pop           "),
             Self::Jump(id) => write!(f, "Jump    \t{}", id),
+            Self::Function { end, arity } => write!(f, "Function\t{}, {}", end, arity),
             Self::JumpIf(id) => write!(f, "JumpIf  \t{}", id),
             Self::JumpIfNot(id) => write!(f, "JumpIfNot\t{}", id),
-            Self::Call => write!(f, "Call              "),
+            Self::JumpIfOrPop(id) => write!(f, "JumpIfOrPop\t{}", id),
+            Self::JumpIfNotOrPop(id) => write!(f, "JumpIfNotOrPop\t{}", id),
+            Self::JumpIfNotNoneOrPop(id) => write!(f, "JumpIfNotNoneOrPop\t{}", id),
+            Self::JumpIfNone(id) => write!(f, "JumpIfNone\t{}", id),
+            Self::Call(argc) => write!(f, "Call    \t{}", argc),
             Self::NativeCall(id) => write!(f, "NativeCall\t{:?}", id),
             Self::Return => write!(f, "Return           "),
 
             Self::JoinMany(amnt) => write!(f, "JoinMany\t{}", amnt),
+            Self::Format(spec) => write!(f, "Format\t{{:{}}}", spec),
             Self::Join => write!(f, "Join           "),
             Self::Index => write!(f, "Index          "),
             Self::IndexStore => write!(f, "IndexStore          "),
 
+            Self::Member(name) => write!(f, "Member  \t#{}", name),
+            Self::IsVariant(name) => write!(f, "IsVariant\t#{}", name),
+            Self::Field(i) => write!(f, "Field   \t{}", i),
+
             Self::Add => write!(f, "Add              "),
             Self::Sub => write!(f, "Sub              "),
             Self::Mul => write!(f, "Mul              "),
             Self::Div => write!(f, "Div              "),
+            Self::FloorDiv => write!(f, "FloorDiv         "),
             Self::Mod => write!(f, "Mod              "),
             Self::Pow => write!(f, "Pow              "),
             Self::Eq => write!(f, "Eq              "),
@@ -766,10 +1711,36 @@ impl fmt::Debug for Instr {
             Self::Gt => write!(f, "Gt              "),
             Self::Lte => write!(f, "Lte              "),
             Self::Gte => write!(f, "Gte              "),
-            Self::And => write!(f, "And              "),
-            Self::Or => write!(f, "Or              "),
             Self::Not => write!(f, "Not              "),
             Self::Neg => write!(f, "Neg              "),
+            Self::BitAnd => write!(f, "BitAnd           "),
+            Self::BitOr => write!(f, "BitOr            "),
+            Self::BitXor => write!(f, "BitXor           "),
+            Self::BitNot => write!(f, "BitNot           "),
+            Self::Shl => write!(f, "Shl              "),
+            Self::Shr => write!(f, "Shr              "),
+
+            Self::AddConst(c) => write!(f, "AddConst\t#{}", c),
+            Self::SubConst(c) => write!(f, "SubConst\t#{}", c),
+            Self::CompareJumpLocalConst {
+                local,
+                constant,
+                op,
+                jump_if,
+                to,
+            } => write!(
+                f,
+                "CompareJumpLocalConst\t{} {:?} #{} {} {}",
+                local,
+                op,
+                constant,
+                if *jump_if { "JumpIf" } else { "JumpIfNot" },
+                to
+            ),
+            Self::CallGlobal { global, argc, .. } => {
+                write!(f, "CallGlobal\t{} {}", global, argc)
+            }
+            Self::LoadLocal2(a, b) => write!(f, "LoadLocal2\t{} {}", a, b),
         }
     }
 }
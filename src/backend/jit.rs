@@ -0,0 +1,889 @@
+use cranelift_codegen::{
+    ir::{
+        condcodes::{FloatCC, IntCC},
+        types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, StackSlot, StackSlotData,
+        StackSlotKind, Value,
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use fxhash::{FxHashMap, FxHashSet};
+
+use super::{
+    instruction::{Compare, Instr, Type},
+    memory::Memory,
+};
+
+/// How many calls make a function hot enough to compile.
+const HOT: u32 = 1000;
+/// How many times compiled code may give up before its function is only
+/// interpreted from then on.
+const DEOPTS: u32 = 10;
+/// How deep compiled code recurses before giving up, the interpreter keeps
+/// its frames on the heap rather than the native stack.
+const DEPTH: i64 = 10_000;
+/// The most arguments a compiled function takes.
+const ARGS: usize = 16;
+
+/// What a value in compiled code is, only numbers and bools get compiled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Int,
+    Float,
+    Bool,
+}
+
+impl Kind {
+    fn of(value: &Type) -> Option<Kind> {
+        match value {
+            Type::Int(_) => Some(Kind::Int),
+            Type::Number(_) => Some(Kind::Float),
+            Type::Bool(_) => Some(Kind::Bool),
+            _ => None,
+        }
+    }
+
+    fn ty(self) -> types::Type {
+        match self {
+            Kind::Int => types::I64,
+            Kind::Float => types::F64,
+            Kind::Bool => types::I8,
+        }
+    }
+
+    fn bits(value: &Type) -> u64 {
+        match value {
+            Type::Int(i) => *i as u64,
+            Type::Number(n) => n.to_bits(),
+            Type::Bool(b) => *b as u64,
+            _ => unreachable!(),
+        }
+    }
+
+    fn value(self, bits: u64) -> Type {
+        match self {
+            Kind::Int => Type::Int(bits as i64),
+            Kind::Float => Type::Number(f64::from_bits(bits)),
+            Kind::Bool => Type::Bool(bits != 0),
+        }
+    }
+}
+
+// takes the arguments, the version of every global, how deep the call is
+// and where to put the result, returns 0 when the call has to be
+// interpreted instead
+type Code = unsafe extern "C" fn(*const u64, *const u32, i64, *mut u64) -> u8;
+
+struct Native {
+    code: Code,
+    args: Vec<Kind>,
+    ret: Kind,
+    deopts: u32,
+}
+
+enum State {
+    Counting(u32),
+    Compiled(Native),
+    Interpreted,
+}
+
+/// Compiles functions that are called a lot and only work with numbers and
+/// bools to native code.
+///
+/// Compiled code has no side effects, so whenever it runs into something it
+/// can't do, like a value that isn't a number, an int that overflows or a
+/// global that was reassigned, it gives up and the call is run again by the
+/// interpreter from the start.
+#[derive(Default)]
+pub struct Jit {
+    functions: FxHashMap<usize, State>,
+    module: Option<JITModule>,
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        Jit::default()
+    }
+
+    /// Drops every compiled function, which compiled in the versions of the
+    /// globals it calls through, so they are counted and compiled again.
+    pub fn forget(&mut self) {
+        self.functions.clear();
+    }
+
+    /// Runs the function at `start` natively, returning how many arguments
+    /// it took off the stack and its result. `arg(i)` is its `i`th argument.
+    /// `None` means the call has to be interpreted, because the function
+    /// isn't compiled (yet), or the arguments aren't what it was compiled
+    /// for, or the compiled code gave up.
+    pub fn call<'a>(
+        &mut self,
+        start: usize,
+        code: &[Instr],
+        constants: &[Type],
+        heap: &Memory,
+        versions: &[u32],
+        arg: impl Fn(usize) -> Option<&'a Type>,
+    ) -> Option<(usize, Type)> {
+        match self.functions.entry(start).or_insert(State::Counting(0)) {
+            State::Compiled(_) => {}
+            State::Interpreted => return None,
+            State::Counting(calls) if *calls + 1 < HOT => {
+                *calls += 1;
+                return None;
+            }
+            State::Counting(_) => {
+                let state = match self.compile(start, code, constants, heap, versions, &arg) {
+                    Some(native) => State::Compiled(native),
+                    None => State::Interpreted,
+                };
+                self.functions.insert(start, state);
+            }
+        }
+        let Some(State::Compiled(native)) = self.functions.get_mut(&start) else {
+            return None;
+        };
+
+        let mut args = [0; ARGS];
+        for (i, kind) in native.args.iter().enumerate() {
+            let value = arg(i)?;
+            if Kind::of(value) != Some(*kind) {
+                return None;
+            }
+            args[i] = Kind::bits(value);
+        }
+
+        let mut result = 0;
+        // SAFETY: the code was compiled for exactly these arguments
+        if unsafe { (native.code)(args.as_ptr(), versions.as_ptr(), 0, &mut result) } != 0 {
+            return Some((native.args.len(), native.ret.value(result)));
+        }
+
+        native.deopts += 1;
+        if native.deopts >= DEOPTS {
+            self.functions.insert(start, State::Interpreted);
+        }
+        None
+    }
+
+    fn compile<'a>(
+        &mut self,
+        start: usize,
+        code: &[Instr],
+        constants: &[Type],
+        heap: &Memory,
+        versions: &[u32],
+        arg: &impl Fn(usize) -> Option<&'a Type>,
+    ) -> Option<Native> {
+        // a function's code runs up to where its header jumps
+        let (end, arity) = match code.get(start.checked_sub(1)?) {
+            Some(Instr::Function { end, arity }) if *end as usize > start => {
+                (*end as usize, *arity as usize)
+            }
+            _ => return None,
+        };
+        if arity > ARGS {
+            return None;
+        }
+        let args = (0..arity)
+            .map(|i| arg(i).and_then(Kind::of))
+            .collect::<Option<Vec<_>>>()?;
+
+        let module = self.module.get_or_insert_with(|| {
+            let mut flags = settings::builder();
+            flags.set("opt_level", "speed").unwrap();
+            let isa = cranelift_native::builder()
+                .expect("The JIT doesn't support this machine")
+                .finish(settings::Flags::new(flags))
+                .unwrap();
+            JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()))
+        });
+
+        let pointer = module.target_config().pointer_type();
+        let mut signature = module.make_signature();
+        for ty in [pointer, pointer, types::I64, pointer] {
+            signature.params.push(AbiParam::new(ty));
+        }
+        signature.returns.push(AbiParam::new(types::I8));
+        let id = module.declare_anonymous_function(&signature).ok()?;
+
+        // what it returns is guessed, then guessed again if it turns out
+        // to be something else
+        let mut ret = Kind::Int;
+        let mut ctx = module.make_context();
+        for _ in 0..3 {
+            ctx.clear();
+            ctx.func.signature = signature.clone();
+            let this = module.declare_func_in_func(id, &mut ctx.func);
+
+            let mut builder_ctx = FunctionBuilderContext::new();
+            let translator = Translator {
+                builder: FunctionBuilder::new(&mut ctx.func, &mut builder_ctx),
+                code,
+                constants,
+                heap,
+                versions,
+                start,
+                end,
+                args: &args,
+                ret,
+                this,
+                pointer,
+                locals: FxHashMap::default(),
+                blocks: FxHashMap::default(),
+                queue: vec![],
+                returns: vec![],
+            };
+            let returns = translator.translate()?;
+
+            match returns.first() {
+                Some(kind) if !returns.contains(&ret) && *kind != ret => ret = *kind,
+                _ => break,
+            }
+        }
+
+        module.define_function(id, &mut ctx).ok()?;
+        module.finalize_definitions().ok()?;
+        // SAFETY: the function was compiled with the signature of `Code`
+        let code =
+            unsafe { std::mem::transmute::<*const u8, Code>(module.get_finalized_function(id)) };
+
+        Some(Native {
+            code,
+            args,
+            ret,
+            deopts: 0,
+        })
+    }
+}
+
+/// Translates the bytecode of one function to Cranelift IR. Blocks of
+/// bytecode start at jump targets and after jumps, and nothing may be left
+/// on the stack between them.
+struct Translator<'a, 'f> {
+    builder: FunctionBuilder<'f>,
+    code: &'a [Instr],
+    constants: &'a [Type],
+    heap: &'a Memory,
+    versions: &'a [u32],
+    start: usize,
+    end: usize,
+    args: &'a [Kind],
+    ret: Kind,
+    this: FuncRef,
+    pointer: types::Type,
+
+    locals: FxHashMap<usize, (Variable, Kind)>,
+    blocks: FxHashMap<usize, Block>,
+    queue: Vec<usize>,
+    // what every return returned
+    returns: Vec<Kind>,
+}
+
+impl Translator<'_, '_> {
+    /// Builds the function, returning the kinds it returns. `None` if it
+    /// does anything compiled code can't.
+    fn translate(mut self) -> Option<Vec<Kind>> {
+        let mut leaders = FxHashSet::default();
+        for pc in self.start..self.end {
+            let instruction = &self.code[pc];
+            if let Some(to) = instruction.jump_target() {
+                if !(self.start..self.end).contains(&to) {
+                    return None;
+                }
+                leaders.insert(to);
+                leaders.insert(pc + 1);
+            }
+            if *instruction == Instr::Return {
+                leaders.insert(pc + 1);
+            }
+        }
+
+        let b = &mut self.builder;
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let params = b.block_params(entry).to_vec();
+        let (args, depth) = (params[0], params[2]);
+
+        for (i, kind) in self.args.iter().enumerate() {
+            let bits =
+                self.builder
+                    .ins()
+                    .load(types::I64, MemFlags::trusted(), args, (i * 8) as i32);
+            let value = self.unpack(bits, *kind);
+            self.store(i, value, *kind);
+        }
+
+        let deopt = self.builder.create_block();
+        let deep = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::SignedGreaterThan, depth, DEPTH);
+        let body = self.block(self.start + self.args.len());
+        self.builder.ins().brif(deep, deopt, &[], body, &[]);
+
+        let mut done = FxHashSet::default();
+        while let Some(pc) = self.queue.pop() {
+            if done.insert(pc) {
+                let block = self.blocks[&pc];
+                self.builder.switch_to_block(block);
+                self.translate_block(pc, &leaders, deopt, &params)?;
+            }
+        }
+
+        self.builder.switch_to_block(deopt);
+        let zero = self.builder.ins().iconst(types::I8, 0);
+        self.builder.ins().return_(&[zero]);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+        Some(self.returns)
+    }
+
+    fn translate_block(
+        &mut self,
+        mut pc: usize,
+        leaders: &FxHashSet<usize>,
+        deopt: Block,
+        params: &[Value],
+    ) -> Option<()> {
+        let mut stack: Vec<(Value, Kind)> = vec![];
+
+        macro_rules! push {
+            ($value:expr) => {
+                match $value {
+                    Some(value) => stack.push(value),
+                    None => {
+                        self.builder.ins().jump(deopt, &[]);
+                        return Some(());
+                    }
+                }
+            };
+        }
+        macro_rules! binary {
+            ($op:expr) => {{
+                let rhs = stack.pop()?;
+                let lhs = stack.pop()?;
+                push!(self.binary($op, lhs, rhs, deopt))
+            }};
+        }
+        macro_rules! branch {
+            ($cond:expr, $jump_if:expr, $to:expr) => {{
+                let cond = $cond;
+                if !stack.is_empty() {
+                    return None;
+                }
+                let cond = self.truthy(cond);
+                let (to, next) = (self.block($to), self.block(pc + 1));
+                if $jump_if {
+                    self.builder.ins().brif(cond, to, &[], next, &[]);
+                } else {
+                    self.builder.ins().brif(cond, next, &[], to, &[]);
+                }
+                return Some(());
+            }};
+        }
+
+        loop {
+            if pc >= self.end {
+                return None;
+            }
+
+            match &self.code[pc] {
+                Instr::Noop => {}
+                Instr::StoreLocal(offset) => {
+                    let (value, kind) = stack.pop()?;
+                    if !self.store(*offset, value, kind) {
+                        self.builder.ins().jump(deopt, &[]);
+                        return Some(());
+                    }
+                }
+                Instr::LoadLocal(offset) => stack.push(self.load(*offset)?),
+                Instr::LoadLocal2(a, b) => {
+                    stack.push(self.load(*a as usize)?);
+                    stack.push(self.load(*b as usize)?);
+                }
+                Instr::PushConst(index) => push!(self.constant(*index)),
+                Instr::Pop => {
+                    stack.pop()?;
+                }
+
+                Instr::Jump(to) => {
+                    if !stack.is_empty() {
+                        return None;
+                    }
+                    let to = self.block(*to);
+                    self.builder.ins().jump(to, &[]);
+                    return Some(());
+                }
+                Instr::JumpIf(to) => branch!(stack.pop()?, true, *to),
+                Instr::JumpIfNot(to) => branch!(stack.pop()?, false, *to),
+                Instr::CompareJumpLocalConst {
+                    local,
+                    constant,
+                    op,
+                    jump_if,
+                    to,
+                } => {
+                    let lhs = self.load(*local as usize)?;
+                    let Some(rhs) = self.constant(*constant) else {
+                        self.builder.ins().jump(deopt, &[]);
+                        return Some(());
+                    };
+                    let instruction = match op {
+                        Compare::Eq => Instr::Eq,
+                        Compare::Neq => Instr::Neq,
+                        Compare::Lt => Instr::Lt,
+                        Compare::Gt => Instr::Gt,
+                        Compare::Lte => Instr::Lte,
+                        Compare::Gte => Instr::Gte,
+                    };
+                    push!(self.binary(&instruction, lhs, rhs, deopt));
+                    branch!(stack.pop()?, *jump_if, *to as usize)
+                }
+
+                Instr::CallGlobal { global, argc, .. } => {
+                    let value =
+                        self.call(*global as usize, *argc as usize, &mut stack, deopt, params)?;
+                    push!(value)
+                }
+                Instr::Return => {
+                    let (value, kind) = stack.pop()?;
+                    self.returns.push(kind);
+                    if kind != self.ret {
+                        self.builder.ins().jump(deopt, &[]);
+                        return Some(());
+                    }
+
+                    let bits = self.pack(value, kind);
+                    let b = &mut self.builder;
+                    b.ins().store(MemFlags::trusted(), bits, params[3], 0);
+                    let one = b.ins().iconst(types::I8, 1);
+                    b.ins().return_(&[one]);
+                    return Some(());
+                }
+
+                Instr::AddConst(index) | Instr::SubConst(index) => {
+                    let lhs = stack.pop()?;
+                    let Some(rhs) = self.constant(*index) else {
+                        self.builder.ins().jump(deopt, &[]);
+                        return Some(());
+                    };
+                    let op = match self.code[pc] {
+                        Instr::AddConst(_) => Instr::Add,
+                        _ => Instr::Sub,
+                    };
+                    push!(self.binary(&op, lhs, rhs, deopt))
+                }
+                op @ (Instr::Add
+                | Instr::Sub
+                | Instr::Mul
+                | Instr::Div
+                | Instr::FloorDiv
+                | Instr::Mod
+                | Instr::Pow
+                | Instr::Lt
+                | Instr::Gt
+                | Instr::Lte
+                | Instr::Gte
+                | Instr::Eq
+                | Instr::Neq
+                | Instr::BitAnd
+                | Instr::BitOr
+                | Instr::BitXor
+                | Instr::Shl
+                | Instr::Shr) => binary!(op),
+                op @ (Instr::Not | Instr::Neg | Instr::BitNot) => {
+                    let value = stack.pop()?;
+                    push!(self.unary(op, value, deopt))
+                }
+
+                // anything else could have side effects, which running the
+                // call again would repeat
+                _ => return None,
+            }
+
+            pc += 1;
+            if leaders.contains(&pc) {
+                if !stack.is_empty() {
+                    return None;
+                }
+                let next = self.block(pc);
+                self.builder.ins().jump(next, &[]);
+                return Some(());
+            }
+        }
+    }
+
+    /// The block starting at `pc`, which gets translated if it isn't yet.
+    fn block(&mut self, pc: usize) -> Block {
+        if let Some(block) = self.blocks.get(&pc) {
+            return *block;
+        }
+        let block = self.builder.create_block();
+        self.blocks.insert(pc, block);
+        self.queue.push(pc);
+        block
+    }
+
+    fn load(&mut self, offset: usize) -> Option<(Value, Kind)> {
+        let (var, kind) = *self.locals.get(&offset)?;
+        Some((self.builder.use_var(var), kind))
+    }
+
+    /// Whether the local could be stored, a local always holds the same kind.
+    fn store(&mut self, offset: usize, value: Value, kind: Kind) -> bool {
+        let (var, local) = *self.locals.entry(offset).or_insert_with(|| {
+            let var = Variable::from_u32(offset as u32);
+            self.builder.declare_var(var, kind.ty());
+            (var, kind)
+        });
+        if local != kind {
+            return false;
+        }
+        self.builder.def_var(var, value);
+        true
+    }
+
+    fn constant(&mut self, index: u32) -> Option<(Value, Kind)> {
+        let b = &mut self.builder;
+        Some(match &self.constants[index as usize] {
+            Type::Int(i) => (b.ins().iconst(types::I64, *i), Kind::Int),
+            Type::Number(n) => (b.ins().f64const(*n), Kind::Float),
+            Type::Bool(v) => (b.ins().iconst(types::I8, *v as i64), Kind::Bool),
+            _ => return None,
+        })
+    }
+
+    // arguments and results are passed around as 64 bit words
+    fn unpack(&mut self, bits: Value, kind: Kind) -> Value {
+        match kind {
+            Kind::Int => bits,
+            Kind::Float => self
+                .builder
+                .ins()
+                .bitcast(types::F64, MemFlags::new(), bits),
+            Kind::Bool => self.builder.ins().ireduce(types::I8, bits),
+        }
+    }
+
+    fn pack(&mut self, value: Value, kind: Kind) -> Value {
+        match kind {
+            Kind::Int => value,
+            Kind::Float => self
+                .builder
+                .ins()
+                .bitcast(types::I64, MemFlags::new(), value),
+            Kind::Bool => self.builder.ins().uextend(types::I64, value),
+        }
+    }
+
+    fn truthy(&mut self, (value, kind): (Value, Kind)) -> Value {
+        let b = &mut self.builder;
+        match kind {
+            Kind::Int => b.ins().icmp_imm(IntCC::NotEqual, value, 0),
+            Kind::Float => {
+                let zero = b.ins().f64const(0.0);
+                b.ins().fcmp(FloatCC::NotEqual, value, zero)
+            }
+            Kind::Bool => value,
+        }
+    }
+
+    /// Carries on in a new block unless `cond`, in which case it gives up.
+    fn guard(&mut self, cond: Value, deopt: Block) {
+        let ok = self.builder.create_block();
+        self.builder.ins().brif(cond, deopt, &[], ok, &[]);
+        self.builder.switch_to_block(ok);
+    }
+
+    /// What `op` does to two values, the same as the interpreter, or `None`
+    /// if the compiled code should give up.
+    fn binary(
+        &mut self,
+        op: &Instr,
+        (lhs, l): (Value, Kind),
+        (rhs, r): (Value, Kind),
+        deopt: Block,
+    ) -> Option<(Value, Kind)> {
+        use Kind::*;
+
+        match (l, r) {
+            (Int, Int) => {}
+            (Float, Float) => return self.float(op, lhs, rhs),
+            // ints mix with floats by becoming floats, except when compared
+            (Int, Float) if !matches!(op, Instr::Mod) && Compare::of(op).is_none() => {
+                let lhs = self.builder.ins().fcvt_from_sint(types::F64, lhs);
+                return self.float(op, lhs, rhs);
+            }
+            (Float, Int) if !matches!(op, Instr::Mod) && Compare::of(op).is_none() => {
+                let rhs = self.builder.ins().fcvt_from_sint(types::F64, rhs);
+                return self.float(op, lhs, rhs);
+            }
+            (Bool, Bool) => {
+                let cc = match op {
+                    Instr::Eq => IntCC::Equal,
+                    Instr::Neq => IntCC::NotEqual,
+                    _ => return None,
+                };
+                return Some((self.builder.ins().icmp(cc, lhs, rhs), Bool));
+            }
+            _ => return None,
+        }
+
+        let b = &mut self.builder;
+        let value = match op {
+            // an int that overflows becomes a big int, which compiled code
+            // doesn't have
+            Instr::Add | Instr::Sub | Instr::Mul => {
+                let (value, overflow) = match op {
+                    Instr::Add => b.ins().sadd_overflow(lhs, rhs),
+                    Instr::Sub => b.ins().ssub_overflow(lhs, rhs),
+                    _ => b.ins().smul_overflow(lhs, rhs),
+                };
+                self.guard(overflow, deopt);
+                value
+            }
+            Instr::Div => {
+                let lhs = b.ins().fcvt_from_sint(types::F64, lhs);
+                let rhs = b.ins().fcvt_from_sint(types::F64, rhs);
+                return self.float(op, lhs, rhs);
+            }
+            Instr::FloorDiv => {
+                let zero = b.ins().icmp_imm(IntCC::Equal, rhs, 0);
+                self.guard(zero, deopt);
+                let b = &mut self.builder;
+                let min = b.ins().icmp_imm(IntCC::Equal, lhs, i64::MIN);
+                let minus_one = b.ins().icmp_imm(IntCC::Equal, rhs, -1);
+                let overflow = b.ins().band(min, minus_one);
+                self.guard(overflow, deopt);
+
+                // rounds towards zero, so one less when the signs differ
+                let b = &mut self.builder;
+                let quotient = b.ins().sdiv(lhs, rhs);
+                let rem = b.ins().srem(lhs, rhs);
+                let inexact = b.ins().icmp_imm(IntCC::NotEqual, rem, 0);
+                let signs = b.ins().bxor(rem, rhs);
+                let differ = b.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+                let adjust = b.ins().band(inexact, differ);
+                let adjust = b.ins().uextend(types::I64, adjust);
+                b.ins().isub(quotient, adjust)
+            }
+            Instr::Mod => {
+                let zero = b.ins().icmp_imm(IntCC::Equal, rhs, 0);
+                self.guard(zero, deopt);
+                // `i64::MIN % -1` traps, anything `% -1` is 0 anyway
+                let b = &mut self.builder;
+                let minus_one = b.ins().icmp_imm(IntCC::Equal, rhs, -1);
+                let one = b.ins().iconst(types::I64, 1);
+                let rhs = b.ins().select(minus_one, one, rhs);
+
+                // takes the sign of `rhs`, so `rhs` more when the signs differ
+                let rem = b.ins().srem(lhs, rhs);
+                let inexact = b.ins().icmp_imm(IntCC::NotEqual, rem, 0);
+                let signs = b.ins().bxor(rem, rhs);
+                let differ = b.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+                let adjust = b.ins().band(inexact, differ);
+                let adjusted = b.ins().iadd(rem, rhs);
+                b.ins().select(adjust, adjusted, rem)
+            }
+            Instr::BitAnd => b.ins().band(lhs, rhs),
+            Instr::BitOr => b.ins().bor(lhs, rhs),
+            Instr::BitXor => b.ins().bxor(lhs, rhs),
+            op => {
+                let cc = match Compare::of(op)? {
+                    Compare::Eq => IntCC::Equal,
+                    Compare::Neq => IntCC::NotEqual,
+                    Compare::Lt => IntCC::SignedLessThan,
+                    Compare::Gt => IntCC::SignedGreaterThan,
+                    Compare::Lte => IntCC::SignedLessThanOrEqual,
+                    Compare::Gte => IntCC::SignedGreaterThanOrEqual,
+                };
+                return Some((b.ins().icmp(cc, lhs, rhs), Bool));
+            }
+        };
+        Some((value, Int))
+    }
+
+    fn float(&mut self, op: &Instr, lhs: Value, rhs: Value) -> Option<(Value, Kind)> {
+        let b = &mut self.builder;
+        let value = match op {
+            Instr::Add => b.ins().fadd(lhs, rhs),
+            Instr::Sub => b.ins().fsub(lhs, rhs),
+            Instr::Mul => b.ins().fmul(lhs, rhs),
+            Instr::Div => b.ins().fdiv(lhs, rhs),
+            Instr::FloorDiv => {
+                let quotient = b.ins().fdiv(lhs, rhs);
+                b.ins().floor(quotient)
+            }
+            // NaN equals itself, like every other value
+            Instr::Eq | Instr::Neq => {
+                let equal = b.ins().fcmp(FloatCC::Equal, lhs, rhs);
+                let l = b.ins().fcmp(FloatCC::Unordered, lhs, lhs);
+                let r = b.ins().fcmp(FloatCC::Unordered, rhs, rhs);
+                let nan = b.ins().band(l, r);
+                let equal = b.ins().bor(equal, nan);
+                return Some(match op {
+                    Instr::Eq => (equal, Kind::Bool),
+                    _ => (b.ins().icmp_imm(IntCC::Equal, equal, 0), Kind::Bool),
+                });
+            }
+            op => {
+                let cc = match Compare::of(op)? {
+                    Compare::Lt => FloatCC::LessThan,
+                    Compare::Gt => FloatCC::GreaterThan,
+                    Compare::Lte => FloatCC::LessThanOrEqual,
+                    Compare::Gte => FloatCC::GreaterThanOrEqual,
+                    Compare::Eq | Compare::Neq => unreachable!(),
+                };
+                return Some((b.ins().fcmp(cc, lhs, rhs), Kind::Bool));
+            }
+        };
+        Some((value, Kind::Float))
+    }
+
+    fn unary(
+        &mut self,
+        op: &Instr,
+        (value, kind): (Value, Kind),
+        deopt: Block,
+    ) -> Option<(Value, Kind)> {
+        Some(match (op, kind) {
+            (Instr::Not, kind) => {
+                let truthy = self.truthy((value, kind));
+                let value = self.builder.ins().icmp_imm(IntCC::Equal, truthy, 0);
+                (value, Kind::Bool)
+            }
+            (Instr::Neg, Kind::Int) => {
+                let min = self.builder.ins().icmp_imm(IntCC::Equal, value, i64::MIN);
+                self.guard(min, deopt);
+                (self.builder.ins().ineg(value), Kind::Int)
+            }
+            (Instr::Neg, Kind::Float) => (self.builder.ins().fneg(value), Kind::Float),
+            (Instr::BitNot, Kind::Int) => (self.builder.ins().bnot(value), Kind::Int),
+            _ => return None,
+        })
+    }
+
+    /// A call to the function itself, through the global it is stored in,
+    /// with as many arguments as it takes. Calls to anything else aren't
+    /// compiled.
+    fn call(
+        &mut self,
+        global: usize,
+        argc: usize,
+        stack: &mut Vec<(Value, Kind)>,
+        deopt: Block,
+        params: &[Value],
+    ) -> Option<Option<(Value, Kind)>> {
+        if *self.heap.get(global) != Type::FuncPtr(self.start) || argc != self.args.len() {
+            return None;
+        }
+
+        // the arguments were pushed last to first
+        let mut args = vec![];
+        for kind in self.args {
+            let (value, k) = stack.pop()?;
+            if k != *kind {
+                return Some(None);
+            }
+            args.push(value);
+        }
+
+        // the global still has to hold this function
+        let b = &mut self.builder;
+        let version = b.ins().load(
+            types::I32,
+            MemFlags::trusted(),
+            params[1],
+            (global * 4) as i32,
+        );
+        let changed = b
+            .ins()
+            .icmp_imm(IntCC::NotEqual, version, self.versions[global] as i64);
+        self.guard(changed, deopt);
+
+        let slot = self.slot(self.args.len().max(1));
+        let out = self.slot(1);
+        for (i, (value, kind)) in args.into_iter().zip(self.args).enumerate() {
+            let bits = self.pack(value, *kind);
+            self.builder.ins().stack_store(bits, slot, (i * 8) as i32);
+        }
+
+        let b = &mut self.builder;
+        let args = b.ins().stack_addr(self.pointer, slot, 0);
+        let result = b.ins().stack_addr(self.pointer, out, 0);
+        let depth = b.ins().iadd_imm(params[2], 1);
+        let call = b.ins().call(self.this, &[args, params[1], depth, result]);
+        let finished = b.inst_results(call)[0];
+        let gave_up = b.ins().icmp_imm(IntCC::Equal, finished, 0);
+        self.guard(gave_up, deopt);
+
+        let bits = self.builder.ins().stack_load(types::I64, out, 0);
+        let value = self.unpack(bits, self.ret);
+        Some(Some((value, self.ret)))
+    }
+
+    fn slot(&mut self, words: usize) -> StackSlot {
+        self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            (words * 8) as u32,
+            3,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::vm::VM;
+    use crate::frontend::parser;
+
+    // runs `code` and returns the global it declared last, and how many
+    // functions ended up compiled
+    fn result(code: &str) -> (Type, usize) {
+        let (program, _) = Instr::compile(parser::parse_code(code).unwrap());
+        let mut vm = VM::new();
+        vm.load(program);
+        vm.run();
+        let compiled = (vm.jit.functions.values())
+            .filter(|state| matches!(state, State::Compiled(_)))
+            .count();
+        (vm.heap.get(vm.heap.len() - 1).clone(), compiled)
+    }
+
+    #[test]
+    fn compiles_hot_functions() {
+        let fib = "fn fib(n) { if (n < 2) { return n }\nreturn fib(n - 1) + fib(n - 2) }\n";
+        assert_eq!(
+            result(&format!("{}let result = fib(20)", fib)),
+            (Type::Int(6765), 1)
+        );
+        // arguments it wasn't compiled for are interpreted
+        assert_eq!(
+            result(&format!("{}fib(20)\nlet result = fib(10.0)", fib)),
+            (Type::Number(55.0), 1)
+        );
+    }
+
+    #[test]
+    fn gives_up_on_overflow() {
+        // hot enough to compile first, then doubled past 64 bits
+        let (result, compiled) = result(
+            "fn double(n) { return n * 2 }\nlet i = 0\nwhile (i < 2000) { double(i)\ni += 1 }\nlet n = 1\ni = 0\nwhile (i < 70) { n = double(n)\ni += 1 }\nlet result = n",
+        );
+        assert_eq!(result.to_string(), "1180591620717411303424");
+        assert_eq!(compiled, 1);
+    }
+
+    #[test]
+    fn interprets_what_it_cant_compile() {
+        let (result, _) = result(
+            "fn greet(n) { return \"hi \" .. n }\nlet i = 0\nlet result = none\nwhile (i < 1000) { result = greet(i)\ni += 1 }",
+        );
+        assert_eq!(result.to_string(), "hi 999");
+    }
+}
@@ -1,113 +1,267 @@
 // use hashbrown::HashMap;
 // use fxhash::FxHashMap;
 
-use super::instruction::Type;
+use std::{
+    fmt,
+    rc::{Rc, Weak},
+};
+
+use super::instruction::{Type, Variant};
 
 #[allow(non_camel_case_types)]
 pub type addr = usize;
 
-// pub struct Memory(FxHashMap<addr, Type>);
-
-// impl Memory {
-//     pub fn new() -> Memory {
-//         Memory(FxHashMap::default())
-//     }
-
-//     pub fn get<'a>(&'a self, addr: addr) -> &'a Type {
-//         self.0.get(&addr).expect("Address not found")
-//         // self.internal.get(&addr).unwrap_or(&Type::Null)
-//     }
-
-//     pub fn set(&mut self, addr: addr, value: Type) {
-//         self.0.insert(addr, value);
-//         // if let None = self.internal.insert(addr, value) {
-//         //     // self.size += 1;
-//         // }
-//     }
-
-//     pub fn delete(&mut self, addr: addr) {
-//         // self.size -= 1;
-//         // self.internal.len();
-//         self.0.remove(&addr);
-//     }
-
-//     pub fn add(&mut self, value: Type) -> addr {
-//         // self.size += 1;
-//         let size = self.0.len();
-//         self.0.insert(size, value);
-//         // self.size
-//         size
-//     }
-// }
-
-pub struct Memory(
-    pub Vec<Type>,
-    // pub next_addr: usize,
-);
+// live objects at which the first collection runs, later ones run once the
+// heap has doubled since the last
+const FIRST_COLLECTION: usize = 1024;
 
-impl Memory {
-    pub fn new() -> Memory {
-        Memory(
-            // internal: Vec::with_capacity(u16::MAX as usize),
-            Vec::new(),
-            // next_addr: 0,
+/// An object on the heap. `Memory` owns every object and values only point
+/// at them, so an object lives exactly as long as the collector finds it
+/// reachable from the stack, a call frame or a global.
+pub struct Gc<T>(Weak<T>);
+
+impl<T> Gc<T> {
+    pub fn get(&self) -> Rc<T> {
+        self.0
+            .upgrade()
+            .expect("Object was collected while it was still reachable")
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        Gc(self.0.clone())
+    }
+}
+
+impl<T: PartialEq> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ptr_eq(&other.0) || *self.get() == *other.get()
+    }
+}
+
+/// What the collector has done so far, as `gc_stats()` shows it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub collections: usize,
+    pub allocated: usize,
+    pub freed: usize,
+    pub live: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "collections: {}, allocated: {}, freed: {}, live: {}",
+            self.collections, self.allocated, self.freed, self.live
         )
     }
+}
+
+/// Slots for globals, then the slots of every live call frame, and the
+/// objects values on either point to.
+pub struct Memory {
+    slots: Vec<Type>,
+
+    objects: Vec<Option<Rc<Variant>>>,
+    // indices into `objects` that a collection emptied, reused first
+    free: Vec<usize>,
+    threshold: usize,
+    // collects on every allocation, to find values the roots miss
+    pub stress: bool,
+    stats: Stats,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    // pub fn push(&mut self, value: Type) {
-    //     self.0.push(value);
-    //     // self.next_addr += 1;
-    // }
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            slots: Vec::new(),
+            objects: Vec::new(),
+            free: Vec::new(),
+            threshold: FIRST_COLLECTION,
+            stress: false,
+            stats: Stats::default(),
+        }
+    }
 
     pub fn add(&mut self, value: Type) -> usize {
-        let next_addr = self.0.len();
-        self.0.push(value);
-
-        // self.next_addr - 1
+        let next_addr = self.slots.len();
+        self.slots.push(value);
         next_addr
     }
 
     pub fn get(&self, index: usize) -> &Type {
-        &self.0[index]
+        &self.slots[index]
     }
 
     pub fn get_mut(&mut self, index: usize) -> &mut Type {
-        &mut self.0[index]
+        &mut self.slots[index]
     }
 
-    // pub fn pop(&mut self, index: usize) -> Type {
-    //     self.internal.remove(index)
-    // }
-
     pub fn set(&mut self, index: usize, value: Type) {
-        if index >= self.0.len() {
-            self.0.resize(index + 1, Type::Null);
+        if index >= self.slots.len() {
+            self.slots.resize(index + 1, Type::Null);
         }
 
-        self.0[index] = value;
+        self.slots[index] = value;
     }
 
-    // pub fn delete(&mut self, index: usize) {
-    //     // self.internal.remove(index);
-    //     // self.internal[index] = Type::None;
-    //     self.next_addr -= 1;
-    // }
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
 
-    pub fn cleanup(&mut self) {
-        let mut amnt = 0;
-        for item in self.0.iter().rev() {
-            if item == &Type::Null {
-                amnt += 1;
-            } else {
-                self.0.drain(self.0.len() - amnt..);
-                return;
-            }
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Makes sure the first `amnt` addresses exist, so that globals are
+    /// never placed inside a call frame.
+    pub fn reserve(&mut self, amnt: usize) {
+        if self.slots.len() < amnt {
+            self.slots.resize(amnt, Type::Null);
         }
     }
 
-    pub fn free(&mut self, addr: usize) {
-        if addr < self.0.len() {
-            self.0[addr] = Type::Null;
+    pub fn truncate(&mut self, len: usize) {
+        self.slots.truncate(len);
+    }
+
+    /// Puts `variant` on the heap. This may collect first, so `roots` has to
+    /// hold every value the caller has that isn't in a slot, like the stack.
+    pub fn alloc(&mut self, variant: Variant, roots: &[Type]) -> Type {
+        if self.stress || self.stats.live >= self.threshold {
+            self.collect(roots.iter().chain(&variant.fields));
+            self.threshold = (self.stats.live * 2).max(FIRST_COLLECTION);
+        }
+
+        let object = Rc::new(variant);
+        let value = Type::Variant(Gc(Rc::downgrade(&object)));
+        match self.free.pop() {
+            Some(index) => self.objects[index] = Some(object),
+            None => self.objects.push(Some(object)),
+        }
+
+        self.stats.allocated += 1;
+        self.stats.live += 1;
+        value
+    }
+
+    /// Frees every object that can't be reached from a slot or from
+    /// `roots`, returning how many there were.
+    pub fn collect<'a>(&mut self, roots: impl IntoIterator<Item = &'a Type>) -> usize {
+        // mark
+        let mut reached: Vec<Rc<Variant>> = vec![];
+        let reach = |value: &Type, reached: &mut Vec<Rc<Variant>>| {
+            if let Type::Variant(gc) = value {
+                let object = gc.get();
+                if !object.marked.replace(true) {
+                    reached.push(object);
+                }
+            }
+        };
+        for value in &self.slots {
+            reach(value, &mut reached);
+        }
+        for value in roots {
+            reach(value, &mut reached);
+        }
+        while let Some(object) = reached.pop() {
+            for field in &object.fields {
+                reach(field, &mut reached);
+            }
         }
+
+        // sweep
+        let mut freed = 0;
+        for (index, entry) in self.objects.iter_mut().enumerate() {
+            match entry {
+                Some(object) if object.marked.replace(false) => {}
+                Some(_) => {
+                    *entry = None;
+                    self.free.push(index);
+                    freed += 1;
+                }
+                None => {}
+            }
+        }
+
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+        self.stats.live -= freed;
+        freed
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::instruction::EnumDef;
+
+    fn variant(fields: Vec<Type>) -> Variant {
+        let def = Rc::new(EnumDef {
+            name: "E".to_owned(),
+            variants: vec![("A".to_owned(), fields.len())],
+        });
+        Variant::new(def, 0, fields)
+    }
+
+    #[test]
+    fn collects_what_nothing_reaches() {
+        let mut memory = Memory::new();
+        memory.reserve(1);
+
+        let inner = memory.alloc(variant(vec![Type::Int(1)]), &[]);
+        let outer = memory.alloc(variant(vec![inner]), &[]);
+        memory.set(0, outer);
+        let garbage = memory.alloc(variant(vec![]), &[]);
+        let on_stack = memory.alloc(variant(vec![]), &[]);
+
+        // the slot reaches both nested objects, the stack one more
+        assert_eq!(memory.collect([&on_stack]), 1);
+        assert_eq!(memory.stats().live, 3);
+        assert_eq!(memory.get(0).to_string(), "E.A(E.A(1))");
+        assert_eq!(on_stack.to_string(), "E.A");
+        drop(garbage);
+
+        // freed places are reused before the heap grows
+        let objects = memory.objects.len();
+        memory.alloc(variant(vec![]), &[]);
+        assert_eq!(memory.objects.len(), objects);
+
+        memory.set(0, Type::None);
+        assert_eq!(memory.collect([]), 4);
+        assert_eq!(
+            memory.stats(),
+            Stats {
+                collections: 2,
+                allocated: 5,
+                freed: 5,
+                live: 0
+            }
+        );
+    }
+
+    #[test]
+    fn stress_collects_on_every_allocation() {
+        let mut memory = Memory::new();
+        memory.stress = true;
+
+        let field = memory.alloc(variant(vec![]), &[]);
+        // the new object's fields are roots while it is being allocated
+        let pair = memory.alloc(variant(vec![field.clone(), field]), &[]);
+        memory.alloc(variant(vec![]), std::slice::from_ref(&pair));
+        assert_eq!(pair.to_string(), "E.A(E.A, E.A)");
+        assert_eq!(memory.stats().collections, 3);
     }
 }
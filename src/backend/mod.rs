@@ -1,5 +1,14 @@
+pub mod fuse;
+pub mod image;
 pub mod instruction;
+#[cfg(feature = "jit")]
+pub mod jit;
 pub mod memory;
+pub mod module;
+pub mod ops;
+pub mod register;
 pub mod stack;
 pub mod stdlib;
 pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -0,0 +1,426 @@
+use std::{
+    env, fmt,
+    fs::read_to_string,
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use peg::{str::LineCol, Parse};
+
+use crate::frontend::{
+    parser,
+    resolver::{self, Diagnostic},
+    second, Expr, Pos, AST,
+};
+
+use super::instruction::{Instr, Program, State};
+
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub path: PathBuf,
+    // exported name -> global address
+    pub exports: FxHashMap<String, usize>,
+}
+
+#[derive(Debug)]
+pub enum ModuleError {
+    NotFound {
+        import: String,
+        from: PathBuf,
+        searched: Vec<PathBuf>,
+    },
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, peg::error::ParseError<peg::str::LineCol>),
+    Cycle(Vec<PathBuf>),
+    MissingExport {
+        module: PathBuf,
+        name: String,
+    },
+    Resolve {
+        path: PathBuf,
+        diagnostics: Vec<(LineCol, Diagnostic)>,
+    },
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // diagnostics carry their own severity
+        if !matches!(self, ModuleError::Resolve { .. }) {
+            write!(f, "error: ")?;
+        }
+
+        match self {
+            ModuleError::NotFound {
+                import,
+                from,
+                searched,
+            } => {
+                write!(
+                    f,
+                    "module {:?} imported from {} not found",
+                    import,
+                    from.display()
+                )?;
+                for path in searched {
+                    write!(f, "\n  searched: {}", path.display())?;
+                }
+                Ok(())
+            }
+            ModuleError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ModuleError::Parse(path, e) => write!(f, "{}:{}: {}", path.display(), e.location, e),
+            ModuleError::Cycle(cycle) => {
+                write!(f, "import cycle detected: ")?;
+                for (i, path) in cycle.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "{}", path.display())?;
+                }
+                Ok(())
+            }
+            ModuleError::MissingExport { module, name } => {
+                write!(
+                    f,
+                    "module {} has no export named {}",
+                    module.display(),
+                    name
+                )
+            }
+            ModuleError::Resolve { path, diagnostics } => {
+                for (i, (location, diagnostic)) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}:{}: {}", path.display(), location, diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ModuleError {}
+
+/// What `Loader` compiles imported modules into, the code of each backend.
+pub(crate) trait Target {
+    fn build_module(
+        &mut self,
+        code: AST,
+        path: &Path,
+        state: &mut State,
+        next: &mut usize,
+        loader: &mut Loader,
+    ) -> Result<(), ModuleError>;
+}
+
+impl Target for Program {
+    fn build_module(
+        &mut self,
+        code: AST,
+        path: &Path,
+        state: &mut State,
+        next: &mut usize,
+        loader: &mut Loader,
+    ) -> Result<(), ModuleError> {
+        Instr::build_module(self, code, path, state, next, loader)
+    }
+}
+
+/// Resolves, parses and compiles imported modules. Every module is compiled
+/// once, into its own namespace, the first time it is imported.
+pub struct Loader {
+    pub search_path: Vec<PathBuf>,
+    // whether the stack VM's code gets superinstructions
+    pub fuse: bool,
+
+    loaded: FxHashMap<PathBuf, Rc<Module>>,
+    importing: Vec<PathBuf>,
+    sources: FxHashMap<PathBuf, String>,
+    // modules parsed to check what they export, before they are loaded
+    parsed: FxHashMap<PathBuf, AST>,
+    pub(crate) prelude: State,
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl Loader {
+    pub fn new(search_path: Vec<PathBuf>) -> Loader {
+        Loader {
+            search_path,
+            fuse: true,
+            loaded: FxHashMap::default(),
+            importing: vec![],
+            sources: FxHashMap::default(),
+            parsed: FxHashMap::default(),
+            prelude: State::default(),
+        }
+    }
+
+    /// Uses the directories listed in `GLASS_PATH` as the search path.
+    pub fn from_env() -> Loader {
+        let search_path = env::var_os("GLASS_PATH")
+            .map(|paths| env::split_paths(&paths).collect())
+            .unwrap_or_default();
+
+        Self::new(search_path)
+    }
+
+    pub fn parse(&mut self, path: &Path) -> Result<AST, ModuleError> {
+        let code = read_to_string(path).map_err(|e| ModuleError::Io(path.to_owned(), e))?;
+        let ast = parser::parse_code(&code).map_err(|e| ModuleError::Parse(path.to_owned(), e))?;
+
+        // kept around to point diagnostics at lines and columns
+        self.sources.insert(path.to_owned(), code);
+        Ok(ast)
+    }
+
+    pub fn location(&self, path: &Path, pos: Pos) -> LineCol {
+        match self.sources.get(path) {
+            Some(code) => code.as_str().position_repr(pos),
+            None => LineCol {
+                line: 0,
+                column: 0,
+                offset: pos,
+            },
+        }
+    }
+
+    /// Finds `import` relative to the importing file first, then in each
+    /// directory of the search path.
+    pub fn resolve(&self, from: &Path, import: &str) -> Result<PathBuf, ModuleError> {
+        let base = from.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut searched = vec![];
+        for dir in std::iter::once(base).chain(self.search_path.iter().map(PathBuf::as_path)) {
+            let candidate = dir.join(import);
+            if candidate.is_file() {
+                return candidate
+                    .canonicalize()
+                    .map_err(|e| ModuleError::Io(candidate, e));
+            }
+            searched.push(candidate);
+        }
+
+        Err(ModuleError::NotFound {
+            import: import.to_owned(),
+            from: from.to_owned(),
+            searched,
+        })
+    }
+
+    /// Reports undefined names and the like in the code of the module at
+    /// `path`, then optimizes it.
+    pub(crate) fn check(
+        &mut self,
+        code: AST,
+        path: &Path,
+        state: &State,
+    ) -> Result<AST, ModuleError> {
+        let modules = self.exports(&code, path);
+        let diagnostics = resolver::resolve(&code, state.keys(), modules);
+        if !diagnostics.is_empty() {
+            return Err(ModuleError::Resolve {
+                path: path.to_owned(),
+                diagnostics: diagnostics
+                    .into_iter()
+                    .map(|d| (self.location(path, d.pos), d))
+                    .collect(),
+            });
+        }
+
+        Ok(second::optimize(code))
+    }
+
+    /// What each module `code` imports exports, by import path, so that
+    /// `module.name` is checked before anything is compiled. Modules that
+    /// can't be found or parsed are left out, loading them reports that.
+    fn exports(&mut self, code: &AST, from: &Path) -> FxHashMap<String, FxHashSet<String>> {
+        let mut modules = FxHashMap::default();
+        for expr in code {
+            let Expr::Import { path: import, .. } = expr else {
+                continue;
+            };
+            let Ok(path) = self.resolve(from, import) else {
+                continue;
+            };
+
+            let exports = match self.loaded.get(&path) {
+                Some(module) => module.exports.keys().cloned().collect(),
+                // part of a cycle, which loading reports
+                None if self.importing.contains(&path) => continue,
+                None => {
+                    if !self.parsed.contains_key(&path) {
+                        let Ok(ast) = self.parse(&path) else {
+                            continue;
+                        };
+                        self.parsed.insert(path.clone(), ast);
+                    }
+                    resolver::exports(&self.parsed[&path])
+                }
+            };
+            modules.insert(import.clone(), exports);
+        }
+        modules
+    }
+
+    pub(crate) fn enter(&mut self, path: &Path) {
+        self.importing
+            .push(path.canonicalize().unwrap_or_else(|_| path.to_owned()));
+    }
+
+    pub(crate) fn exit(&mut self) {
+        self.importing.pop();
+    }
+
+    /// Returns the module at `import`, compiling its code into `ins` if this
+    /// is the first time it is imported.
+    pub(crate) fn load(
+        &mut self,
+        ins: &mut impl Target,
+        from: &Path,
+        import: &str,
+        next: &mut usize,
+    ) -> Result<Rc<Module>, ModuleError> {
+        let path = self.resolve(from, import)?;
+
+        if let Some(module) = self.loaded.get(&path) {
+            return Ok(module.clone());
+        }
+
+        if let Some(start) = self.importing.iter().position(|p| *p == path) {
+            let mut cycle = self.importing[start..].to_vec();
+            cycle.push(path);
+            return Err(ModuleError::Cycle(cycle));
+        }
+
+        let ast = match self.parsed.remove(&path) {
+            Some(ast) => ast,
+            None => self.parse(&path)?,
+        };
+        let mut state = self.prelude.clone();
+
+        self.enter(&path);
+        ins.build_module(ast, &path, &mut state, next, self)?;
+        self.exit();
+
+        let exports = state
+            .into_iter()
+            .filter(|(name, entry)| self.prelude.get(name) != Some(entry))
+            .map(|(name, (id, _))| (name, id))
+            .collect();
+
+        let module = Rc::new(Module {
+            name: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            path: path.clone(),
+            exports,
+        });
+
+        self.loaded.insert(path, module.clone());
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // a directory of scripts that is removed again when dropped
+    struct Scripts(PathBuf);
+
+    impl Scripts {
+        fn new(name: &str, files: &[(&str, &str)]) -> Scripts {
+            let dir = env::temp_dir().join(format!("glass-{}-{}", name, std::process::id()));
+            for (file, code) in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, code).unwrap();
+            }
+            Scripts(dir.canonicalize().unwrap())
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+
+        fn compile(&self, file: &str, search_path: Vec<PathBuf>) -> Result<(), ModuleError> {
+            let mut loader = Loader::new(search_path);
+            Instr::compile_file(&self.path(file), &mut loader).map(|_| ())
+        }
+    }
+
+    impl Drop for Scripts {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn search_path() {
+        let scripts = Scripts::new(
+            "search-path",
+            &[
+                ("main.rv", "import \"utils.rv\"\nprint(utils.double(2))"),
+                ("lib/utils.rv", "fn double(n) { return n * 2 }"),
+            ],
+        );
+        let lib = scripts.path("lib");
+
+        let loader = Loader::new(vec![lib.clone()]);
+        assert_eq!(
+            loader
+                .resolve(&scripts.path("main.rv"), "utils.rv")
+                .unwrap(),
+            lib.join("utils.rv")
+        );
+        assert!(scripts.compile("main.rv", vec![lib]).is_ok());
+
+        let Err(ModuleError::NotFound { searched, .. }) = scripts.compile("main.rv", vec![]) else {
+            panic!("found utils.rv without the search path");
+        };
+        assert_eq!(searched, [scripts.path("utils.rv")]);
+    }
+
+    #[test]
+    fn cycles_and_exports() {
+        let scripts = Scripts::new(
+            "cycles",
+            &[
+                ("a.rv", "import \"b.rv\""),
+                ("b.rv", "import \"a.rv\""),
+                ("main.rv", "from \"c.rv\" import missing"),
+                ("c.rv", "let present = 1"),
+            ],
+        );
+
+        let Err(ModuleError::Cycle(cycle)) = scripts.compile("a.rv", vec![]) else {
+            panic!("no cycle reported");
+        };
+        assert_eq!(
+            cycle,
+            [
+                scripts.path("a.rv"),
+                scripts.path("b.rv"),
+                scripts.path("a.rv")
+            ]
+        );
+
+        let Err(ModuleError::MissingExport { name, .. }) = scripts.compile("main.rv", vec![])
+        else {
+            panic!("no missing export reported");
+        };
+        assert_eq!(name, "missing");
+    }
+}
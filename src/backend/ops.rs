@@ -0,0 +1,756 @@
+use std::{cmp::Ordering, fmt, rc::Rc};
+
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
+
+use crate::frontend::{Align, FormatKind, FormatSpec};
+
+use super::{
+    instruction::{EnumDef, Type, Variant},
+    memory::Memory,
+};
+
+// what the operators and other instructions do to values, shared by the
+// stack and register VMs
+
+/// `+`, which also concatenates when either side is a string.
+#[inline]
+pub fn add(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => lhs
+            .checked_add(*rhs)
+            .map_or_else(|| normalize(BigInt::from(*lhs) + rhs), Type::Int),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs + rhs),
+        (Type::String(_), _) | (_, Type::String(_)) => {
+            Type::String(format!("{}{}", lhs, rhs).into())
+        }
+        (lhs, rhs) => numeric(
+            lhs,
+            rhs,
+            |lhs, rhs| normalize(lhs + rhs),
+            |lhs, rhs| Type::Number(lhs + rhs),
+        )
+        .unwrap_or_else(|| panic!("Addition not supported")),
+    }
+}
+
+#[inline]
+pub fn sub(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => lhs
+            .checked_sub(*rhs)
+            .map_or_else(|| normalize(BigInt::from(*lhs) - rhs), Type::Int),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs - rhs),
+        (lhs, rhs) => numeric(
+            lhs,
+            rhs,
+            |lhs, rhs| normalize(lhs - rhs),
+            |lhs, rhs| Type::Number(lhs - rhs),
+        )
+        .unwrap_or_else(|| panic!("Subtraction not supported")),
+    }
+}
+
+#[inline]
+pub fn mul(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => lhs
+            .checked_mul(*rhs)
+            .map_or_else(|| normalize(BigInt::from(*lhs) * rhs), Type::Int),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs * rhs),
+        (lhs, rhs) => numeric(
+            lhs,
+            rhs,
+            |lhs, rhs| normalize(lhs * rhs),
+            |lhs, rhs| Type::Number(lhs * rhs),
+        )
+        .unwrap_or_else(|| panic!("Multiplication not supported")),
+    }
+}
+
+#[inline]
+pub fn div(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        // `/` always divides exactly, `~/` is the integer division
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs / rhs),
+        (lhs, rhs) => match promote(lhs, rhs) {
+            Some((lhs, rhs)) => Type::Number(lhs / rhs),
+            None => panic!("Division not supported"),
+        },
+    }
+}
+
+#[inline]
+pub fn floor_div(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) if *rhs != 0 && (*lhs, *rhs) != (i64::MIN, -1) => {
+            Type::Int(lhs.div_floor(rhs))
+        }
+        (lhs, rhs) => numeric(
+            lhs,
+            rhs,
+            |lhs, rhs| {
+                if rhs.is_zero() {
+                    panic!("Integer division by zero");
+                }
+                normalize(lhs.div_floor(&rhs))
+            },
+            |lhs, rhs| Type::Number((lhs / rhs).floor()),
+        )
+        .unwrap_or_else(|| panic!("Integer division not supported")),
+    }
+}
+
+/// `%`, the remainder of `~/`, so it takes the sign of the divisor and
+/// `a == (a ~/ b) * b + a % b` holds.
+#[inline]
+pub fn modulo(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) if *rhs != 0 => {
+            let rem = lhs.wrapping_rem(*rhs);
+            if rem != 0 && (rem < 0) != (*rhs < 0) {
+                Type::Int(rem + rhs)
+            } else {
+                Type::Int(rem)
+            }
+        }
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Number(float_mod(*lhs, *rhs)),
+        (lhs, rhs) => numeric(
+            lhs,
+            rhs,
+            |lhs, rhs| {
+                if rhs.is_zero() {
+                    panic!("Modulo by zero");
+                }
+                normalize(lhs.mod_floor(&rhs))
+            },
+            |lhs, rhs| Type::Number(float_mod(lhs, rhs)),
+        )
+        .unwrap_or_else(|| panic!("Modulo not supported")),
+    }
+}
+
+#[inline]
+pub fn pow(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) if (0..=u32::MAX as i64).contains(rhs) => lhs
+            .checked_pow(*rhs as u32)
+            .map_or_else(|| big_pow(&BigInt::from(*lhs), *rhs as u32), Type::Int),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs.powf(*rhs)),
+        (lhs, rhs) => numeric(
+            lhs,
+            rhs,
+            |lhs, rhs| match rhs.to_u32() {
+                Some(rhs) => big_pow(&lhs, rhs),
+                // a negative power of an integer is a fraction
+                None if rhs.is_negative() => Type::Number(big_to_f64(&lhs).powf(big_to_f64(&rhs))),
+                None => panic!("Exponent too large"),
+            },
+            |lhs, rhs| Type::Number(lhs.powf(rhs)),
+        )
+        .unwrap_or_else(|| panic!("Power not supported")),
+    }
+}
+
+fn big_pow(lhs: &BigInt, rhs: u32) -> Type {
+    // 0, 1 and -1 never grow, anything else by about its bits per power
+    if lhs.bits() > 1 && lhs.bits().saturating_mul(rhs as u64) > MAX_BITS as u64 {
+        panic!("Exponent too large");
+    }
+    normalize(lhs.pow(rhs))
+}
+
+/// `==` and `!=` work on any two values, see `Type`'s `PartialEq`.
+#[inline]
+pub fn eq(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Type::Bool(lhs == rhs),
+        (lhs, rhs) => Type::Bool(lhs == rhs),
+    }
+}
+
+#[inline]
+pub fn neq(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Type::Bool(lhs != rhs),
+        (lhs, rhs) => Type::Bool(lhs != rhs),
+    }
+}
+
+#[inline]
+pub fn lt(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Type::Bool(lhs < rhs),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs < rhs),
+        (lhs, rhs) => Type::Bool(matches!(compare(lhs, rhs, "<"), Some(Ordering::Less))),
+    }
+}
+
+#[inline]
+pub fn gt(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Type::Bool(lhs > rhs),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs > rhs),
+        (lhs, rhs) => Type::Bool(matches!(compare(lhs, rhs, ">"), Some(Ordering::Greater))),
+    }
+}
+
+#[inline]
+pub fn lte(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Type::Bool(lhs <= rhs),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs <= rhs),
+        (lhs, rhs) => Type::Bool(matches!(
+            compare(lhs, rhs, "<="),
+            Some(Ordering::Less | Ordering::Equal)
+        )),
+    }
+}
+
+#[inline]
+pub fn gte(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Type::Bool(lhs >= rhs),
+        (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs >= rhs),
+        (lhs, rhs) => Type::Bool(matches!(
+            compare(lhs, rhs, ">="),
+            Some(Ordering::Greater | Ordering::Equal)
+        )),
+    }
+}
+
+#[inline]
+pub fn bit_and(lhs: &Type, rhs: &Type) -> Type {
+    bitwise(lhs, rhs, |lhs, rhs| lhs & rhs, |lhs, rhs| lhs & rhs, "and")
+}
+
+#[inline]
+pub fn bit_or(lhs: &Type, rhs: &Type) -> Type {
+    bitwise(lhs, rhs, |lhs, rhs| lhs | rhs, |lhs, rhs| lhs | rhs, "or")
+}
+
+#[inline]
+pub fn bit_xor(lhs: &Type, rhs: &Type) -> Type {
+    bitwise(lhs, rhs, |lhs, rhs| lhs ^ rhs, |lhs, rhs| lhs ^ rhs, "xor")
+}
+
+// the most bits a left shift or a power can make, so a typo can't ask for
+// gigabytes
+const MAX_BITS: usize = 1 << 22;
+
+#[inline]
+pub fn shl(lhs: &Type, rhs: &Type) -> Type {
+    let shift = shift_amount(lhs, rhs, "left");
+    if shift > MAX_BITS {
+        panic!("Shift left by too large an amount");
+    }
+    match lhs {
+        Type::Int(lhs) if shift < 64 && (lhs << shift) >> shift == *lhs => Type::Int(lhs << shift),
+        Type::Int(lhs) => normalize(BigInt::from(*lhs) << shift),
+        Type::BigInt(lhs) => normalize(lhs.as_ref() << shift),
+        _ => unreachable!(),
+    }
+}
+
+#[inline]
+pub fn shr(lhs: &Type, rhs: &Type) -> Type {
+    let shift = shift_amount(lhs, rhs, "right");
+    match lhs {
+        // shifting out every bit leaves just the sign
+        Type::Int(lhs) => Type::Int(lhs >> shift.min(63)),
+        Type::BigInt(lhs) => normalize(lhs.as_ref() >> shift),
+        _ => unreachable!(),
+    }
+}
+
+/// `..`, where at least one side has to be a string.
+#[inline]
+pub fn join(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::String(_), _) | (_, Type::String(_)) => {
+            Type::String(format!("{}{}", lhs, rhs).into())
+        }
+        _ => panic!("Join not supported"),
+    }
+}
+
+#[inline]
+pub fn not(value: &Type) -> Type {
+    Type::Bool(!value.is_truthy())
+}
+
+#[inline]
+pub fn neg(value: &Type) -> Type {
+    match value {
+        Type::Int(value) => value
+            .checked_neg()
+            .map_or_else(|| normalize(-BigInt::from(*value)), Type::Int),
+        Type::BigInt(value) => normalize(-value.as_ref()),
+        Type::Number(value) => Type::Number(-value),
+        _ => panic!("Negation not supported"),
+    }
+}
+
+#[inline]
+pub fn bit_not(value: &Type) -> Type {
+    match value {
+        Type::Int(value) => Type::Int(!value),
+        Type::BigInt(value) => normalize(!value.as_ref()),
+        _ => panic!("Bitwise not is only supported on integers, not {:?}", value),
+    }
+}
+
+/// `item[index]`, only strings can be indexed for now.
+pub fn index(item: &Type, index: &Type) -> Type {
+    match (item, index) {
+        // (Type::List(list), Type::Number(index)) => {
+        //     let index = index as usize;
+        //     if index >= list.len() {
+        //         panic!("Index out of bounds");
+        //     }
+        //     list[index].clone()
+        // }
+        (Type::String(string), Type::Int(index)) => {
+            let c = usize::try_from(*index)
+                .ok()
+                .and_then(|index| string.chars().nth(index))
+                .unwrap_or_else(|| panic!("Index out of bounds"));
+            Type::String(c.to_string().into())
+        }
+        (Type::String(_), Type::BigInt(_)) => panic!("Index out of bounds"),
+        (Type::String(_), index) => {
+            panic!("String indices must be integers, not {:?}", index)
+        }
+        _ => panic!("Index not supported"),
+    }
+}
+
+/// `Enum.name`, the variant itself when it has no fields, otherwise its
+/// constructor. A variant goes on `heap`, see `Memory::alloc` for `roots`.
+pub fn variant(def: &Rc<EnumDef>, name: &str, heap: &mut Memory, roots: &[Type]) -> Type {
+    let tag = def
+        .variants
+        .iter()
+        .position(|(variant, _)| variant == name)
+        .unwrap_or_else(|| panic!("{} has no variant {}", def.name, name));
+
+    if def.variants[tag].1 == 0 {
+        heap.alloc(Variant::new(def.clone(), tag, vec![]), roots)
+    } else {
+        Type::Constructor(def.clone(), tag as u32)
+    }
+}
+
+/// Stops a call that passes `argc` values to a `callee` taking `takes`.
+#[inline]
+pub fn arity(callee: &Type, takes: usize, argc: usize) {
+    if argc == takes {
+        return;
+    }
+    let s = if takes == 1 { "" } else { "s" };
+    match callee {
+        Type::Constructor(def, tag) => panic!(
+            "{}.{} expects {} field{}, got {}",
+            def.name, def.variants[*tag as usize].0, takes, s, argc
+        ),
+        _ => panic!("Function expects {} argument{}, got {}", takes, s, argc),
+    }
+}
+
+/// Whether `value` is the variant `name` of the enum `def`, which a pattern
+/// names.
+pub fn is_variant(value: &Type, def: &Type, name: &str) -> Type {
+    match (value, def) {
+        (Type::Variant(v), Type::Enum(def)) => Type::Bool(v.get().is(def, name)),
+        (_, Type::Enum(_)) => Type::Bool(false),
+        (_, def) => panic!("Patterns need an enum, not {:?}", def),
+    }
+}
+
+pub fn field(value: &Type, i: usize) -> Type {
+    match value {
+        Type::Variant(v) => {
+            let v = v.get();
+            v.fields.get(i).cloned().unwrap_or_else(|| {
+                panic!(
+                    "{}.{} has no field {}",
+                    v.def.name, v.def.variants[v.tag].0, i
+                )
+            })
+        }
+        _ => panic!("Field access not supported on {:?}", value),
+    }
+}
+
+/// Writes `value` onto the end of `buffer` the way string interpolation
+/// shows it.
+pub fn push_str(buffer: &mut String, value: &Type) {
+    match value {
+        Type::String(s) => buffer.push_str(s),
+        value => buffer.push_str(&value.to_string()),
+    }
+}
+
+/// `%` on floats, a zero remainder keeps the divisor's sign too.
+#[inline]
+fn float_mod(lhs: f64, rhs: f64) -> f64 {
+    let rem = lhs % rhs;
+    if rem == 0.0 {
+        0f64.copysign(rhs)
+    } else if (rem < 0.0) != (rhs < 0.0) {
+        rem + rhs
+    } else {
+        rem
+    }
+}
+
+/// Both operands as floats, when one of them is a float and the other a
+/// number of any kind.
+#[inline]
+fn promote(lhs: &Type, rhs: &Type) -> Option<(f64, f64)> {
+    let float = |value: &Type| match value {
+        Type::Int(i) => Some(*i as f64),
+        Type::BigInt(i) => Some(big_to_f64(i)),
+        Type::Number(n) => Some(*n),
+        _ => None,
+    };
+
+    Some((float(lhs)?, float(rhs)?))
+}
+
+/// Arithmetic on any mix of ints, big ints and floats. Integers stay exact,
+/// anything involving a float is done in floats.
+#[inline]
+fn numeric(
+    lhs: &Type,
+    rhs: &Type,
+    int: impl FnOnce(BigInt, BigInt) -> Type,
+    float: impl FnOnce(f64, f64) -> Type,
+) -> Option<Type> {
+    match (lhs, rhs) {
+        (Type::Int(_) | Type::BigInt(_), Type::Int(_) | Type::BigInt(_)) => {
+            Some(int(big(lhs)?, big(rhs)?))
+        }
+        _ => promote(lhs, rhs).map(|(lhs, rhs)| float(lhs, rhs)),
+    }
+}
+
+fn big(value: &Type) -> Option<BigInt> {
+    match value {
+        Type::Int(i) => Some(BigInt::from(*i)),
+        Type::BigInt(i) => Some(i.as_ref().clone()),
+        _ => None,
+    }
+}
+
+fn big_to_f64(int: &BigInt) -> f64 {
+    int.to_f64().unwrap_or(f64::NAN)
+}
+
+/// Big ints that fit back into 64 bits become plain ints again, so every
+/// integer has exactly one representation.
+fn normalize(int: BigInt) -> Type {
+    match i64::try_from(&int) {
+        Ok(i) => Type::Int(i),
+        Err(_) => Type::BigInt(Rc::new(int)),
+    }
+}
+
+/// `&`, `|` and `^`, which only make sense on integers.
+#[inline]
+fn bitwise(
+    lhs: &Type,
+    rhs: &Type,
+    int: impl FnOnce(i64, i64) -> i64,
+    wide: impl FnOnce(BigInt, BigInt) -> BigInt,
+    op: &str,
+) -> Type {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Type::Int(int(*lhs, *rhs)),
+        (Type::Int(_) | Type::BigInt(_), Type::Int(_) | Type::BigInt(_)) => {
+            normalize(wide(big(lhs).unwrap(), big(rhs).unwrap()))
+        }
+        _ => panic!(
+            "Bitwise {} is only supported on integers, not {:?} and {:?}",
+            op, lhs, rhs
+        ),
+    }
+}
+
+/// Checks both operands of a shift, returning how far to shift.
+fn shift_amount(lhs: &Type, rhs: &Type, direction: &str) -> usize {
+    match (lhs, rhs) {
+        (Type::Int(_) | Type::BigInt(_), Type::Int(shift)) => usize::try_from(*shift)
+            .unwrap_or_else(|_| panic!("Shift {} by a negative amount", direction)),
+        (Type::Int(_) | Type::BigInt(_), Type::BigInt(_)) => {
+            panic!("Shift {} by too large an amount", direction)
+        }
+        _ => panic!(
+            "Shift {} is only supported on integers, not {:?} and {:?}",
+            direction, lhs, rhs
+        ),
+    }
+}
+
+/// Orders two values for `<`, `>`, `<=` and `>=`. Numbers compare by value,
+/// strings lexicographically and variants of the same enum by their variant
+/// then their fields. `None` means unordered, a NaN is involved.
+fn compare(lhs: &Type, rhs: &Type, op: &str) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Type::Int(lhs), Type::Int(rhs)) => Some(lhs.cmp(rhs)),
+        (Type::Int(_) | Type::BigInt(_), Type::Int(_) | Type::BigInt(_)) => {
+            Some(big(lhs)?.cmp(&big(rhs)?))
+        }
+        (Type::Number(lhs), Type::Number(rhs)) => lhs.partial_cmp(rhs),
+        (Type::Int(_) | Type::BigInt(_), Type::Number(rhs)) => cmp_int_float(lhs, *rhs),
+        (Type::Number(lhs), Type::Int(_) | Type::BigInt(_)) => {
+            cmp_int_float(rhs, *lhs).map(Ordering::reverse)
+        }
+        (Type::String(lhs), Type::String(rhs)) => Some(lhs.cmp(rhs)),
+        (Type::Variant(lhs), Type::Variant(rhs)) if Rc::ptr_eq(&lhs.get().def, &rhs.get().def) => {
+            let (lhs, rhs) = (lhs.get(), rhs.get());
+            let mut ordering = lhs.tag.cmp(&rhs.tag);
+            for (lhs, rhs) in lhs.fields.iter().zip(&rhs.fields) {
+                if ordering != Ordering::Equal {
+                    break;
+                }
+                ordering = compare(lhs, rhs, op)?;
+            }
+            Some(ordering)
+        }
+        _ => panic!(
+            "`{}` is not supported between {} and {}",
+            op,
+            lhs.type_name(),
+            rhs.type_name()
+        ),
+    }
+}
+
+/// Compares exactly, rather than rounding the integer to the nearest float.
+fn cmp_int_float(int: &Type, float: f64) -> Option<Ordering> {
+    if float.is_nan() {
+        return None;
+    }
+    if float.is_infinite() {
+        return Some(if float > 0.0 {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        });
+    }
+
+    let floor = BigInt::from_f64(float.floor())?;
+    let fraction = if float.fract() > 0.0 {
+        Ordering::Less
+    } else {
+        Ordering::Equal
+    };
+    Some(big(int)?.cmp(&floor).then(fraction))
+}
+
+/// Formats `value` the way an f-string interpolation with `spec` asks for.
+pub fn format_with(value: &Type, spec: &FormatSpec) -> String {
+    let number = matches!(value, Type::Int(_) | Type::BigInt(_) | Type::Number(_));
+
+    if !number || spec.kind == FormatKind::Debug {
+        let text = match spec.kind {
+            FormatKind::Display => value.to_string(),
+            FormatKind::Debug => value.repr(),
+            kind => panic!(
+                "Format `{}` is only supported on integers, not {:?}",
+                kind, value
+            ),
+        };
+        // a precision cuts strings short
+        let text = match spec.precision {
+            Some(precision) if spec.kind == FormatKind::Display => {
+                text.chars().take(precision).collect()
+            }
+            _ => text,
+        };
+        let align = if number { Align::Right } else { Align::Left };
+        return pad(text, spec, align);
+    }
+
+    let (negative, digits) = match value {
+        Type::Int(int) => (*int < 0, radix(&int.unsigned_abs(), spec)),
+        Type::BigInt(int) => (int.is_negative(), radix(int.magnitude(), spec)),
+        Type::Number(n) => {
+            let digits = match spec.kind {
+                FormatKind::Display => match spec.precision {
+                    Some(precision) => format!("{:.*}", precision, n.abs()),
+                    None => format!("{:?}", n.abs()),
+                },
+                kind => panic!(
+                    "Format `{}` is only supported on integers, not {:?}",
+                    kind, value
+                ),
+            };
+            (n.is_sign_negative() && !n.is_nan(), digits)
+        }
+        _ => unreachable!(),
+    };
+
+    let sign = match (negative, spec.sign) {
+        (true, _) => "-",
+        (false, true) => "+",
+        (false, false) => "",
+    };
+    let prefix = match spec.kind {
+        FormatKind::LowerHex | FormatKind::UpperHex if spec.alternate => "0x",
+        FormatKind::Binary if spec.alternate => "0b",
+        FormatKind::Octal if spec.alternate => "0o",
+        _ => "",
+    };
+
+    if spec.zero {
+        // zeros go between the sign and the digits
+        let len = sign.len() + prefix.len() + digits.len();
+        let zeros = "0".repeat(spec.width.saturating_sub(len));
+        return format!("{}{}{}{}", sign, prefix, zeros, digits);
+    }
+
+    pad(format!("{}{}{}", sign, prefix, digits), spec, Align::Right)
+}
+
+/// The digits of an integer's magnitude in the base `spec` asks for.
+fn radix<T>(int: &T, spec: &FormatSpec) -> String
+where
+    T: fmt::Display + fmt::LowerHex + fmt::UpperHex + fmt::Binary + fmt::Octal,
+{
+    match spec.kind {
+        FormatKind::LowerHex => format!("{:x}", int),
+        FormatKind::UpperHex => format!("{:X}", int),
+        FormatKind::Binary => format!("{:b}", int),
+        FormatKind::Octal => format!("{:o}", int),
+        _ => match spec.precision {
+            Some(precision) if precision > 0 => format!("{}.{}", int, "0".repeat(precision)),
+            _ => int.to_string(),
+        },
+    }
+}
+
+/// Fills `text` out to the width of `spec`.
+fn pad(text: String, spec: &FormatSpec, default: Align) -> String {
+    let len = text.chars().count();
+    if len >= spec.width {
+        return text;
+    }
+
+    let fill = |n: usize| spec.fill.to_string().repeat(n);
+    let padding = spec.width - len;
+    match spec.align.unwrap_or(default) {
+        Align::Left => text + &fill(padding),
+        Align::Right => fill(padding) + &text,
+        Align::Center => fill(padding / 2) + &text + &fill(padding - padding / 2),
+    }
+}
+
+// fn replace_nth_char_ascii(s: &mut str, idx: usize, newchar: char) {
+//     let s_bytes: &mut [u8] = unsafe { s.as_bytes_mut() };
+//     assert!(idx < s_bytes.len());
+//     assert!(s_bytes[idx].is_ascii());
+//     assert!(newchar.is_ascii());
+//     // we've made sure this is safe.
+//     s_bytes[idx] = newchar as u8;
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> Type {
+        Type::Int(i)
+    }
+
+    fn big(i: &str) -> Type {
+        Type::BigInt(Rc::new(i.parse().unwrap()))
+    }
+
+    #[test]
+    fn remainders() {
+        // `%` takes the sign of the divisor, like `~/` rounds down
+        for (a, b) in [(7, 2), (-7, 2), (7, -2), (-7, -2), (6, -3), (i64::MIN, -1)] {
+            let (a, b) = (int(a), int(b));
+            let back = add(&mul(&floor_div(&a, &b), &b), &modulo(&a, &b));
+            assert_eq!(back, a, "{:?} ~/ {:?} and % don't agree", a, b);
+        }
+        assert_eq!(modulo(&int(-7), &int(2)), int(1));
+        assert_eq!(modulo(&int(7), &int(-2)), int(-1));
+        assert_eq!(modulo(&big("-18446744073709551617"), &int(2)), int(1));
+        assert_eq!(
+            modulo(&int(5), &big("-18446744073709551616")),
+            big("-18446744073709551611")
+        );
+        assert_eq!(modulo(&Type::Number(-7.5), &int(2)), Type::Number(0.5));
+        assert_eq!(
+            modulo(&Type::Number(7.5), &Type::Number(-2.0)),
+            Type::Number(-0.5)
+        );
+        let zero = modulo(&Type::Number(-4.0), &Type::Number(-2.0));
+        assert!(matches!(zero, Type::Number(z) if z == 0.0 && z.is_sign_negative()));
+    }
+
+    #[test]
+    fn shifts() {
+        assert!(
+            matches!(shl(&int(1), &int(1 << 22)), Type::BigInt(i) if i.bits() == (1 << 22) + 1)
+        );
+        assert_eq!(shr(&int(-1), &int(1 << 40)), int(-1));
+        assert_eq!(shr(&big("18446744073709551616"), &int(1 << 40)), int(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Shift left by too large an amount")]
+    fn huge_left_shift() {
+        shl(&int(1), &int(100000000000));
+    }
+
+    #[test]
+    fn powers() {
+        assert_eq!(pow(&int(-1), &int(3000000001)), int(-1));
+        assert_eq!(pow(&int(0), &int(3000000000)), int(0));
+        assert!(
+            matches!(pow(&int(2), &int(1 << 20)), Type::BigInt(i) if i.bits() == (1 << 20) + 1)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Exponent too large")]
+    fn huge_power() {
+        pow(&int(2), &int(3000000000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Exponent too large")]
+    fn huge_power_of_big_int() {
+        pow(&big("18446744073709551616"), &int(1 << 20));
+    }
+
+    #[test]
+    fn formatting() {
+        let spec = |width, kind| FormatSpec {
+            width,
+            kind,
+            zero: true,
+            ..FormatSpec::default()
+        };
+        assert_eq!(
+            format_with(&int(-42), &spec(6, FormatKind::Display)),
+            "-00042"
+        );
+        assert_eq!(format_with(&int(255), &spec(0, FormatKind::UpperHex)), "FF");
+        assert_eq!(
+            format_with(
+                &Type::String(Rc::new("glass".to_owned())),
+                &FormatSpec {
+                    precision: Some(2),
+                    ..FormatSpec::default()
+                }
+            ),
+            "gl"
+        );
+    }
+}
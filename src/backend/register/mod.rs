@@ -0,0 +1,996 @@
+pub mod vm;
+
+use std::{fmt, path::Path, rc::Rc};
+
+use crate::{
+    frontend::{Expr, FormatSpec, Op, Pattern, AST},
+    stdlib::{prelude, NativeFunction},
+};
+
+use super::{
+    instruction::{self, EnumDef, Pool, State, Type},
+    module::{Loader, ModuleError, Target},
+};
+
+#[allow(non_camel_case_types)]
+pub type reg = u16;
+
+// Three-address code for the register VM. Registers are numbered from the
+// start of the current call frame, the destination always comes first.
+#[derive(Clone, PartialEq)]
+pub enum Instr {
+    Noop,
+
+    // reserves the global slots, the main frame starts right above them
+    Reserve(usize),
+    // makes sure the current frame has this many registers, the first of
+    // them the arguments a call has to pass
+    Enter(reg, reg),
+
+    Const(reg, u32),
+    Move(reg, reg),
+    GetGlobal(reg, usize),
+    SetGlobal(usize, reg),
+
+    Jump(usize),
+    JumpIf(reg, usize),
+    JumpIfNot(reg, usize),
+    JumpIfNone(reg, usize),
+    JumpIfNotNone(reg, usize),
+    // the arguments sit in consecutive registers from `args`, which become
+    // the first registers of the callee's frame
+    Call {
+        dst: reg,
+        callee: reg,
+        args: reg,
+        argc: reg,
+    },
+    NativeCall {
+        dst: reg,
+        args: reg,
+        argc: reg,
+        function: NativeFunction,
+    },
+    Return(reg),
+
+    Add(reg, reg, reg),
+    Sub(reg, reg, reg),
+    Mul(reg, reg, reg),
+    Div(reg, reg, reg),
+    FloorDiv(reg, reg, reg),
+    Mod(reg, reg, reg),
+    Pow(reg, reg, reg),
+    Lt(reg, reg, reg),
+    Gt(reg, reg, reg),
+    Lte(reg, reg, reg),
+    Gte(reg, reg, reg),
+    Eq(reg, reg, reg),
+    Neq(reg, reg, reg),
+    BitAnd(reg, reg, reg),
+    BitOr(reg, reg, reg),
+    BitXor(reg, reg, reg),
+    Shl(reg, reg, reg),
+    Shr(reg, reg, reg),
+    Neg(reg, reg),
+    Not(reg, reg),
+    BitNot(reg, reg),
+
+    // `Index(dst, item, index)`
+    Index(reg, reg, reg),
+    Join(reg, reg, reg),
+    // joins the registers `start..start + amount`
+    JoinMany(reg, reg, reg),
+    Format(reg, reg, Box<FormatSpec>),
+
+    // names are string constants
+    Member(reg, reg, u32),
+    // checks the value against the enum in the third register
+    IsVariant(reg, reg, reg, u32),
+    Field(reg, reg, reg),
+}
+
+const _: () = assert!(std::mem::size_of::<Instr>() == 16);
+
+/// The register code of a program along with its constants.
+#[derive(Default)]
+pub struct Program {
+    pub code: Vec<Instr>,
+    pub constants: Pool,
+}
+
+impl Program {
+    /// The constant pool, then the instructions from `start` on, with the
+    /// constant each one refers to.
+    pub fn disassemble(&self, start: usize) -> String {
+        let mut out = self.constants.disassemble();
+        out += "\n";
+
+        out += "ln#\topcode    \toperands\n";
+        out += "-------------------------\n";
+        for (i, instruction) in self.code.iter().enumerate().skip(start) {
+            out += &format!("{}:\t{:?}", i, instruction);
+            match instruction {
+                Instr::Const(_, c) | Instr::Member(_, _, c) | Instr::IsVariant(_, _, _, c) => {
+                    out += &format!("\t; {:?}", self.constants[*c as usize])
+                }
+                _ => {}
+            }
+            out += "\n";
+        }
+        out += "-------------------------\n";
+        out
+    }
+}
+
+/// Compiles the AST to register code. Locals live in registers of their
+/// function's frame, with temporaries allocated above them like a stack.
+pub struct Builder {
+    program: Program,
+    depth: usize,
+    // the next free register of the frame being built, and how many it needs
+    top: usize,
+    size: usize,
+}
+
+/// Where a variable lives.
+enum Place {
+    Global(usize),
+    Register(reg),
+}
+
+impl Instr {
+    pub fn compile(ast: AST) -> (Program, usize) {
+        Self::compile_with(ast, Path::new("<main>"), &mut Loader::default())
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn compile_file(path: &Path, loader: &mut Loader) -> Result<(Program, usize), ModuleError> {
+        let ast = loader.parse(path)?;
+        Self::compile_with(ast, path, loader)
+    }
+
+    pub fn compile_with(
+        ast: AST,
+        path: &Path,
+        loader: &mut Loader,
+    ) -> Result<(Program, usize), ModuleError> {
+        let mut builder = Builder {
+            program: Program::default(),
+            depth: 0,
+            top: 0,
+            size: 0,
+        };
+        builder.emit(Instr::Noop); // placeholder for Reserve
+        builder.emit(Instr::Noop); // placeholder for Enter
+        let mut state = State::default();
+        let mut next = 0;
+
+        builder.iter_build(prelude(), &mut state, &mut next);
+        loader.prelude = state.clone();
+
+        let prog_start = builder.program.code.len();
+        loader.enter(path);
+        builder.build_module(ast, path, &mut state, &mut next, loader)?;
+        loader.exit();
+
+        let size = builder.register(builder.size);
+        builder.program.code[0] = Instr::Reserve(next);
+        builder.program.code[1] = Instr::Enter(size, 0);
+
+        Ok((builder.program, prog_start))
+    }
+}
+
+impl Target for Builder {
+    /// Builds the top level of a module, where imports are allowed.
+    fn build_module(
+        &mut self,
+        code: AST,
+        path: &Path,
+        state: &mut State,
+        next: &mut usize,
+        loader: &mut Loader,
+    ) -> Result<(), ModuleError> {
+        for expr in loader.check(code, path, state)? {
+            match expr {
+                Expr::Import {
+                    path: import,
+                    alias,
+                    ..
+                } => {
+                    let module = loader.load(self, path, &import, next)?;
+                    let name = alias.unwrap_or_else(|| module.name.clone());
+
+                    let value = self.alloc();
+                    self.constant(value, Type::Module(module));
+                    let id = *next;
+                    *next += 1;
+                    state.insert(name, (id, 0));
+                    self.emit(Instr::SetGlobal(id, value));
+                    self.free(value);
+                }
+                Expr::FromImport {
+                    path: import,
+                    names,
+                    ..
+                } => {
+                    let module = loader.load(self, path, &import, next)?;
+
+                    for name in names {
+                        let id = *module.exports.get(&name).ok_or_else(|| {
+                            ModuleError::MissingExport {
+                                module: module.path.clone(),
+                                name: name.clone(),
+                            }
+                        })?;
+                        state.insert(name, (id, 0));
+                    }
+                }
+                expr => self.iter_build(vec![expr], state, next),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Builder {
+    fn emit(&mut self, instruction: Instr) -> usize {
+        self.program.code.push(instruction);
+        self.program.code.len() - 1
+    }
+
+    fn here(&self) -> usize {
+        self.program.code.len()
+    }
+
+    fn register(&self, index: usize) -> reg {
+        reg::try_from(index).unwrap_or_else(|_| panic!("Too many registers in one function"))
+    }
+
+    fn alloc(&mut self) -> reg {
+        let register = self.register(self.top);
+        self.top += 1;
+        self.size = self.size.max(self.top);
+        register
+    }
+
+    /// Frees `register` and everything allocated after it.
+    fn free(&mut self, register: reg) {
+        self.top = register as usize;
+    }
+
+    fn constant(&mut self, dst: reg, value: Type) {
+        let index = self.program.constants.add(value);
+        self.emit(Instr::Const(dst, index));
+    }
+
+    fn name(&mut self, name: String) -> u32 {
+        self.program.constants.add(Type::String(Rc::new(name)))
+    }
+
+    fn place(&self, id: usize, depth: usize) -> Place {
+        if depth == 0 {
+            Place::Global(id)
+        } else if depth == self.depth {
+            Place::Register(id as reg)
+        } else {
+            panic!("Cannot capture locals of an enclosing function")
+        }
+    }
+
+    fn lookup(&self, name: &str, state: &State) -> Place {
+        let (id, depth) = *state
+            .get(name)
+            .unwrap_or_else(|| panic!("Variable not found {}", name));
+        self.place(id, depth)
+    }
+
+    /// A new variable in the current scope, a global at the top level.
+    fn declare(&mut self, name: String, state: &mut State, next: &mut usize) -> Place {
+        let place = if self.depth == 0 {
+            let id = *next;
+            *next += 1;
+            Place::Global(id)
+        } else {
+            Place::Register(self.alloc())
+        };
+
+        let id = match place {
+            Place::Global(id) => id,
+            Place::Register(register) => register as usize,
+        };
+        state.insert(name, (id, self.depth));
+        place
+    }
+
+    /// Stores the value in `value` to a variable.
+    fn store(&mut self, place: Place, value: reg) {
+        match place {
+            Place::Global(id) => self.emit(Instr::SetGlobal(id, value)),
+            Place::Register(register) if register != value => {
+                self.emit(Instr::Move(register, value))
+            }
+            Place::Register(_) => return,
+        };
+    }
+
+    /// Whether `register` holds a variable that is still in scope, writing
+    /// to it before an expression is done would change what it reads.
+    fn named(&self, register: reg, state: &State) -> bool {
+        self.depth > 0
+            && state
+                .values()
+                .any(|&(id, depth)| depth == self.depth && id == register as usize)
+    }
+
+    fn open_scope(&self, state: &State) -> (State, usize) {
+        (state.clone(), self.top)
+    }
+
+    fn close_scope(&mut self, state: &mut State, scope: (State, usize)) {
+        let (outer, top) = scope;
+        *state = outer;
+        self.top = top;
+    }
+
+    pub fn iter_build(&mut self, code: Vec<Expr>, state: &mut State, next: &mut usize) {
+        for expr in code {
+            self.build_expr(expr, None, state, next);
+        }
+    }
+
+    /// Builds `code` writing the value of its last expression to `dst`, or
+    /// none if it doesn't have one.
+    fn value_iter_build(
+        &mut self,
+        mut code: Vec<Expr>,
+        dst: reg,
+        state: &mut State,
+        next: &mut usize,
+    ) {
+        let last = code.pop();
+        self.iter_build(code, state, next);
+
+        match last {
+            Some(expr) if instruction::Instr::yields_value(&expr) => {
+                self.build_expr(expr, Some(dst), state, next)
+            }
+            Some(expr) => {
+                self.iter_build(vec![expr], state, next);
+                self.constant(dst, Type::None);
+            }
+            None => self.constant(dst, Type::None),
+        }
+    }
+
+    /// Builds `code` in its own lexical scope, writing its value to `dst` if
+    /// there is one.
+    fn block_build(
+        &mut self,
+        code: Vec<Expr>,
+        dst: Option<reg>,
+        state: &mut State,
+        next: &mut usize,
+    ) {
+        let scope = self.open_scope(state);
+        match dst {
+            Some(dst) => self.value_iter_build(code, dst, state, next),
+            None => self.iter_build(code, state, next),
+        }
+        self.close_scope(state, scope);
+    }
+
+    /// A register holding the value of `expr`, which is the variable's own
+    /// register when it is a local.
+    fn build(&mut self, expr: Expr, state: &mut State, next: &mut usize) -> reg {
+        if let Expr::Identifier(name, _) = &expr {
+            if let Place::Register(register) = self.lookup(name, state) {
+                return register;
+            }
+        }
+
+        let dst = self.alloc();
+        self.build_expr(expr, Some(dst), state, next);
+        dst
+    }
+
+    /// Builds a function body, returning where it starts.
+    fn function(
+        &mut self,
+        args: Vec<(String, usize)>,
+        code: Vec<Expr>,
+        state: &State,
+        next: &mut usize,
+    ) -> usize {
+        let jump = self.emit(Instr::Noop); // placeholder for the jump past the body
+        let start = self.emit(Instr::Noop); // placeholder for Enter
+
+        let outer = (self.depth, self.top, self.size);
+        self.depth += 1;
+        self.top = 0;
+        self.size = 0;
+
+        // the arguments are the first registers of the frame
+        let arity = self.register(args.len());
+        let mut fn_state = state.clone();
+        for (arg, _) in args {
+            let register = self.alloc();
+            fn_state.insert(arg, (register as usize, self.depth));
+        }
+
+        self.iter_build(code, &mut fn_state, next);
+
+        let none = self.alloc();
+        self.constant(none, Type::None);
+        self.emit(Instr::Return(none));
+
+        self.program.code[start] = Instr::Enter(self.register(self.size), arity);
+        (self.depth, self.top, self.size) = outer;
+        self.program.code[jump] = Instr::Jump(self.here());
+
+        start
+    }
+
+    /// Builds `expr`, writing its value to `dst`. Without a `dst` it is only
+    /// built for its effects.
+    fn build_expr(&mut self, expr: Expr, dst: Option<reg>, state: &mut State, next: &mut usize) {
+        // temporaries are done with once the expression is, the registers of
+        // new locals are not
+        let declares = self.depth > 0
+            && matches!(
+                expr,
+                Expr::Declaration(..) | Expr::Const(..) | Expr::Function { .. } | Expr::Enum { .. }
+            );
+        let top = self.top;
+
+        match expr {
+            Expr::Int(_)
+            | Expr::BigInt(_)
+            | Expr::Number(_)
+            | Expr::String(_)
+            | Expr::Bool(_)
+            | Expr::None
+            | Expr::Identifier(..)
+                if dst.is_none() => {}
+            Expr::Declaration(name, value) | Expr::Const(name, value) => {
+                if self.depth == 0 {
+                    let value = self.build(*value, state, next);
+                    let place = self.declare(name, state, next);
+                    self.store(place, value);
+                } else {
+                    // the name is only in scope after its value
+                    let register = self.alloc();
+                    self.build_expr(*value, Some(register), state, next);
+                    state.insert(name, (register as usize, self.depth));
+                }
+            }
+            Expr::Assignment(assignee, value, _) => match *assignee {
+                Expr::Identifier(name, _) => match self.lookup(&name, state) {
+                    Place::Register(register) => {
+                        self.build_expr(*value, Some(register), state, next)
+                    }
+                    place => {
+                        let value = self.build(*value, state, next);
+                        self.store(place, value);
+                    }
+                },
+                // the resolver rejects every other assignee
+                _ => panic!("cannot assign"),
+            },
+            Expr::Function {
+                name, args, body, ..
+            } => {
+                let place = self.declare(name, state, next);
+                let start = self.function(args, body, state, next);
+
+                let value = match place {
+                    Place::Register(register) => register,
+                    Place::Global(_) => self.alloc(),
+                };
+                self.constant(value, Type::FuncPtr(start));
+                self.store(place, value);
+            }
+            Expr::Enum { name, variants } => {
+                let def = EnumDef {
+                    name: name.clone(),
+                    variants: variants
+                        .into_iter()
+                        .map(|(variant, fields)| (variant, fields.len()))
+                        .collect(),
+                };
+                let place = self.declare(name, state, next);
+                let value = match place {
+                    Place::Register(register) => register,
+                    Place::Global(_) => self.alloc(),
+                };
+                self.constant(value, Type::Enum(Rc::new(def)));
+                self.store(place, value);
+            }
+            Expr::Import { .. } | Expr::FromImport { .. } => {
+                panic!("Imports are only allowed at the top level of a module");
+            }
+            Expr::While { condition, body } => {
+                let start = self.here();
+                let condition = self.build(*condition, state, next);
+                let jump_if_not = self.emit(Instr::Noop); // placeholder for JumpIfNot
+                self.top = top;
+
+                self.block_build(body, None, state, next);
+                self.emit(Instr::Jump(start));
+
+                self.program.code[jump_if_not] = Instr::JumpIfNot(condition, self.here());
+            }
+            Expr::Block(code) => self.block_build(code, dst, state, next),
+            Expr::If {
+                condition,
+                then,
+                otherwise,
+            } => match *condition {
+                Expr::Bool(true) => self.block_build(then, dst, state, next),
+                Expr::Bool(false) => self.block_build(otherwise, dst, state, next),
+                condition => {
+                    let mut fails = vec![];
+                    match condition {
+                        Expr::Op(Op::Or, lhs, rhs) => {
+                            let lhs = self.build(*lhs, state, next);
+                            let jump_if = self.emit(Instr::Noop);
+                            let rhs = self.build(*rhs, state, next);
+                            fails.push((self.emit(Instr::Noop), rhs));
+                            self.program.code[jump_if] = Instr::JumpIf(lhs, self.here());
+                        }
+                        Expr::Op(Op::And, lhs, rhs) => {
+                            // either side being falsy skips the then branch
+                            let lhs = self.build(*lhs, state, next);
+                            fails.push((self.emit(Instr::Noop), lhs));
+                            let rhs = self.build(*rhs, state, next);
+                            fails.push((self.emit(Instr::Noop), rhs));
+                        }
+                        condition => {
+                            let condition = self.build(condition, state, next);
+                            fails.push((self.emit(Instr::Noop), condition));
+                        }
+                    }
+                    self.top = top;
+
+                    self.block_build(then, dst, state, next);
+                    let jump = self.emit(Instr::Noop); // placeholder for Jump
+
+                    for (fail, condition) in fails {
+                        self.program.code[fail] = Instr::JumpIfNot(condition, self.here());
+                    }
+                    self.block_build(otherwise, dst, state, next);
+                    self.program.code[jump] = Instr::Jump(self.here());
+                }
+            },
+            Expr::Match {
+                value: matched,
+                arms,
+            } => {
+                // the subject gets a register of its own so arms can't change it
+                let subject = self.alloc();
+                self.build_expr(*matched, Some(subject), state, next);
+
+                let mut end_jumps = vec![];
+                for (pattern, body) in arms {
+                    let arm_top = self.top;
+                    let mut fails = vec![];
+                    let mut bindings = vec![];
+                    self.build_pattern(&pattern, subject, state, next, &mut fails, &mut bindings);
+
+                    let arm_scope = self.open_scope(state);
+                    for (name, value) in bindings {
+                        if self.depth == 0 {
+                            let place = self.declare(name, state, next);
+                            self.store(place, value);
+                        } else {
+                            // the field already sits in a register of its own
+                            state.insert(name, (value as usize, self.depth));
+                        }
+                    }
+
+                    match dst {
+                        Some(dst) => self.value_iter_build(body, dst, state, next),
+                        None => self.iter_build(body, state, next),
+                    }
+                    self.close_scope(state, arm_scope);
+                    self.top = arm_top;
+
+                    end_jumps.push(self.emit(Instr::Noop)); // placeholder for Jump
+
+                    for (fail, check) in fails {
+                        self.program.code[fail] = Instr::JumpIfNot(check, self.here());
+                    }
+                }
+
+                // no arm matched
+                if let Some(dst) = dst {
+                    self.constant(dst, Type::None);
+                }
+
+                for jump in end_jumps {
+                    self.program.code[jump] = Instr::Jump(self.here());
+                }
+            }
+            Expr::Return(value) => {
+                let value = self.build(*value, state, next);
+                self.emit(Instr::Return(value));
+            }
+            expr => {
+                let dst = dst.unwrap_or_else(|| self.alloc());
+                self.build_value(expr, dst, state, next);
+            }
+        }
+
+        if !declares {
+            self.top = top;
+        }
+    }
+
+    /// Builds an expression that always has a value.
+    fn build_value(&mut self, expr: Expr, dst: reg, state: &mut State, next: &mut usize) {
+        match expr {
+            Expr::Int(int) => self.constant(dst, Type::Int(int)),
+            Expr::BigInt(int) => self.constant(dst, Type::BigInt(Rc::new(int))),
+            Expr::Number(num) => self.constant(dst, Type::Number(num)),
+            Expr::String(str) => self.constant(dst, Type::String(str.into())),
+            Expr::Bool(bool) => self.constant(dst, Type::Bool(bool)),
+            Expr::None => self.constant(dst, Type::None),
+            Expr::Identifier(name, _) => match self.lookup(&name, state) {
+                Place::Global(id) => {
+                    self.emit(Instr::GetGlobal(dst, id));
+                }
+                Place::Register(register) if register != dst => {
+                    self.emit(Instr::Move(dst, register));
+                }
+                Place::Register(_) => {}
+            },
+            Expr::FormatString(parts) => {
+                let n = parts.len();
+
+                if n == 0 {
+                    self.constant(dst, Type::String(Rc::new(String::new())));
+                } else if n == 1 {
+                    let part = parts.into_iter().next().unwrap();
+                    // a lone interpolation still has to become a string
+                    if matches!(part, Expr::String(_) | Expr::Formatted(..)) {
+                        self.build_expr(part, Some(dst), state, next);
+                    } else {
+                        let value = self.build(part, state, next);
+                        self.emit(Instr::Format(dst, value, Box::default()));
+                    }
+                } else {
+                    // the parts are evaluated last to first, like on the stack
+                    let start = self.top;
+                    for _ in 0..n {
+                        self.alloc();
+                    }
+                    for (i, part) in parts.into_iter().enumerate().rev() {
+                        let register = self.register(start + i);
+                        self.build_expr(part, Some(register), state, next);
+                    }
+                    let (start, n) = (self.register(start), self.register(n));
+                    self.emit(Instr::JoinMany(dst, start, n));
+                }
+            }
+            Expr::Formatted(value, spec) => {
+                let value = self.build(*value, state, next);
+                self.emit(Instr::Format(dst, value, Box::new(spec)));
+            }
+            Expr::Join(lhs, rhs) => {
+                let lhs = self.build(*lhs, state, next);
+                let rhs = self.build(*rhs, state, next);
+                self.emit(Instr::Join(dst, lhs, rhs));
+            }
+            Expr::Index { item, index } => {
+                let index = self.build(*index, state, next);
+                let item = self.build(*item, state, next);
+                self.emit(Instr::Index(dst, item, index));
+            }
+            Expr::Lambda(args, body) => {
+                let start = self.function(args, body, state, next);
+                self.constant(dst, Type::FuncPtr(start));
+            }
+            Expr::Member(item, name) => {
+                let item = self.build(*item, state, next);
+                let name = self.name(name);
+                self.emit(Instr::Member(dst, item, name));
+            }
+            Expr::OptionalMember(item, name) => {
+                let item = self.build(*item, state, next);
+                let jump_if_none = self.emit(Instr::Noop);
+
+                let name = self.name(name);
+                self.emit(Instr::Member(dst, item, name));
+                let jump = self.emit(Instr::Noop);
+
+                self.program.code[jump_if_none] = Instr::JumpIfNone(item, self.here());
+                self.constant(dst, Type::None);
+                self.program.code[jump] = Instr::Jump(self.here());
+            }
+            Expr::Call(callee, args) if matches!(*callee, Expr::OptionalMember(..)) => {
+                let Expr::OptionalMember(item, name) = *callee else {
+                    unreachable!()
+                };
+
+                // `item?.name(args)` skips the whole call when `item` is none,
+                // arguments included
+                let item = {
+                    let register = self.alloc();
+                    self.build_expr(*item, Some(register), state, next);
+                    register
+                };
+                let jump_if_none = self.emit(Instr::Noop);
+
+                let callee = self.alloc();
+                let (args, argc) = self.arguments(args, state, next);
+                let name = self.name(name);
+                self.emit(Instr::Member(callee, item, name));
+                self.emit(Instr::Call {
+                    dst,
+                    callee,
+                    args,
+                    argc,
+                });
+                let jump = self.emit(Instr::Noop);
+
+                self.program.code[jump_if_none] = Instr::JumpIfNone(item, self.here());
+                self.constant(dst, Type::None);
+                self.program.code[jump] = Instr::Jump(self.here());
+            }
+            Expr::Call(callee, args) => {
+                let (args, argc) = self.arguments(args, state, next);
+                // the callee is read before the call, so it may sit in the
+                // registers of the new frame
+                let callee = self.build(*callee, state, next);
+                self.emit(Instr::Call {
+                    dst,
+                    callee,
+                    args,
+                    argc,
+                });
+            }
+            Expr::NativeCall(name, args) => {
+                let function = NativeFunction::from(&name)
+                    .unwrap_or_else(|| panic!("Native function not found: {}", name));
+                let (args, argc) = self.arguments(args, state, next);
+                self.emit(Instr::NativeCall {
+                    dst,
+                    args,
+                    argc,
+                    function,
+                });
+            }
+            Expr::Op(op @ (Op::And | Op::Or | Op::Coalesce), lhs, rhs) => {
+                // the result is whichever operand decided it, `rhs` is only
+                // evaluated when `lhs` doesn't
+                let result = if self.named(dst, state) {
+                    self.alloc()
+                } else {
+                    dst
+                };
+                self.build_expr(*lhs, Some(result), state, next);
+                let jump = self.emit(Instr::Noop); // placeholder for the jump past `rhs`
+
+                self.build_expr(*rhs, Some(result), state, next);
+                self.program.code[jump] = match op {
+                    Op::And => Instr::JumpIfNot(result, self.here()),
+                    Op::Or => Instr::JumpIf(result, self.here()),
+                    _ => Instr::JumpIfNotNone(result, self.here()),
+                };
+                self.store(Place::Register(dst), result);
+            }
+            Expr::Chain(first, rest) => {
+                // `a < b < c` is `a < b && b < c` with `b` evaluated once
+                let result = if self.named(dst, state) {
+                    self.alloc()
+                } else {
+                    dst
+                };
+                let mut lhs = self.build(*first, state, next);
+
+                let last = rest.len() - 1;
+                let mut jumps = vec![];
+                for (i, (op, operand)) in rest.into_iter().enumerate() {
+                    let rhs = self.build(operand, state, next);
+                    self.emit(match op {
+                        Op::Eq => Instr::Eq(result, lhs, rhs),
+                        Op::Neq => Instr::Neq(result, lhs, rhs),
+                        Op::Lt => Instr::Lt(result, lhs, rhs),
+                        Op::Gt => Instr::Gt(result, lhs, rhs),
+                        Op::Lte => Instr::Lte(result, lhs, rhs),
+                        Op::Gte => Instr::Gte(result, lhs, rhs),
+                        op => unreachable!("{:?} does not chain", op),
+                    });
+                    if i != last {
+                        jumps.push(self.emit(Instr::Noop)); // placeholder for JumpIfNot
+                    }
+                    lhs = rhs;
+                }
+
+                for jump in jumps {
+                    self.program.code[jump] = Instr::JumpIfNot(result, self.here());
+                }
+                self.store(Place::Register(dst), result);
+            }
+            Expr::Op(op, lhs, rhs) => {
+                let lhs = self.build(*lhs, state, next);
+                // unary operators carry a placeholder operand
+                if matches!(op, Op::Neg | Op::Not | Op::BitNot) {
+                    self.emit(match op {
+                        Op::Neg => Instr::Neg(dst, lhs),
+                        Op::Not => Instr::Not(dst, lhs),
+                        _ => Instr::BitNot(dst, lhs),
+                    });
+                    return;
+                }
+
+                let rhs = self.build(*rhs, state, next);
+                self.emit(match op {
+                    Op::Add => Instr::Add(dst, lhs, rhs),
+                    Op::Sub => Instr::Sub(dst, lhs, rhs),
+                    Op::Mul => Instr::Mul(dst, lhs, rhs),
+                    Op::Div => Instr::Div(dst, lhs, rhs),
+                    Op::FloorDiv => Instr::FloorDiv(dst, lhs, rhs),
+                    Op::Mod => Instr::Mod(dst, lhs, rhs),
+                    Op::Pow => Instr::Pow(dst, lhs, rhs),
+                    Op::Eq => Instr::Eq(dst, lhs, rhs),
+                    Op::Neq => Instr::Neq(dst, lhs, rhs),
+                    Op::Lt => Instr::Lt(dst, lhs, rhs),
+                    Op::Gt => Instr::Gt(dst, lhs, rhs),
+                    Op::Lte => Instr::Lte(dst, lhs, rhs),
+                    Op::Gte => Instr::Gte(dst, lhs, rhs),
+                    Op::BitAnd => Instr::BitAnd(dst, lhs, rhs),
+                    Op::BitOr => Instr::BitOr(dst, lhs, rhs),
+                    Op::BitXor => Instr::BitXor(dst, lhs, rhs),
+                    Op::Shl => Instr::Shl(dst, lhs, rhs),
+                    Op::Shr => Instr::Shr(dst, lhs, rhs),
+                    _ => unreachable!(),
+                });
+            }
+            _ => panic!("Not implemented"),
+        }
+    }
+
+    /// Evaluates `args` into consecutive registers at the top of the frame,
+    /// last to first like on the stack, returning the first and how many.
+    fn arguments(&mut self, args: Vec<Expr>, state: &mut State, next: &mut usize) -> (reg, reg) {
+        let start = self.top;
+        let argc = args.len();
+        for _ in 0..argc {
+            self.alloc();
+        }
+
+        for (i, arg) in args.into_iter().enumerate().rev() {
+            let register = self.register(start + i);
+            self.build_expr(arg, Some(register), state, next);
+        }
+
+        (self.register(start), self.register(argc))
+    }
+
+    /// Emits the checks for `pattern` against the value in `value`. Each
+    /// failing check is left as a placeholder in `fails`, along with the
+    /// register it tests, and every binding is recorded with the register
+    /// holding its value.
+    fn build_pattern(
+        &mut self,
+        pattern: &Pattern,
+        value: reg,
+        state: &mut State,
+        next: &mut usize,
+        fails: &mut Vec<(usize, reg)>,
+        bindings: &mut Vec<(String, reg)>,
+    ) {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Binding(name) => bindings.push((name.clone(), value)),
+            Pattern::Literal(literal) => {
+                let check = self.alloc();
+                self.constant(check, instruction::Instr::literal(literal));
+                self.emit(Instr::Eq(check, value, check));
+                fails.push((self.emit(Instr::Noop), check)); // placeholder for JumpIfNot
+            }
+            Pattern::Variant {
+                enum_name,
+                name,
+                fields,
+                pos,
+            } => {
+                let check = self.alloc();
+                // the enum the name resolves to here, not any enum called that
+                let def = self.build(Expr::Identifier(enum_name.clone(), *pos), state, next);
+                let name = self.name(name.clone());
+                self.emit(Instr::IsVariant(check, value, def, name));
+                fails.push((self.emit(Instr::Noop), check)); // placeholder for JumpIfNot
+
+                for (i, field) in fields.iter().enumerate() {
+                    if *field == Pattern::Wildcard {
+                        continue;
+                    }
+                    let register = self.alloc();
+                    let i = self.register(i);
+                    self.emit(Instr::Field(register, value, i));
+                    self.build_pattern(field, register, state, next, fails, bindings);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let binary = |f: &mut fmt::Formatter<'_>, name: &str, dst, lhs, rhs| {
+            write!(f, "{:<8}\tr{}, r{}, r{}", name, dst, lhs, rhs)
+        };
+
+        match self {
+            Self::Noop => write!(f, "Noop"),
+            Self::Reserve(amount) => write!(f, "Reserve \t{}", amount),
+            Self::Enter(size, arity) => write!(f, "Enter   \t{} {}", size, arity),
+            Self::Const(dst, c) => write!(f, "Const   \tr{}, #{}", dst, c),
+            Self::Move(dst, src) => write!(f, "Move    \tr{}, r{}", dst, src),
+            Self::GetGlobal(dst, id) => write!(f, "GetGlobal\tr{}, g{}", dst, id),
+            Self::SetGlobal(id, src) => write!(f, "SetGlobal\tg{}, r{}", id, src),
+            Self::Jump(to) => write!(f, "Jump    \t{}", to),
+            Self::JumpIf(cond, to) => write!(f, "JumpIf  \tr{}, {}", cond, to),
+            Self::JumpIfNot(cond, to) => write!(f, "JumpIfNot\tr{}, {}", cond, to),
+            Self::JumpIfNone(cond, to) => write!(f, "JumpIfNone\tr{}, {}", cond, to),
+            Self::JumpIfNotNone(cond, to) => write!(f, "JumpIfNotNone\tr{}, {}", cond, to),
+            Self::Call {
+                dst,
+                callee,
+                args,
+                argc,
+            } => write!(f, "Call    \tr{}, r{}(r{}, {})", dst, callee, args, argc),
+            Self::NativeCall {
+                dst,
+                args,
+                argc,
+                function,
+            } => write!(
+                f,
+                "NativeCall\tr{}, #{}(r{}, {})",
+                dst,
+                function.name(),
+                args,
+                argc
+            ),
+            Self::Return(value) => write!(f, "Return  \tr{}", value),
+            Self::Add(d, l, r) => binary(f, "Add", d, l, r),
+            Self::Sub(d, l, r) => binary(f, "Sub", d, l, r),
+            Self::Mul(d, l, r) => binary(f, "Mul", d, l, r),
+            Self::Div(d, l, r) => binary(f, "Div", d, l, r),
+            Self::FloorDiv(d, l, r) => binary(f, "FloorDiv", d, l, r),
+            Self::Mod(d, l, r) => binary(f, "Mod", d, l, r),
+            Self::Pow(d, l, r) => binary(f, "Pow", d, l, r),
+            Self::Lt(d, l, r) => binary(f, "Lt", d, l, r),
+            Self::Gt(d, l, r) => binary(f, "Gt", d, l, r),
+            Self::Lte(d, l, r) => binary(f, "Lte", d, l, r),
+            Self::Gte(d, l, r) => binary(f, "Gte", d, l, r),
+            Self::Eq(d, l, r) => binary(f, "Eq", d, l, r),
+            Self::Neq(d, l, r) => binary(f, "Neq", d, l, r),
+            Self::BitAnd(d, l, r) => binary(f, "BitAnd", d, l, r),
+            Self::BitOr(d, l, r) => binary(f, "BitOr", d, l, r),
+            Self::BitXor(d, l, r) => binary(f, "BitXor", d, l, r),
+            Self::Shl(d, l, r) => binary(f, "Shl", d, l, r),
+            Self::Shr(d, l, r) => binary(f, "Shr", d, l, r),
+            Self::Neg(dst, src) => write!(f, "Neg     \tr{}, r{}", dst, src),
+            Self::Not(dst, src) => write!(f, "Not     \tr{}, r{}", dst, src),
+            Self::BitNot(dst, src) => write!(f, "BitNot  \tr{}, r{}", dst, src),
+            Self::Index(d, item, index) => binary(f, "Index", d, item, index),
+            Self::Join(d, l, r) => binary(f, "Join", d, l, r),
+            Self::JoinMany(dst, start, amount) => {
+                write!(f, "JoinMany\tr{}, r{}, {}", dst, start, amount)
+            }
+            Self::Format(dst, src, spec) => {
+                write!(f, "Format  \tr{}, r{}, {{:{}}}", dst, src, spec)
+            }
+            Self::Member(dst, src, name) => write!(f, "Member  \tr{}, r{}, #{}", dst, src, name),
+            Self::IsVariant(dst, src, e, name) => {
+                write!(f, "IsVariant\tr{}, r{}, r{}, #{}", dst, src, e, name)
+            }
+            Self::Field(dst, src, i) => write!(f, "Field   \tr{}, r{}, {}", dst, src, i),
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::borrow::Cow;
+
+use crate::stdlib::Host;
+
+use super::{
+    super::{
+        instruction::{Type, Variant},
+        memory::{Memory, Stats},
+        ops,
+    },
+    reg, Instr, Program,
+};
+
+struct Frame {
+    return_to: usize,
+    fp: usize,
+    // where the caller wants the result, and how far its frame went
+    dst: reg,
+    len: usize,
+}
+
+/// Runs register code. Registers are heap slots, so globals sit at the bottom
+/// of the heap and every frame's registers right above its caller's.
+pub struct VM {
+    pub program: Vec<Instr>,
+    pub constants: Vec<Type>,
+    pub pc: usize,
+    pub fp: usize,
+
+    call_stack: Vec<Frame>,
+    // the arguments of the native call being made, last first
+    args: Vec<Type>,
+
+    pub heap: Memory,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> VM {
+        VM {
+            program: vec![],
+            constants: vec![],
+            pc: 0,
+            fp: 0,
+            call_stack: Vec::with_capacity(1000),
+            args: vec![],
+            heap: Memory::new(),
+        }
+    }
+
+    pub fn load(&mut self, program: Program) {
+        self.program = program.code;
+        self.constants = program.constants.into_values();
+    }
+
+    pub fn run(&mut self) {
+        while self.pc < self.program.len() {
+            self.step();
+        }
+    }
+
+    /// The value of a register in the current frame.
+    pub fn register(&self, register: reg) -> &Type {
+        self.heap.get(self.fp + register as usize)
+    }
+
+    pub fn step(&mut self) {
+        let instruction = &self.program[self.pc];
+        self.pc += 1;
+
+        macro_rules! get {
+            ($register:expr) => {
+                self.heap.get(self.fp + *$register as usize)
+            };
+        }
+        macro_rules! set {
+            ($register:expr, $value:expr) => {{
+                let value = $value;
+                self.heap.set(self.fp + *$register as usize, value)
+            }};
+        }
+        macro_rules! name {
+            ($index:expr) => {
+                match &self.constants[*$index as usize] {
+                    Type::String(name) => name.as_str(),
+                    constant => panic!("Constant {:?} is not a name", constant),
+                }
+            };
+        }
+
+        match instruction {
+            Instr::Noop => {}
+            Instr::Reserve(globals) => {
+                self.heap.reserve(*globals);
+                self.fp = *globals;
+            }
+            Instr::Enter(size, _) => {
+                self.heap.reserve(self.fp + *size as usize);
+            }
+
+            Instr::Const(dst, index) => set!(dst, self.constants[*index as usize].clone()),
+            Instr::Move(dst, src) => set!(dst, get!(src).clone()),
+            Instr::GetGlobal(dst, id) => set!(dst, self.heap.get(*id).clone()),
+            Instr::SetGlobal(id, src) => {
+                let value = get!(src).clone();
+                self.heap.set(*id, value);
+            }
+
+            Instr::Jump(to) => self.pc = *to,
+            Instr::JumpIf(cond, to) => {
+                if get!(cond).is_truthy() {
+                    self.pc = *to;
+                }
+            }
+            Instr::JumpIfNot(cond, to) => {
+                if !get!(cond).is_truthy() {
+                    self.pc = *to;
+                }
+            }
+            Instr::JumpIfNone(value, to) => {
+                if matches!(get!(value), Type::None) {
+                    self.pc = *to;
+                }
+            }
+            Instr::JumpIfNotNone(value, to) => {
+                if !matches!(get!(value), Type::None) {
+                    self.pc = *to;
+                }
+            }
+            Instr::Call {
+                dst,
+                callee,
+                args,
+                argc,
+            } => match get!(callee) {
+                callee @ Type::FuncPtr(start) => {
+                    let Instr::Enter(_, arity) = self.program[*start] else {
+                        panic!("Function at {} doesn't start with Enter", start);
+                    };
+                    ops::arity(callee, arity as usize, *argc as usize);
+
+                    self.call_stack.push(Frame {
+                        return_to: self.pc,
+                        fp: self.fp,
+                        dst: *dst,
+                        len: self.heap.len(),
+                    });
+                    self.pc = *start;
+                    self.fp += *args as usize;
+                }
+                callee @ Type::Constructor(def, tag) => {
+                    let (def, tag) = (def.clone(), *tag as usize);
+                    ops::arity(callee, def.variants[tag].1, *argc as usize);
+
+                    let fields = (0..*argc as usize)
+                        .map(|i| self.heap.get(self.fp + *args as usize + i).clone())
+                        .collect();
+
+                    let result = self.heap.alloc(Variant::new(def, tag, fields), &self.args);
+                    set!(dst, result);
+                }
+                callee => panic!("Call to non-function {:?}", callee),
+            },
+            Instr::NativeCall {
+                dst,
+                args,
+                argc,
+                function,
+            } => {
+                let (dst, function) = (*dst, function.clone());
+                let args = self.fp + *args as usize..self.fp + (*args + *argc) as usize;
+                self.args = args.rev().map(|i| self.heap.get(i).clone()).collect();
+
+                let result = function.call(self);
+                self.heap.set(self.fp + dst as usize, result);
+            }
+            Instr::Return(value) => {
+                let value = get!(value).clone();
+                let frame = self.call_stack.pop().expect("Exited from empty scope");
+
+                self.heap.truncate(frame.len);
+                self.fp = frame.fp;
+                self.pc = frame.return_to;
+                self.heap.set(self.fp + frame.dst as usize, value);
+            }
+
+            Instr::Add(dst, lhs, rhs) => set!(dst, ops::add(get!(lhs), get!(rhs))),
+            Instr::Sub(dst, lhs, rhs) => set!(dst, ops::sub(get!(lhs), get!(rhs))),
+            Instr::Mul(dst, lhs, rhs) => set!(dst, ops::mul(get!(lhs), get!(rhs))),
+            Instr::Div(dst, lhs, rhs) => set!(dst, ops::div(get!(lhs), get!(rhs))),
+            Instr::FloorDiv(dst, lhs, rhs) => set!(dst, ops::floor_div(get!(lhs), get!(rhs))),
+            Instr::Mod(dst, lhs, rhs) => set!(dst, ops::modulo(get!(lhs), get!(rhs))),
+            Instr::Pow(dst, lhs, rhs) => set!(dst, ops::pow(get!(lhs), get!(rhs))),
+            Instr::Lt(dst, lhs, rhs) => set!(dst, ops::lt(get!(lhs), get!(rhs))),
+            Instr::Gt(dst, lhs, rhs) => set!(dst, ops::gt(get!(lhs), get!(rhs))),
+            Instr::Lte(dst, lhs, rhs) => set!(dst, ops::lte(get!(lhs), get!(rhs))),
+            Instr::Gte(dst, lhs, rhs) => set!(dst, ops::gte(get!(lhs), get!(rhs))),
+            Instr::Eq(dst, lhs, rhs) => set!(dst, ops::eq(get!(lhs), get!(rhs))),
+            Instr::Neq(dst, lhs, rhs) => set!(dst, ops::neq(get!(lhs), get!(rhs))),
+            Instr::BitAnd(dst, lhs, rhs) => set!(dst, ops::bit_and(get!(lhs), get!(rhs))),
+            Instr::BitOr(dst, lhs, rhs) => set!(dst, ops::bit_or(get!(lhs), get!(rhs))),
+            Instr::BitXor(dst, lhs, rhs) => set!(dst, ops::bit_xor(get!(lhs), get!(rhs))),
+            Instr::Shl(dst, lhs, rhs) => set!(dst, ops::shl(get!(lhs), get!(rhs))),
+            Instr::Shr(dst, lhs, rhs) => set!(dst, ops::shr(get!(lhs), get!(rhs))),
+            Instr::Neg(dst, value) => set!(dst, ops::neg(get!(value))),
+            Instr::Not(dst, value) => set!(dst, ops::not(get!(value))),
+            Instr::BitNot(dst, value) => set!(dst, ops::bit_not(get!(value))),
+
+            Instr::Index(dst, item, index) => set!(dst, ops::index(get!(item), get!(index))),
+            Instr::Join(dst, lhs, rhs) => set!(dst, ops::join(get!(lhs), get!(rhs))),
+            Instr::JoinMany(dst, start, amount) => {
+                let mut result = String::new();
+                for i in *start..*start + *amount {
+                    ops::push_str(&mut result, get!(&i));
+                }
+                set!(dst, Type::String(result.into()));
+            }
+            Instr::Format(dst, value, spec) => {
+                set!(
+                    dst,
+                    Type::String(ops::format_with(get!(value), spec).into())
+                )
+            }
+
+            Instr::Member(dst, value, name) => {
+                let result = match get!(value).clone() {
+                    Type::Enum(def) => ops::variant(&def, name!(name), &mut self.heap, &self.args),
+                    Type::Module(module) => {
+                        let addr = *module.exports.get(name!(name)).unwrap_or_else(|| {
+                            panic!("Module {} has no member {}", module.name, name!(name))
+                        });
+                        self.heap.get(addr).clone()
+                    }
+                    value => panic!("Member access not supported on {:?}", value),
+                };
+                set!(dst, result);
+            }
+            Instr::IsVariant(dst, value, def, name) => {
+                set!(dst, ops::is_variant(get!(value), get!(def), name!(name)))
+            }
+            Instr::Field(dst, value, i) => set!(dst, ops::field(get!(value), *i as usize)),
+        }
+    }
+}
+
+impl Host for VM {
+    fn arg(&mut self) -> Cow<'_, Type> {
+        Cow::Owned(
+            self.args
+                .pop()
+                .expect("Missing argument to a native function"),
+        )
+    }
+
+    fn collect(&mut self) -> usize {
+        self.heap.collect(&self.args)
+    }
+
+    fn gc_stats(&self) -> Stats {
+        self.heap.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{instruction, vm};
+    use crate::frontend::parser;
+
+    // runs `code` on both VMs and returns the global it declared last on each
+    fn results(code: &str) -> (Type, Type) {
+        let (program, _) = instruction::Instr::compile(parser::parse_code(code).unwrap());
+        let mut stack = vm::VM::new();
+        stack.load(program);
+        stack.run();
+        let expected = stack.heap.get(stack.heap.len() - 1).clone();
+
+        let (program, _) = Instr::compile(parser::parse_code(code).unwrap());
+        let Instr::Reserve(globals) = program.code[0] else {
+            panic!("no globals");
+        };
+        let mut vm = VM::new();
+        vm.load(program);
+        vm.run();
+        (expected, vm.heap.get(globals - 1).clone())
+    }
+
+    // runs `code` on the register VM alone
+    fn run(code: &str) {
+        let (program, _) = Instr::compile(parser::parse_code(code).unwrap());
+        let mut vm = VM::new();
+        vm.load(program);
+        vm.run();
+    }
+
+    #[test]
+    fn agrees_with_the_stack_vm() {
+        for code in [
+            "let result = 1 + 2 * 3",
+            "fn fib(n) { if (n < 2) { return n }\nreturn fib(n - 1) + fib(n - 2) }\nlet result = fib(15)",
+            "let i = 0\nlet total = 0\nwhile (i < 10) { total += i\ni += 1 }\nlet result = total",
+            "enum S { R(w, h), C(r) }\nlet result = match S.R(2, 3) { S.R(w, h) => { w * h } _ => { 0 } }",
+            "let s = \"a\"\nlet result = f\"{s}{1 + 1:>3}\"",
+            "let result = none ?? 1 < 2 <= 3",
+            "let add = (a, b) => a + b\nlet result = add(1, 2)",
+        ] {
+            let (expected, actual) = results(code);
+            assert_eq!(expected, actual, "{:?}", code);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "S.R expects 2 fields, got 1")]
+    fn constructor_arity() {
+        run("enum S { R(w, h) }\nlet r = S.R\nlet result = r(1)");
+    }
+
+    #[test]
+    #[should_panic(expected = "Function expects 1 argument, got 0")]
+    fn function_arity() {
+        run("let id = (a) => a\nlet result = id()");
+    }
+}
@@ -1,28 +1,19 @@
-use std::fmt;
-
-use super::{instruction::Type, memory::addr};
-
-#[derive(Clone, PartialEq)]
-pub enum StackValue {
-    Literal(Type),
-    Addr(addr),
-}
-
-impl fmt::Debug for StackValue {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            StackValue::Literal(ty) => write!(f, "{:?}", ty),
-            StackValue::Addr(addr) => write!(f, "addr({})", addr),
-        }
-    }
-}
+use super::instruction::Type;
 
+/// The values being worked on. They are held directly, loading a slot
+/// pushes a copy of what it holds.
 pub struct Stack {
-    internal: Vec<StackValue>,
+    internal: Vec<Type>,
     // pub sp: usize,
     // pub fp: usize,
 }
 
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Stack {
     pub fn new() -> Stack {
         Stack {
@@ -32,7 +23,7 @@ impl Stack {
         }
     }
 
-    pub fn new_with(first: StackValue) -> Stack {
+    pub fn new_with(first: Type) -> Stack {
         Stack {
             internal: vec![first],
             // sp: 0,
@@ -41,12 +32,12 @@ impl Stack {
     }
 
     #[inline]
-    pub fn push(&mut self, value: StackValue) {
+    pub fn push(&mut self, value: Type) {
         self.internal.push(value);
         // self.sp += 1;
     }
     #[inline]
-    pub fn pop(&mut self) -> StackValue {
+    pub fn pop(&mut self) -> Type {
         let val = self.internal.pop().expect("Popped from empty stack");
         // self.sp -= 1;
         val
@@ -59,13 +50,26 @@ impl Stack {
     //     }
     // }
 
+    /// Everything on the stack, bottom first.
+    #[inline]
+    pub fn values(&self) -> &[Type] {
+        &self.internal
+    }
+
     #[inline]
-    pub fn peek(&self) -> Option<&StackValue> {
+    pub fn peek(&self) -> Option<&Type> {
         self.internal.last()
     }
 
+    /// The value `n` below the top.
+    #[inline]
+    pub fn peek_nth(&self, n: usize) -> Option<&Type> {
+        let i = self.internal.len().checked_sub(n + 1)?;
+        self.internal.get(i)
+    }
+
     #[inline]
-    pub fn peek_mut(&mut self) -> &mut StackValue {
+    pub fn peek_mut(&mut self) -> &mut Type {
         self.internal.last_mut().expect("Peeked from empty stack")
     }
 }
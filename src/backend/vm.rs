@@ -1,46 +1,81 @@
-use std::{borrow::Cow, thread, time, usize};
+use std::{fmt, thread, time};
+
+use fxhash::FxHashMap;
+
+use std::rc::Rc;
 
 use super::{
-    instruction::{Instr, Type},
+    instruction::{Compare, Instr, Program, Type, Variant},
     memory::Memory,
-    stack::{Stack, StackValue},
+    ops,
+    stack::Stack,
 };
 
-const FALSE: Type = Type::Bool(false);
-const TRUE: Type = Type::Bool(true);
+#[cfg(feature = "jit")]
+use super::jit::Jit;
 
 // native functions
 
 pub struct VM {
     pub program: Vec<Instr>,
+    pub constants: Vec<Type>,
     pub pc: usize,
 
     pub stack: Stack,
     pub call_stack: Vec<(usize, usize)>,
+    // bumped every time a global is assigned, so a `CallGlobal` knows when
+    // the function it cached may have changed. 0 is never a version, and
+    // every cache is emptied when one wraps around, see `VM::wrapped`
+    versions: Vec<u32>,
 
     pub fp: usize,
 
-    pub local_addrs: Vec<usize>,
     pub heap: Memory,
+
+    #[cfg(feature = "jit")]
+    pub jit: Jit,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
             program: vec![],
+            constants: vec![],
             pc: 0,
 
             stack: Stack::new(),
 
-            local_addrs: vec![],
             call_stack: {
                 let mut cs = Vec::with_capacity(1000);
                 cs.push((0, 0));
                 cs
             },
 
+            versions: vec![],
             fp: 0,
             heap: Memory::new(),
+
+            #[cfg(feature = "jit")]
+            jit: Jit::new(),
+        }
+    }
+
+    pub fn load(&mut self, program: Program) {
+        self.program = program.code;
+        self.constants = program.constants.into_values();
+    }
+
+    /// A name the compiler put in the constant pool, like a member name.
+    fn name(&self, index: u32) -> Rc<String> {
+        match &self.constants[index as usize] {
+            Type::String(name) => name.clone(),
+            constant => panic!("Constant {:?} is not a name", constant),
         }
     }
 
@@ -50,6 +85,41 @@ impl VM {
         }
     }
 
+    /// Runs the program like `run`, counting how often each opcode is
+    /// dispatched, and each pair of opcodes where one runs straight after
+    /// the other. Those pairs are what `fuse` turns into superinstructions.
+    pub fn profile(&mut self) -> Profile {
+        let mut runs = vec![0; self.program.len()];
+        // how often the instruction at each pc went on to the next one
+        let mut falls = vec![0; self.program.len()];
+
+        while self.pc < self.program.len() {
+            let pc = self.pc;
+            self.step();
+
+            runs[pc] += 1;
+            if self.pc == pc + 1 {
+                falls[pc] += 1;
+            }
+        }
+
+        let mut opcodes = FxHashMap::default();
+        let mut pairs = FxHashMap::default();
+        for (pc, instruction) in self.program.iter().enumerate() {
+            *opcodes.entry(opcode(instruction)).or_insert(0) += runs[pc];
+            if let Some(next) = self.program.get(pc + 1) {
+                let pair = format!("{}; {}", opcode(instruction), opcode(next));
+                *pairs.entry(pair).or_insert(0) += falls[pc];
+            }
+        }
+
+        Profile {
+            dispatched: runs.iter().sum(),
+            opcodes: Profile::ranked(opcodes),
+            pairs: Profile::ranked(pairs),
+        }
+    }
+
     pub fn debug(&mut self) {
         let delay = time::Duration::from_millis(20);
 
@@ -72,53 +142,34 @@ impl VM {
     }
 
     pub fn peek_stack(&mut self) -> Option<&Type> {
-        if let Some(value) = self.stack.peek() {
-            return Some(match value {
-                StackValue::Literal(value) => value,
-                StackValue::Addr(addr) => self.heap.get(*addr),
-            });
-        }
-
-        None
+        self.stack.peek()
     }
 
     #[inline]
-    pub fn pop_stack<'a>(&'a mut self) -> Cow<'a, Type> {
-        match self.stack.pop() {
-            StackValue::Literal(value) => Cow::Owned(value),
-            StackValue::Addr(addr) => {
-                let val = self.heap.get(addr);
-                Cow::Borrowed(&*val)
-            }
-        }
+    pub fn pop_stack(&mut self) -> Type {
+        self.stack.pop()
     }
 
     #[inline]
-    pub fn double_pop_stack<'a>(&'a mut self) -> (Cow<'a, Type>, Cow<'a, Type>) {
-        (
-            match self.stack.pop() {
-                StackValue::Literal(value) => Cow::Owned(value),
-                StackValue::Addr(addr) => Cow::Borrowed(self.heap.get(addr)),
-            },
-            match self.stack.pop() {
-                StackValue::Literal(value) => Cow::Owned(value),
-                StackValue::Addr(addr) => Cow::Borrowed(self.heap.get(addr)),
-            },
-        )
+    pub fn double_pop_stack(&mut self) -> (Type, Type) {
+        (self.stack.pop(), self.stack.pop())
     }
 
-    pub fn free_locals(&mut self, amnt: usize) {
-        for _ in 0..amnt {
-            self.heap.free(self.local_addrs.pop().unwrap());
-        }
-        self.heap.cleanup()
+    /// Pushes a copy of the slot at `addr`, so that what the slot holds when
+    /// it is loaded is what gets used, even if something evaluated later
+    /// assigns to it. Copies are cheap, big values are behind an `Rc`.
+    #[inline]
+    fn push_slot(&mut self, addr: usize) {
+        let value = self.heap.get(addr).clone();
+        self.stack.push(value);
     }
 
     #[inline]
     pub fn enter_scope(&mut self, return_to: usize) {
-        // self.scopes.push(Scope::new(return_to));
-        self.call_stack.push((return_to, 0));
-        self.fp += 1;
+        // the new frame starts at the top of the heap, above the globals and
+        // every frame that is still live
+        self.call_stack.push((return_to, self.fp));
+        self.fp = self.heap.len();
 
         // if self.fp > 1000 {
         //     panic!("Stack overflow");
@@ -127,16 +178,106 @@ impl VM {
 
     #[inline]
     pub fn exit_scope(&mut self) -> usize {
-        // let scope = self.scopes.pop().expect("Exited from empty scope");
-        let (return_to, amnt) = self.call_stack.pop().expect("Exited from empty scope");
-        // self.heap.free(amnt);
+        let (return_to, fp) = self.call_stack.pop().expect("Exited from empty scope");
 
-        // self.free_locals(amnt);
-        self.fp -= 1;
+        self.heap.truncate(self.fp);
+        self.fp = fp;
         return_to
     }
 
     #[inline]
+    fn assigned(&mut self, global: usize) {
+        let version = &mut self.versions[global];
+        *version = version.wrapping_add(1);
+        if *version == 0 {
+            self.wrapped();
+        }
+    }
+
+    /// A global's version came back around, so a cache that saw it once
+    /// could see the same version again for another function. Every cache
+    /// forgets what it saw and the versions start over.
+    #[cold]
+    fn wrapped(&mut self) {
+        for instruction in &self.program {
+            if let Instr::CallGlobal { version, .. } = instruction {
+                version.set(0);
+            }
+        }
+        self.versions.fill(1);
+
+        #[cfg(feature = "jit")]
+        self.jit.forget();
+    }
+
+    /// Runs the function at `start` as native code instead of interpreting
+    /// it, if the JIT compiled it for arguments like the ones on the stack.
+    #[cfg(feature = "jit")]
+    #[inline]
+    fn native_call(&mut self, start: usize) -> bool {
+        let (stack, heap) = (&self.stack, &self.heap);
+        let arg = |i| stack.peek_nth(i);
+
+        match (self.jit).call(
+            start,
+            &self.program,
+            &self.constants,
+            heap,
+            &self.versions,
+            arg,
+        ) {
+            Some((args, result)) => {
+                for _ in 0..args {
+                    self.stack.pop();
+                }
+                self.stack.push(result);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many arguments the function starting at `start` takes, which its
+    /// header says.
+    #[inline]
+    fn arity(&self, start: usize) -> usize {
+        match self.program[start - 1] {
+            Instr::Function { arity, .. } => arity as usize,
+            ref header => unreachable!("Function at {} has no header: {:?}", start, header),
+        }
+    }
+
+    #[inline]
+    fn call(&mut self, callee: Type, argc: usize) {
+        match callee {
+            Type::FuncPtr(jump) => {
+                ops::arity(&callee, self.arity(jump), argc);
+
+                #[cfg(feature = "jit")]
+                if self.native_call(jump) {
+                    return;
+                }
+
+                self.enter_scope(self.pc);
+                self.pc = jump;
+            }
+            Type::Constructor(ref def, tag) => {
+                let tag = tag as usize;
+                ops::arity(&callee, def.variants[tag].1, argc);
+
+                let def = def.clone();
+                let fields = (0..argc).map(|_| self.pop_stack()).collect();
+
+                let result = self
+                    .heap
+                    .alloc(Variant::new(def, tag, fields), self.stack.values());
+                self.stack.push(result);
+            }
+            top => panic!("Call to non-function {:?}", top),
+        }
+    }
+
+    #[inline(always)]
     pub fn step(&mut self) {
         let instruction = &self.program[self.pc];
         self.pc += 1;
@@ -144,10 +285,14 @@ impl VM {
         match instruction {
             Instr::Halt => {
                 println!("Halt");
-                return;
             }
-            Instr::Push(value) => {
-                self.stack.push(value.clone());
+            Instr::Reserve(globals) => {
+                self.heap.reserve(*globals);
+                self.versions = vec![1; *globals];
+            }
+            Instr::PushConst(index) => {
+                let value = self.constants[*index as usize].clone();
+                self.stack.push(value);
             }
             Instr::Pop => {
                 self.stack.pop();
@@ -155,12 +300,16 @@ impl VM {
 
             Instr::StoreAddr(addr) => {
                 let addr = *addr;
-                let value = self.pop_stack().into_owned();
+                let value = self.pop_stack();
                 self.heap.set(addr, value);
+
+                if addr < self.versions.len() {
+                    self.assigned(addr);
+                }
             }
             Instr::StoreLocal(offset) => {
                 let addr = *offset + self.fp;
-                let value = self.pop_stack().into_owned();
+                let value = self.pop_stack();
                 self.heap.set(addr, value);
 
                 // self.call_stack[self.fp].1 += 1;
@@ -168,304 +317,232 @@ impl VM {
             }
             Instr::StoreGlobal(offset) => {
                 let addr = *offset;
-                let value = self.pop_stack().into_owned();
+                let value = self.pop_stack();
                 self.heap.set(addr, value);
+                self.assigned(addr);
             }
 
             // Instr::LoadDeref(offset) => {
             //     let addr = *offset + self.fp;
             //     let value = self.heap.get(addr);
-            //     self.stack.push(StackValue::Literal(value.clone()));
+            //     self.stack.push(value.clone());
             // }
             Instr::LoadAddr(addr) => {
-                self.stack.push(StackValue::Addr(*addr));
+                self.push_slot(*addr);
             }
             Instr::LoadLocal(offset) => {
-                self.stack.push(StackValue::Addr(*offset + self.fp));
+                self.push_slot(*offset + self.fp);
             }
             Instr::LoadGlobal(offset) => {
-                self.stack.push(StackValue::Addr(*offset));
+                self.push_slot(*offset);
             }
 
             Instr::Jump(to) => {
                 self.pc = *to;
             }
+            Instr::Function { end, .. } => {
+                self.pc = *end as usize;
+            }
             Instr::JumpIf(to) => {
                 let to = *to;
                 let c_val = self.pop_stack();
-                let value = c_val.as_ref();
+                let value = &c_val;
 
-                if value == &TRUE {
+                if value.is_truthy() {
                     self.pc = to;
                 }
             }
             Instr::JumpIfNot(to) => {
                 let to = *to;
                 let c_val = self.pop_stack();
-                let value = c_val.as_ref();
+                let value = &c_val;
 
-                if value == &FALSE {
+                if !value.is_truthy() {
                     self.pc = to;
                 }
             }
-
-            Instr::Call => {
-                let c_val = self.pop_stack();
-                let top = c_val.as_ref();
-
-                if let Type::FuncPtr(jump) = top {
-                    let jump = *jump;
-                    self.enter_scope(self.pc);
-                    self.pc = jump;
+            Instr::JumpIfOrPop(to) => {
+                let to = *to;
+                if self.peek_stack().is_some_and(Type::is_truthy) {
+                    self.pc = to;
+                } else {
+                    self.stack.pop();
+                }
+            }
+            Instr::JumpIfNotOrPop(to) => {
+                let to = *to;
+                if self.peek_stack().is_some_and(Type::is_truthy) {
+                    self.stack.pop();
                 } else {
-                    panic!("Call to non-function {:?}", top);
+                    self.pc = to;
+                }
+            }
+            Instr::JumpIfNotNoneOrPop(to) => {
+                let to = *to;
+                if self.peek_stack() == Some(&Type::None) {
+                    self.stack.pop();
+                } else {
+                    self.pc = to;
                 }
             }
+            Instr::JumpIfNone(to) => {
+                let to = *to;
+                if self.peek_stack() == Some(&Type::None) {
+                    self.pc = to;
+                }
+            }
+
+            Instr::Call(argc) => {
+                let argc = *argc;
+                let callee = self.pop_stack();
+                self.call(callee, argc);
+            }
             Instr::NativeCall(nf) => {
                 let nf = nf.clone();
-                nf.call(self);
+                let result = nf.call(self);
+                self.stack.push(result);
             }
             Instr::Return => {
-                let value = &self.stack.pop();
-                match value {
-                    StackValue::Literal(_) => {
-                        self.stack.push(value.to_owned());
-                    }
-                    StackValue::Addr(addr) => self
-                        .stack
-                        .push(StackValue::Literal(self.heap.get(*addr).to_owned())),
-                }
-
                 self.pc = self.exit_scope();
             }
 
             Instr::Add => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs + rhs),
-                    (Type::String(lhs), rhs) => Type::String(lhs.to_owned() + &rhs.to_string()),
-                    (lhs, Type::String(rhs)) => Type::String(lhs.to_string() + rhs),
-                    _ => panic!("Addition not supported"),
-                };
+                let result = ops::add(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Sub => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs - rhs),
-                    _ => panic!("Subtraction not supported"),
-                };
+                let result = ops::sub(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Mul => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs * rhs),
-                    _ => panic!("Multiplication not supported"),
-                };
+                let result = ops::mul(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Div => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
+                let result = ops::div(&c2, &c1);
 
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs / rhs),
-                    _ => panic!("Division not supported"),
-                };
+                self.stack.push(result);
+            }
+            Instr::FloorDiv => {
+                let (c1, c2) = self.double_pop_stack();
+                let result = ops::floor_div(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Mod => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
+                let result = ops::modulo(&c2, &c1);
 
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs % rhs),
-                    _ => panic!("Modulo not supported"),
-                };
-
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Eq => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
+                let result = ops::eq(&c2, &c1);
 
-                // println!("Eq {:?} {:?}", lhs, rhs);
-
-                let result = match (&lhs, &rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs == rhs),
-                    (Type::String(lhs), Type::String(rhs)) => Type::Bool(lhs == rhs),
-                    (Type::Bool(lhs), Type::Bool(rhs)) => Type::Bool(lhs == rhs),
-                    _ => Type::Bool(lhs == rhs),
-                };
-
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Neq => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs != rhs),
-                    (Type::String(lhs), Type::String(rhs)) => Type::Bool(lhs != rhs),
-                    (Type::Bool(lhs), Type::Bool(rhs)) => Type::Bool(lhs != rhs),
-                    _ => panic!("Inequality not supported"),
-                };
+                let result = ops::neq(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Lt => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs < rhs),
-                    _ => panic!("Less than not supported"),
-                };
+                let result = ops::lt(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Gt => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs > rhs),
-                    _ => panic!("Greater than not supported"),
-                };
+                let result = ops::gt(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Lte => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs <= rhs),
-                    _ => panic!("Less than or equal not supported"),
-                };
+                let result = ops::lte(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Gte => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Bool(lhs >= rhs),
-                    _ => panic!("Greater than or equal not supported"),
-                };
+                let result = ops::gte(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
-            Instr::And => {
-                let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
+            Instr::Not => {
+                let c_val = self.pop_stack();
+                let result = ops::not(&c_val);
 
-                let result = match (lhs, rhs) {
-                    (Type::Bool(lhs), Type::Bool(rhs)) => Type::Bool(*lhs && *rhs),
-                    _ => panic!("And not supported"),
-                };
+                self.stack.push(result);
+            }
+            Instr::Neg => {
+                let c_val = self.pop_stack();
+                let result = ops::neg(&c_val);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
-            Instr::Or => {
+            Instr::Pow => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
+                let result = ops::pow(&c2, &c1);
 
-                let result = match (lhs, rhs) {
-                    (Type::Bool(lhs), Type::Bool(rhs)) => Type::Bool(*lhs || *rhs),
-                    _ => panic!("Or not supported"),
-                };
+                self.stack.push(result);
+            }
+            Instr::BitAnd => {
+                let (c1, c2) = self.double_pop_stack();
+                let result = ops::bit_and(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
-            Instr::Not => {
-                let c_val = self.pop_stack();
-                let value = c_val.as_ref();
+            Instr::BitOr => {
+                let (c1, c2) = self.double_pop_stack();
+                let result = ops::bit_or(&c2, &c1);
 
-                let result = match value {
-                    Type::Bool(value) => Type::Bool(!value),
-                    _ => panic!("Not not supported"),
-                };
+                self.stack.push(result);
+            }
+            Instr::BitXor => {
+                let (c1, c2) = self.double_pop_stack();
+                let result = ops::bit_xor(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
-            Instr::Neg => {
+            Instr::BitNot => {
                 let c_val = self.pop_stack();
-                let value = c_val.as_ref();
+                let result = ops::bit_not(&c_val);
 
-                let result = match value {
-                    Type::Number(value) => Type::Number(-value),
-                    _ => panic!("Negation not supported"),
-                };
-
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
-            Instr::Pow => {
+            Instr::Shl => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
+                let result = ops::shl(&c2, &c1);
 
-                let result = match (lhs, rhs) {
-                    (Type::Number(lhs), Type::Number(rhs)) => Type::Number(lhs.powf(*rhs)),
-                    _ => panic!("Power not supported"),
-                };
+                self.stack.push(result);
+            }
+            Instr::Shr => {
+                let (c1, c2) = self.double_pop_stack();
+                let result = ops::shr(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::Index => {
                 let (c1, c2) = self.double_pop_stack();
-                let item = c1.as_ref();
-                let index = c2.as_ref();
-
-                let result = match (item, index) {
-                    // (Type::List(list), Type::Number(index)) => {
-                    //     let index = index as usize;
-                    //     if index >= list.len() {
-                    //         panic!("Index out of bounds");
-                    //     }
-                    //     list[index].clone()
-                    // }
-                    (Type::String(string), Type::Number(index)) => {
-                        let index = *index as usize;
-                        if index >= string.len() {
-                            panic!("Index out of bounds");
-                        }
-                        Type::String(string.chars().nth(index).unwrap().to_string())
-                    }
-                    _ => panic!("Index not supported"),
-                };
-                self.stack.push(StackValue::Literal(result));
+                let result = ops::index(&c1, &c2);
+
+                self.stack.push(result);
             }
             // Instr::IndexStore => {
             //     let item = self.stack.pop();
             //     let (c1, c2) = self.double_pop_stack();
-            //     let value = c1.as_ref();
-            //     let index = c2.as_ref();
+            //     let value = &c1;
+            //     let index = &c2;
 
             //     match (item, index) {
             //         (StackValue::Addr(addr), Type::Number(idx)) => {
@@ -487,36 +564,126 @@ impl VM {
             // }
             Instr::Join => {
                 let (c1, c2) = self.double_pop_stack();
-                let rhs = c1.as_ref();
-                let lhs = c2.as_ref();
-
-                let result = match (lhs, rhs) {
-                    (Type::String(lhs), rhs) => Type::String(lhs.to_owned() + &rhs.to_string()),
-                    (lhs, Type::String(rhs)) => Type::String(lhs.to_string() + rhs),
-                    _ => panic!("Power not supported"),
-                };
+                let result = ops::join(&c2, &c1);
 
-                self.stack.push(StackValue::Literal(result));
+                self.stack.push(result);
             }
             Instr::JoinMany(amnt) => {
-                let mut res = None;
-
+                // every part is written into one buffer, rather than joining
+                // them a pair at a time
+                let mut result = String::new();
                 for _ in 0..*amnt {
-                    let c_val = self.pop_stack();
-                    let value = c_val.as_ref();
-
-                    if let Some(result) = res {
-                        res = Some(match (result, value) {
-                            (lhs, Type::String(value)) => Type::String(lhs.to_string() + value),
-                            (Type::String(value), rhs) => Type::String(value + &rhs.to_string()),
-                            _ => panic!("Join not supported"),
+                    ops::push_str(&mut result, &self.pop_stack());
+                }
+
+                self.stack.push(Type::String(result.into()));
+            }
+            Instr::Format(spec) => {
+                let spec = spec.clone();
+                let c_val = self.pop_stack();
+                let result = ops::format_with(&c_val, &spec);
+
+                self.stack.push(Type::String(result.into()));
+            }
+            Instr::Member(name) => {
+                let name = self.name(*name);
+                let c_val = self.pop_stack();
+                let value = &c_val;
+
+                let result = match value {
+                    Type::Enum(def) => {
+                        ops::variant(def, &name, &mut self.heap, self.stack.values())
+                    }
+                    Type::Module(module) => {
+                        let addr = *module.exports.get(name.as_str()).unwrap_or_else(|| {
+                            panic!("Module {} has no member {}", module.name, name)
                         });
-                    } else {
-                        res = Some(value.to_owned());
+
+                        self.push_slot(addr);
+                        return;
+                    }
+                    _ => panic!("Member access not supported on {:?}", value),
+                };
+
+                self.stack.push(result);
+            }
+            Instr::IsVariant(name) => {
+                let name = self.name(*name);
+                let (value, def) = self.double_pop_stack();
+
+                let result = ops::is_variant(&value, &def, &name);
+
+                self.stack.push(result);
+            }
+            Instr::Field(i) => {
+                let i = *i;
+                let c_val = self.pop_stack();
+                let value = &c_val;
+
+                let result = ops::field(value, i);
+
+                self.stack.push(result);
+            }
+
+            Instr::AddConst(index) => {
+                let constant = &self.constants[*index as usize];
+                let value = self.stack.pop();
+                let result = ops::add(&value, constant);
+
+                self.stack.push(result);
+            }
+            Instr::SubConst(index) => {
+                let constant = &self.constants[*index as usize];
+                let value = self.stack.pop();
+                let result = ops::sub(&value, constant);
+
+                self.stack.push(result);
+            }
+            Instr::CompareJumpLocalConst {
+                local,
+                constant,
+                op,
+                jump_if,
+                to,
+            } => {
+                let value = self.heap.get(self.fp + *local as usize);
+                let constant = &self.constants[*constant as usize];
+                if compare(*op, value, constant) == *jump_if {
+                    self.pc = *to as usize;
+                }
+            }
+            Instr::CallGlobal {
+                global,
+                argc,
+                version,
+                to,
+            } => {
+                let global = *global as usize;
+                if version.get() == self.versions[global] {
+                    let to = to.get() as usize;
+
+                    #[cfg(feature = "jit")]
+                    if self.native_call(to) {
+                        return;
                     }
+
+                    self.enter_scope(self.pc);
+                    self.pc = to;
+                } else {
+                    // `call` checks the arguments, a hit calls the same
+                    // function with as many again so doesn't have to
+                    let callee = self.heap.get(global).clone();
+                    if let Type::FuncPtr(start) = callee {
+                        version.set(self.versions[global]);
+                        to.set(start as u32);
+                    }
+                    self.call(callee, *argc as usize);
                 }
-                self.stack
-                    .push(StackValue::Literal(res.unwrap_or(Type::None)));
+            }
+            Instr::LoadLocal2(a, b) => {
+                let (a, b) = (*a as usize, *b as usize);
+                self.push_slot(a + self.fp);
+                self.push_slot(b + self.fp);
             }
             Instr::Noop => {}
             _ => {
@@ -526,11 +693,370 @@ impl VM {
     }
 }
 
-// fn replace_nth_char_ascii(s: &mut str, idx: usize, newchar: char) {
-//     let s_bytes: &mut [u8] = unsafe { s.as_bytes_mut() };
-//     assert!(idx < s_bytes.len());
-//     assert!(s_bytes[idx].is_ascii());
-//     assert!(newchar.is_ascii());
-//     // we've made sure this is safe.
-//     s_bytes[idx] = newchar as u8;
-// }
+#[inline]
+fn compare(op: Compare, lhs: &Type, rhs: &Type) -> bool {
+    let result = match op {
+        Compare::Eq => ops::eq(lhs, rhs),
+        Compare::Neq => ops::neq(lhs, rhs),
+        Compare::Lt => ops::lt(lhs, rhs),
+        Compare::Gt => ops::gt(lhs, rhs),
+        Compare::Lte => ops::lte(lhs, rhs),
+        Compare::Gte => ops::gte(lhs, rhs),
+    };
+    result.is_truthy()
+}
+
+fn opcode(instruction: &Instr) -> String {
+    let debug = format!("{:?}", instruction);
+    debug
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// What `VM::profile` counted, most dispatched first.
+pub struct Profile {
+    pub dispatched: usize,
+    pub opcodes: Vec<(String, usize)>,
+    pub pairs: Vec<(String, usize)>,
+}
+
+impl Profile {
+    fn ranked(counts: FxHashMap<String, usize>) -> Vec<(String, usize)> {
+        let mut ranked: Vec<_> = counts.into_iter().filter(|(_, n)| *n > 0).collect();
+        ranked.sort_by(|(a, n), (b, m)| m.cmp(n).then_with(|| a.cmp(b)));
+        ranked
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "dispatched {} instructions", self.dispatched)?;
+
+        let percent = |n: usize| 100.0 * n as f64 / self.dispatched.max(1) as f64;
+        writeln!(f, "\nopcode\t\t\tcount\t%")?;
+        for (opcode, n) in &self.opcodes {
+            writeln!(f, "{:<24}{}\t{:.1}", opcode, n, percent(*n))?;
+        }
+        writeln!(f, "\npair\t\t\t\tcount\t%")?;
+        for (pair, n) in self.pairs.iter().take(10) {
+            writeln!(f, "{:<32}{}\t{:.1}", pair, n, percent(*n))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fuse;
+    use crate::frontend::parser;
+
+    fn ran(code: &str) -> VM {
+        let (program, _) = Instr::compile(parser::parse_code(code).unwrap());
+        let mut vm = VM::new();
+        vm.load(program);
+        vm.run();
+        vm
+    }
+
+    // runs `code` and returns the global it declared last
+    fn result(code: &str) -> Type {
+        let vm = ran(code);
+        vm.heap.get(vm.heap.len() - 1).clone()
+    }
+
+    // like `result`, but formatted while the VM still owns what it points to
+    fn shown(code: &str) -> String {
+        let vm = ran(code);
+        vm.heap.get(vm.heap.len() - 1).to_string()
+    }
+
+    fn string(s: &str) -> Type {
+        Type::String(Rc::new(s.to_owned()))
+    }
+
+    #[test]
+    fn enums() {
+        let shape = "enum Shape { Circle(r), Rect(w, h), Empty }\n";
+        assert_eq!(
+            shown(&format!("{}let result = Shape.Rect(2, 3)", shape)),
+            "Shape.Rect(2, 3)"
+        );
+        assert_eq!(
+            result(&format!(
+                "{}let area = 0\nif let Shape.Rect(w, h) = Shape.Rect(2, 3) {{ area = w * h }}\nlet result = area",
+                shape
+            )),
+            Type::Int(6)
+        );
+        assert_eq!(
+            result(&format!(
+                "{}let found = 0\nmatch Shape.Empty {{ Shape.Circle(r) => {{ found = r }} _ => {{ found = 1 }} }}\nlet result = found",
+                shape
+            )),
+            Type::Int(1)
+        );
+        assert_eq!(
+            result(&format!(
+                "{}let result = Shape.Circle(1) == Shape.Circle(1)",
+                shape
+            )),
+            Type::Bool(true)
+        );
+    }
+
+    #[test]
+    fn collects_garbage() {
+        let code = "enum E { A(x), B }\nlet kept = E.A(E.B)\nlet i = 0\nwhile i < 10 { E.A(i)\ni += 1 }\nlet result = gc()";
+        assert_eq!(result(code), Type::Int(10));
+
+        assert_eq!(ran(code).heap.stats().live, 2);
+        assert_eq!(
+            shown(&code.replace("let result = gc()", "gc()\nlet result = kept")),
+            "E.A(E.B)"
+        );
+    }
+
+    // constructors the resolver can't see through are still checked
+    #[test]
+    #[should_panic(expected = "Shape.Rect expects 2 fields, got 1")]
+    fn constructor_arity() {
+        result("enum Shape { Rect(w, h) }\nlet rect = Shape.Rect\nlet result = rect(1)");
+    }
+
+    #[test]
+    #[should_panic(expected = "Function expects 2 arguments, got 3")]
+    fn function_arity() {
+        result("fn f(a, b) { return a }\nlet result = f(1, 2, 3)");
+    }
+
+    #[test]
+    fn block_scopes() {
+        assert_eq!(
+            result("let x = 1\n{ let x = 2 }\nlet result = x"),
+            Type::Int(1)
+        );
+        assert_eq!(result("let x = 1\n{ x = 2 }\nlet result = x"), Type::Int(2));
+        // the second block reuses the first one's slot
+        assert_eq!(
+            result(
+                "fn f() {\nlet a = 1\n{ let b = 10\na = a + b }\n{ let c = 100\na = a + c }\nreturn a\n}\nlet result = f()"
+            ),
+            Type::Int(111)
+        );
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        assert_eq!(result("let result = 7 / 2"), Type::Number(3.5));
+        assert_eq!(result("let result = 6 / 2"), Type::Number(3.0));
+        assert_eq!(result("let result = 7 ~/ 2"), Type::Int(3));
+        assert_eq!(result("let result = -7 ~/ 2"), Type::Int(-4));
+        assert_eq!(result("let result = -7.5 ~/ 2"), Type::Number(-4.0));
+        assert_eq!(result("let result = 7 % 3"), Type::Int(1));
+        assert_eq!(result("let result = 1 + 2.5"), Type::Number(3.5));
+        assert_eq!(result("let result = 3 == 3.0"), Type::Bool(true));
+        assert_eq!(result("let result = 2 * 3").to_string(), "6");
+        assert_eq!(result("let result = 2 * 3.0").to_string(), "6.0");
+    }
+
+    #[test]
+    fn overflow_promotes() {
+        let big = |code: &str| result(&format!("let result = {}", code)).to_string();
+        assert_eq!(big("9223372036854775807 + 1"), "9223372036854775808");
+        assert_eq!(big("-9223372036854775807 - 2"), "-9223372036854775809");
+        assert_eq!(big("4611686018427387904 * 4"), "18446744073709551616");
+        assert_eq!(big("2 ** 64"), "18446744073709551616");
+        assert_eq!(big("18446744073709551616 ~/ 2"), "9223372036854775808");
+        // and results that fit again come back as ints
+        assert_eq!(
+            result("let result = 9223372036854775808 - 1"),
+            Type::Int(i64::MAX)
+        );
+        assert_eq!(result("let result = 2 ** 10"), Type::Int(1024));
+        assert_eq!(result("let result = 2 ** -1"), Type::Number(0.5));
+    }
+
+    #[test]
+    fn bits() {
+        assert_eq!(result("let result = 0b1100 & 0b1010"), Type::Int(0b1000));
+        assert_eq!(
+            result("let result = 0b1100 | 0b1010 ^ 0b0110"),
+            Type::Int(0b1100)
+        );
+        assert_eq!(
+            result("let result = 1 << 70").to_string(),
+            "1180591620717411303424"
+        );
+        assert_eq!(result("let result = -1 >> 100"), Type::Int(-1));
+        assert_eq!(result("let result = ~5"), Type::Int(-6));
+    }
+
+    #[test]
+    fn truthiness() {
+        assert_eq!(result("let result = 0 || \"\" || 3"), Type::Int(3));
+        assert_eq!(result("let result = 1 && none"), Type::None);
+        assert_eq!(result("let result = !\"\""), Type::Bool(true));
+        // the right operand is never evaluated once the left decides
+        assert_eq!(
+            result("let x = 1\nfn bump() { x = 2\nreturn true }\nfalse && bump()\ntrue || bump()\nlet result = x"),
+            Type::Int(1)
+        );
+        assert_eq!(result("let result = none ?? 0 ?? 1"), Type::Int(0));
+        assert_eq!(result("let s = none\nlet result = s?.name"), Type::None);
+        assert_eq!(shown("enum E { A }\nlet result = E?.A"), "E.A");
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(
+            result("let i = 3\nlet result = if (i % 2 == 0) { \"even\" } else { \"odd\" }"),
+            string("odd")
+        );
+        assert_eq!(result("let result = { let w = 3\nw * 4 }"), Type::Int(12));
+        assert_eq!(
+            result(
+                "enum E { A(n), B }\nlet result = match E.A(5) { E.A(n) => { n + 1 } _ => { 0 } }"
+            ),
+            Type::Int(6)
+        );
+        assert_eq!(result("let result = if (false) { 1 }"), Type::None);
+        // the second block's local takes the first one's slot, whose value was
+        // copied out before
+        assert_eq!(
+            result("fn f() { return { let w = 3\nw } + { let z = 4\nz } }\nlet result = f()"),
+            Type::Int(7)
+        );
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(
+            result("let result = f\"{3.14159:>8.2}|{\"x\":?}\"").to_string(),
+            "    3.14|\"x\""
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(result("let result = 2 < 2.5"), Type::Bool(true));
+        // exact, where rounding 2^53 + 1 to a float would make them equal
+        assert_eq!(
+            result("let result = 9007199254740993 > 9007199254740992.0"),
+            Type::Bool(true)
+        );
+        assert_eq!(result("let result = \"ab\" < \"abc\""), Type::Bool(true));
+        assert_eq!(
+            result("let nan = 0.0 / 0.0\nlet result = nan < 1 || nan >= 1"),
+            Type::Bool(false)
+        );
+        // each operand of a chain is evaluated once
+        assert_eq!(
+            result(
+                "let n = 0\nfn five() { n = n + 1\nreturn 5 }\n1 < five() <= 10\nlet result = n"
+            ),
+            Type::Int(1)
+        );
+        assert_eq!(result("let result = 1 < 3 < 2"), Type::Bool(false));
+    }
+
+    #[test]
+    fn equality() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let hash = |value: &Type| {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(Type::Int(1), Type::Number(1.0));
+        assert_eq!(hash(&Type::Int(1)), hash(&Type::Number(1.0)));
+        assert_eq!(Type::Number(f64::NAN), Type::Number(f64::NAN));
+        assert_ne!(Type::Int(1), string("1"));
+        // an integral float equals only the integer it holds
+        assert_ne!(Type::Number(2.0), Type::Int(3));
+        assert_ne!(Type::Int(3), Type::Number(2.0));
+        assert_eq!(Type::Number(3.0), Type::Int(3));
+        assert_eq!(result("let result = 2 ** 64 != 1.0"), Type::Bool(true));
+
+        assert_eq!(result("let result = 1 != none"), Type::Bool(true));
+        assert_eq!(
+            result("fn f() {}\nfn g() {}\nlet result = f == f && f != g"),
+            Type::Bool(true)
+        );
+        assert_eq!(
+            result("enum E { A(n) }\nlet result = E.A(1) == E.A(1.0) && E.A(1) != E.A(2)"),
+            Type::Bool(true)
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(result("let result = \"n: \" + 1"), string("n: 1"));
+        assert_eq!(result("let result = 1.5 .. \"x\""), string("1.5x"));
+        assert_eq!(result("let result = \"héllo\"[1]"), string("é"));
+    }
+
+    #[test]
+    fn constant_pool() {
+        let code = "let a = 1\nlet b = 1\nlet c = 1.0\nlet d = \"s\"\nlet e = \"s\"";
+        let (program, _) = Instr::compile(parser::parse_code(code).unwrap());
+        let count = |value: &str| {
+            program
+                .constants
+                .iter()
+                .filter(|constant| constant.repr() == value)
+                .count()
+        };
+        assert_eq!(count("1"), 1);
+        assert_eq!(count("1.0"), 1);
+        assert_eq!(count("\"s\""), 1);
+        assert_eq!(result(code), string("s"));
+    }
+
+    #[test]
+    fn reassigned_globals_are_called() {
+        assert_eq!(
+            result(
+                "let f = () => 1\nlet total = 0\nlet i = 0\nwhile (i < 4) { total = total * 10 + f()\nif (i == 1) { f = () => 2 }\ni += 1 }\nlet result = total"
+            ),
+            Type::Int(1122)
+        );
+    }
+
+    #[test]
+    fn caches_are_emptied_when_a_version_wraps() {
+        let code = "let f = () => 1\nlet result = f()";
+        let (mut program, start) = Instr::compile(parser::parse_code(code).unwrap());
+        fuse::fuse(&mut program, start);
+        let mut vm = VM::new();
+        vm.load(program);
+        vm.run();
+
+        let cached = |vm: &VM| {
+            vm.program.iter().find_map(|instruction| match instruction {
+                Instr::CallGlobal {
+                    global, version, ..
+                } if version.get() != 0 => Some(*global as usize),
+                _ => None,
+            })
+        };
+        let global = cached(&vm).expect("the call wasn't cached");
+
+        vm.versions[global] = u32::MAX;
+        vm.assigned(global);
+        assert_eq!(cached(&vm), None);
+        assert!(vm.versions.iter().all(|version| *version == 1));
+    }
+
+    #[test]
+    fn operands_keep_the_value_they_were_loaded_with() {
+        assert_eq!(
+            result("let y = 1\nfn bumpy() { y = 7\nreturn 1 }\nlet result = y + bumpy()"),
+            Type::Int(2)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use wasmi::{core::HostError, Caller, Engine, Extern, Linker, Memory, Module, Store};
+
+use crate::backend::instruction::Type;
+
+// Runs the modules `lower` makes in-process, providing the functions they
+// import from "glass".
+
+/// What `fail` stops a module with, by index.
+const FAILURES: [&str; 9] = [
+    "Operation not supported on these types",
+    "Call to non-function",
+    "Division by zero",
+    "Index out of bounds",
+    "Value stack overflow",
+    "Call stack overflow",
+    "Too many slots",
+    "Shift by a negative amount",
+    "Out of memory",
+];
+
+/// Why a module stopped before it got to the end.
+#[derive(Debug)]
+pub enum Trap {
+    /// It used something only the VMs can do, like big ints or enums.
+    Unsupported(String),
+    /// It failed where a VM would have panicked.
+    Failed(String),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::Unsupported(what) => write!(f, "{} are not supported in wasm", what),
+            Trap::Failed(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl HostError for Trap {}
+
+/// Runs a module made by `lower`, returning what it printed and whether it
+/// ran to the end.
+pub fn run(wasm: &[u8]) -> (Vec<u8>, Result<(), Trap>) {
+    let engine = Engine::default();
+    let module = Module::new(&engine, wasm).expect("Not a module made by `lower`");
+    // everything printed so far
+    let mut store = Store::new(&engine, Vec::new());
+
+    let mut linker = <Linker<Vec<u8>>>::new(&engine);
+    linker
+        .func_wrap(
+            "glass",
+            "stdout",
+            |mut caller: Caller<'_, Vec<u8>>, at: i32, len: i32| {
+                let memory = memory(&caller);
+                let (memory, out) = memory.data_and_store_mut(&mut caller);
+                out.extend_from_slice(&memory[at as usize..][..len as usize]);
+            },
+        )
+        .unwrap()
+        .func_wrap("glass", "time", || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Failed to get time")
+                .as_millis() as i64
+        })
+        .unwrap()
+        .func_wrap(
+            "glass",
+            "show_float",
+            |mut caller: Caller<'_, Vec<u8>>, float: f64, at: i32| {
+                let text = Type::Number(float).to_string();
+                memory(&caller).data_mut(&mut caller)[at as usize..][..text.len()]
+                    .copy_from_slice(text.as_bytes());
+                text.len() as i32
+            },
+        )
+        .unwrap()
+        .func_wrap("glass", "fmod", |lhs: f64, rhs: f64| lhs % rhs)
+        .unwrap()
+        .func_wrap("glass", "powf", |lhs: f64, rhs: f64| lhs.powf(rhs))
+        .unwrap()
+        .func_wrap(
+            "glass",
+            "unsupported",
+            |caller: Caller<'_, Vec<u8>>, what: i32| -> Result<(), wasmi::Error> {
+                let memory = memory(&caller).data(&caller);
+                let at = what as usize;
+                let len = u32::from_le_bytes(memory[at..at + 4].try_into().unwrap()) as usize;
+                let what = String::from_utf8_lossy(&memory[at + 4..][..len]);
+                Err(wasmi::Error::host(Trap::Unsupported(what.into_owned())))
+            },
+        )
+        .unwrap()
+        .func_wrap("glass", "fail", |error: i32| -> Result<(), wasmi::Error> {
+            let error = FAILURES[error as usize].to_owned();
+            Err(wasmi::Error::host(Trap::Failed(error)))
+        })
+        .unwrap();
+
+    let result = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .and_then(|instance| instance.get_typed_func::<(), ()>(&store, "run"))
+        .and_then(|run| run.call(&mut store, ()))
+        .map_err(|error| match error.downcast_ref::<Trap>() {
+            Some(_) => error.downcast().unwrap(),
+            None => Trap::Failed(error.to_string()),
+        });
+
+    (store.into_data(), result)
+}
+
+fn memory(caller: &Caller<'_, Vec<u8>>) -> Memory {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .expect("Module exports no memory")
+}
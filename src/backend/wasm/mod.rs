@@ -0,0 +1,438 @@
+pub mod host;
+
+use std::fmt::Write;
+
+use fxhash::FxHashMap;
+
+use crate::{frontend::FormatSpec, stdlib::NativeFunction};
+
+use super::instruction::{Compare, Instr, Program, Type};
+
+// Lowers a compiled program to a WebAssembly module. Every instruction turns
+// into a few calls into `runtime.wat`, which keeps the same value stack,
+// slots and call frames the stack VM does in the module's memory. Jumps can
+// go anywhere, so the code is split into basic blocks that one loop
+// dispatches between, and a call is a jump that remembers which block to
+// come back to.
+//
+// Only what can be done without the host is lowered: ints, floats, bools,
+// strings and functions. Anything else, like big ints or enums, stops the
+// module with `host::Trap::Unsupported` when it is used, and `unsupported`
+// lists what a program uses of it up front.
+
+const RUNTIME: &str = include_str!("runtime.wat");
+// where the string constants go, see `runtime.wat`
+const DATA: usize = 0x590000;
+
+/// The `.wasm` module that runs `program`, see `host` for the imports it
+/// needs.
+pub fn lower(program: &Program) -> Vec<u8> {
+    wat::parse_str(text(program)).expect("Lowered an invalid module")
+}
+
+/// The module `lower` makes, as text.
+pub fn text(program: &Program) -> String {
+    lowered(program, 0).0
+}
+
+/// What the code from `start` on uses that the module can't do, each once,
+/// in the order it is first used. The prelude before `start` is lowered
+/// whether the program calls it or not, so it isn't looked at, and big ints
+/// that only come from arithmetic overflowing aren't known until it runs.
+pub fn unsupported(program: &Program, start: usize) -> Vec<String> {
+    lowered(program, start).1
+}
+
+fn lowered(program: &Program, start: usize) -> (String, Vec<String>) {
+    let mut lowering = Lowering::new(program, start);
+
+    let mut body = String::new();
+    for (pc, instruction) in program.code.iter().enumerate() {
+        if lowering.blocks.contains_key(&pc) {
+            body.push_str("    end\n");
+        }
+        lowering.instruction(&mut body, pc, instruction);
+    }
+    body.push_str("    end\n");
+
+    let mut out = format!("(module\n{}\n", RUNTIME);
+
+    let heap = (DATA + lowering.data.len()).next_multiple_of(8);
+    writeln!(out, "(memory (export \"memory\") {})", heap / 0x10000 + 1).unwrap();
+    writeln!(out, "(global $heap (mut i32) (i32.const {}))", heap).unwrap();
+    writeln!(
+        out,
+        "(data (i32.const {}) \"{}\")",
+        DATA,
+        escape(&lowering.data)
+    )
+    .unwrap();
+
+    // one block per basic block, the first innermost. The code of each
+    // follows its `end`, so it falls through to the next one
+    out.push_str("(func (export \"run\") (local $pc i32)\n  loop $dispatch\n");
+    out.push_str(&"    block\n".repeat(lowering.blocks.len()));
+    out.push_str("    local.get $pc\n    br_table");
+    for block in 0..lowering.blocks.len() {
+        write!(out, " {}", block).unwrap();
+    }
+    out.push('\n');
+    out.push_str(&body);
+    out.push_str("  end)\n)\n");
+    (out, lowering.unsupported)
+}
+
+struct Lowering<'a> {
+    program: &'a Program,
+    // the pc every basic block starts at -> its index
+    blocks: FxHashMap<usize, usize>,
+    // string constants, each its length then its bytes
+    data: Vec<u8>,
+    strings: FxHashMap<String, usize>,
+    // what the lowered code from `start` on traps on, see `unsupported`
+    start: usize,
+    pc: usize,
+    unsupported: Vec<String>,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(program: &'a Program, start: usize) -> Lowering<'a> {
+        let code = &program.code;
+
+        // a block starts wherever something can jump to, and after anything
+        // that jumps. The last one is empty, falling off it ends the program
+        let mut starts = vec![0, code.len()];
+        starts.extend(code.iter().filter_map(Instr::jump_target));
+        starts.extend(
+            program
+                .constants
+                .iter()
+                .filter_map(|constant| match constant {
+                    Type::FuncPtr(start) => Some(*start),
+                    _ => None,
+                }),
+        );
+        for (pc, instruction) in code.iter().enumerate() {
+            if instruction.jump_target().is_some()
+                || matches!(
+                    instruction,
+                    Instr::Call(_) | Instr::CallGlobal { .. } | Instr::Return
+                )
+            {
+                starts.push(pc + 1);
+            }
+        }
+        starts.sort_unstable();
+        starts.dedup();
+
+        Lowering {
+            program,
+            blocks: starts
+                .into_iter()
+                .enumerate()
+                .map(|(block, pc)| (pc, block))
+                .collect(),
+            data: vec![],
+            strings: FxHashMap::default(),
+            start,
+            pc: 0,
+            unsupported: vec![],
+        }
+    }
+
+    /// The address of the message for something the module can't do,
+    /// noting that the program uses it.
+    fn unsupported(&mut self, what: &str) -> usize {
+        if self.pc >= self.start && !self.unsupported.iter().any(|seen| seen == what) {
+            self.unsupported.push(what.to_owned());
+        }
+        self.string(what)
+    }
+
+    /// The address of a string constant.
+    fn string(&mut self, string: &str) -> usize {
+        if let Some(&at) = self.strings.get(string) {
+            return at;
+        }
+
+        let at = DATA + self.data.len();
+        self.data.extend(
+            (string.len() as u32)
+                .to_le_bytes()
+                .into_iter()
+                .chain(string.bytes()),
+        );
+        self.strings.insert(string.to_owned(), at);
+        at
+    }
+
+    /// The tag and payload of a constant, see `runtime.wat`.
+    fn value(&mut self, constant: &Type) -> (u32, i64) {
+        let other = |lowering: &mut Self, what| (7, lowering.unsupported(what) as i64);
+
+        match constant {
+            Type::Null => (0, 0),
+            Type::None => (1, 0),
+            Type::Bool(b) => (2, *b as i64),
+            Type::Int(i) => (3, *i),
+            Type::Number(n) => (4, n.to_bits() as i64),
+            Type::String(s) => (5, self.string(s) as i64),
+            Type::FuncPtr(start) => (6, ((*start as i64) << 32) | self.blocks[start] as i64),
+            Type::BigInt(_) => other(self, "big integers"),
+            Type::Enum(_) | Type::Constructor(..) | Type::Variant(_) => other(self, "enums"),
+            Type::Module(_) => other(self, "modules"),
+            Type::Addr(_) => other(self, "heap objects"),
+            Type::Error(_) => other(self, "errors"),
+        }
+    }
+
+    fn instruction(&mut self, out: &mut String, pc: usize, instruction: &Instr) {
+        self.pc = pc;
+        macro_rules! emit {
+            ($($arg:tt)*) => {{
+                writeln!(out, $($arg)*).unwrap();
+            }};
+        }
+        macro_rules! goto {
+            ($to:expr) => {{
+                emit!(
+                    "      i32.const {}\n      local.set $pc\n      br $dispatch",
+                    self.blocks[&$to]
+                );
+            }};
+        }
+        // calls the function on the stack, coming back to the next block
+        macro_rules! call {
+            () => {{
+                emit!("    i32.const {}\n    call $call", self.blocks[&(pc + 1)]);
+                emit!("    local.set $pc\n    br $dispatch");
+            }};
+        }
+        macro_rules! push {
+            ($constant:expr) => {{
+                let program = self.program;
+                let (tag, payload) = self.value(&program.constants[$constant as usize]);
+                emit!("    i32.const {}\n    i64.const {}", tag, payload);
+            }};
+        }
+        macro_rules! local {
+            ($offset:expr) => {{
+                emit!("    global.get $fp\n    i32.const {}\n    i32.add", $offset);
+            }};
+        }
+        macro_rules! unsupported {
+            ($what:expr) => {{
+                let what = self.unsupported($what);
+                emit!("    i32.const {}\n    call $unsupported", what);
+            }};
+        }
+
+        match instruction {
+            Instr::Noop => {}
+            Instr::Halt => unsupported!("halt"),
+            Instr::Reserve(globals) => emit!("    i32.const {}\n    call $grow", globals),
+
+            Instr::LoadAddr(addr) | Instr::LoadGlobal(addr) => {
+                emit!("    i32.const {}\n    call $load", addr)
+            }
+            Instr::LoadLocal(offset) => {
+                local!(offset);
+                emit!("    call $load");
+            }
+            Instr::LoadLocal2(a, b) => {
+                local!(a);
+                emit!("    call $load");
+                local!(b);
+                emit!("    call $load");
+            }
+            Instr::StoreAddr(addr) | Instr::StoreGlobal(addr) => {
+                emit!("    i32.const {}\n    call $store", addr)
+            }
+            Instr::StoreLocal(offset) => {
+                local!(offset);
+                emit!("    call $store");
+            }
+            Instr::PushConst(constant) => {
+                push!(*constant);
+                emit!("    call $push");
+            }
+            Instr::Pop => emit!("    call $drop"),
+
+            Instr::Jump(to) => goto!(*to),
+            Instr::Function { end, .. } => goto!(*end as usize),
+            Instr::JumpIf(to) => {
+                emit!("    call $pop_truthy\n    if");
+                goto!(*to);
+                emit!("    end");
+            }
+            Instr::JumpIfNot(to) => {
+                emit!("    call $pop_truthy\n    i32.eqz\n    if");
+                goto!(*to);
+                emit!("    end");
+            }
+            Instr::JumpIfOrPop(to) => {
+                emit!("    call $peek_truthy\n    if");
+                goto!(*to);
+                emit!("    else\n      call $drop\n    end");
+            }
+            Instr::JumpIfNotOrPop(to) => {
+                emit!("    call $peek_truthy\n    if\n      call $drop\n    else");
+                goto!(*to);
+                emit!("    end");
+            }
+            Instr::JumpIfNotNoneOrPop(to) => {
+                emit!("    call $peek_none\n    if\n      call $drop\n    else");
+                goto!(*to);
+                emit!("    end");
+            }
+            Instr::JumpIfNone(to) => {
+                emit!("    call $peek_none\n    if");
+                goto!(*to);
+                emit!("    end");
+            }
+            Instr::CompareJumpLocalConst {
+                op,
+                jump_if,
+                local,
+                constant,
+                to,
+            } => {
+                local!(local);
+                emit!("    call $load\n    call $pop");
+                push!(*constant);
+                emit!("    call ${}\n    call $truthy", compare(*op));
+                if !jump_if {
+                    emit!("    i32.eqz");
+                }
+                emit!("    if");
+                goto!(*to as usize);
+                emit!("    end");
+            }
+
+            Instr::Call(_) => call!(),
+            Instr::CallGlobal { global, .. } => {
+                emit!("    i32.const {}\n    call $load", global);
+                call!();
+            }
+            Instr::Return => emit!("    call $return\n    local.set $pc\n    br $dispatch"),
+            Instr::NativeCall(function) => match function {
+                NativeFunction::stdout => emit!("    call $stdout"),
+                NativeFunction::time => emit!("    call $time"),
+                NativeFunction::flushout => emit!("    call $flushout"),
+                function => unsupported!(&format!("#{}", function.name())),
+            },
+
+            Instr::Add => emit!("    call $pop2\n    call $add\n    call $push"),
+            Instr::Sub => emit!("    call $pop2\n    call $sub\n    call $push"),
+            Instr::Mul => emit!("    call $pop2\n    call $mul\n    call $push"),
+            Instr::Div => emit!("    call $pop2\n    call $div\n    call $push"),
+            Instr::FloorDiv => emit!("    call $pop2\n    call $floor_div\n    call $push"),
+            Instr::Mod => emit!("    call $pop2\n    call $mod\n    call $push"),
+            Instr::Pow => emit!("    call $pop2\n    call $pow\n    call $push"),
+            Instr::Lt => emit!("    call $pop2\n    call $lt\n    call $push"),
+            Instr::Gt => emit!("    call $pop2\n    call $gt\n    call $push"),
+            Instr::Lte => emit!("    call $pop2\n    call $lte\n    call $push"),
+            Instr::Gte => emit!("    call $pop2\n    call $gte\n    call $push"),
+            Instr::Eq => emit!("    call $pop2\n    call $eq\n    call $push"),
+            Instr::Neq => emit!("    call $pop2\n    call $neq\n    call $push"),
+            Instr::BitAnd => emit!("    call $pop2\n    call $bit_and\n    call $push"),
+            Instr::BitOr => emit!("    call $pop2\n    call $bit_or\n    call $push"),
+            Instr::BitXor => emit!("    call $pop2\n    call $bit_xor\n    call $push"),
+            Instr::Shl => emit!("    call $pop2\n    call $shl\n    call $push"),
+            Instr::Shr => emit!("    call $pop2\n    call $shr\n    call $push"),
+            Instr::Neg => emit!("    call $pop\n    call $neg\n    call $push"),
+            Instr::Not => emit!("    call $pop\n    call $not\n    call $push"),
+            Instr::BitNot => emit!("    call $pop\n    call $bit_not\n    call $push"),
+            Instr::AddConst(constant) => {
+                emit!("    call $pop");
+                push!(*constant);
+                emit!("    call $add\n    call $push");
+            }
+            Instr::SubConst(constant) => {
+                emit!("    call $pop");
+                push!(*constant);
+                emit!("    call $sub\n    call $push");
+            }
+
+            Instr::Index => emit!("    call $pop2\n    call $index\n    call $push"),
+            Instr::IndexStore => unsupported!("index stores"),
+            Instr::Join => emit!("    call $pop2\n    call $join\n    call $push"),
+            Instr::JoinMany(n) => emit!("    i32.const {}\n    call $join_many", n),
+            // a plain interpolation is just the value as a string
+            Instr::Format(spec) if **spec == FormatSpec::default() => {
+                emit!("    i32.const 1\n    call $join_many")
+            }
+            Instr::Format(_) => unsupported!("format specs"),
+
+            Instr::Member(_) => unsupported!("member access"),
+            Instr::IsVariant(..) | Instr::Field(_) => unsupported!("enums"),
+        }
+    }
+}
+
+/// The runtime function for a comparison.
+fn compare(op: Compare) -> &'static str {
+    match op {
+        Compare::Eq => "eq",
+        Compare::Neq => "neq",
+        Compare::Lt => "lt",
+        Compare::Gt => "gt",
+        Compare::Lte => "lte",
+        Compare::Gte => "gte",
+    }
+}
+
+/// Bytes as the contents of a string in the text format.
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len() * 3);
+    for byte in bytes {
+        match byte {
+            b' '..=b'~' if *byte != b'"' && *byte != b'\\' => escaped.push(*byte as char),
+            _ => write!(escaped, "\\{:02x}", byte).unwrap(),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host::Trap, *};
+    use crate::frontend::parser;
+
+    fn run(code: &str) -> (String, Result<(), Trap>) {
+        let (program, _) = Instr::compile(parser::parse_code(code).unwrap());
+        let (stdout, result) = host::run(&lower(&program));
+        (String::from_utf8(stdout).unwrap(), result)
+    }
+
+    #[test]
+    fn runs_like_the_vm() {
+        let (stdout, result) = run(
+            "fn fib(n) { if (n < 2) { return n }\nreturn fib(n - 1) + fib(n - 2) }\nlet s = \"fib\"\nprint(f\"{s} {fib(15)} {7 / 2} {1 < 2 <= 3}\")",
+        );
+        assert!(result.is_ok());
+        assert_eq!(stdout, "fib 610 3.5 true\n");
+    }
+
+    #[test]
+    fn lists_what_it_cant_do() {
+        let unsupported = |code: &str| {
+            let (program, start) = Instr::compile(parser::parse_code(code).unwrap());
+            unsupported(&program, start)
+        };
+        assert_eq!(
+            unsupported("enum E { A(n) }\nlet e = E.A(1)\nprint(f\"{1:>4}\")\nprint(E.A(2))"),
+            ["enums", "member access", "format specs"]
+        );
+        assert_eq!(unsupported("print(18446744073709551616)"), ["big integers"]);
+        assert!(unsupported("print(1 + 2)").is_empty());
+    }
+
+    #[test]
+    fn traps_on_what_it_cant_do() {
+        let (_, result) = run("print(2 ** 70)");
+        assert!(matches!(result, Err(Trap::Unsupported(_))));
+        let (_, result) = run("print(1 ~/ 0)");
+        assert!(matches!(result, Err(Trap::Failed(_))));
+    }
+}
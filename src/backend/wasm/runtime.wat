@@ -0,0 +1,710 @@
+;; The part of every module `lower` makes that is the same for every
+;; program: how values are kept and what the instructions do to them.
+;;
+;; A value is a tag and a 64 bit payload, 16 bytes in memory:
+;;   0 null
+;;   1 none
+;;   2 bool
+;;   3 int
+;;   4 float, its bits
+;;   5 string, the address of its length, which its bytes follow
+;;   6 function, the block it starts at, and its pc in the high half
+;;   7 anything else, the address of a string naming what it is
+;;
+;; Memory holds
+;;   0x100     the text values are shown with
+;;   0x1000    room to write the digits of an int
+;;   0x10000   the value stack
+;;   0x110000  the call stack, a return block and frame pointer per call
+;;   0x190000  slots, the globals then the locals of every live call
+;;   0x590000  string constants, then the strings made while running, which
+;;             are never freed
+
+(import "glass" "stdout" (func $host_stdout (param i32 i32)))
+(import "glass" "time" (func $host_time (result i64)))
+;; writes a float the way the VM shows it, returning how long it is
+(import "glass" "show_float" (func $host_show_float (param f64 i32) (result i32)))
+(import "glass" "fmod" (func $host_fmod (param f64 f64) (result f64)))
+(import "glass" "powf" (func $host_powf (param f64 f64) (result f64)))
+;; these two never return. `unsupported` takes a string naming what the
+;; module can't do, `fail` the index of an error in `host::FAILURES`
+(import "glass" "unsupported" (func $unsupported (param i32)))
+(import "glass" "fail" (func $fail (param i32)))
+
+(global $sp (mut i32) (i32.const 0x10000))
+(global $csp (mut i32) (i32.const 0x110000))
+(global $fp (mut i32) (i32.const 0))
+(global $len (mut i32) (i32.const 0))
+;; where the string being made by $begin starts
+(global $building (mut i32) (i32.const 0))
+
+(data (i32.const 0x100) "nullnonetruefalse<function at=>")
+(data (i32.const 0x120) "\0c\00\00\00big integers")
+
+;; the value stack
+
+(func $push (param $tag i32) (param $payload i64)
+  (if (i32.ge_u (global.get $sp) (i32.const 0x110000))
+    (then (call $fail (i32.const 4))))
+  (i32.store (global.get $sp) (local.get $tag))
+  (i64.store offset=8 (global.get $sp) (local.get $payload))
+  (global.set $sp (i32.add (global.get $sp) (i32.const 16))))
+
+(func $pop (result i32 i64)
+  (global.set $sp (i32.sub (global.get $sp) (i32.const 16)))
+  (i32.load (global.get $sp))
+  (i64.load offset=8 (global.get $sp)))
+
+;; the two operands of a binary operator, left first
+(func $pop2 (result i32 i64 i32 i64)
+  (global.set $sp (i32.sub (global.get $sp) (i32.const 32)))
+  (i32.load (global.get $sp))
+  (i64.load offset=8 (global.get $sp))
+  (i32.load offset=16 (global.get $sp))
+  (i64.load offset=24 (global.get $sp)))
+
+(func $drop
+  (global.set $sp (i32.sub (global.get $sp) (i32.const 16))))
+
+(func $pop_truthy (result i32)
+  (call $truthy (call $pop)))
+
+(func $peek_truthy (result i32)
+  (call $truthy
+    (i32.load (i32.sub (global.get $sp) (i32.const 16)))
+    (i64.load (i32.sub (global.get $sp) (i32.const 8)))))
+
+(func $peek_none (result i32)
+  (i32.eq (i32.load (i32.sub (global.get $sp) (i32.const 16))) (i32.const 1)))
+
+;; slots
+
+(func $slot (param $i i32) (result i32)
+  (i32.add (i32.const 0x190000) (i32.shl (local.get $i) (i32.const 4))))
+
+;; makes sure there are `n` slots, the new ones null
+(func $grow (param $n i32)
+  (if (i32.gt_u (local.get $n) (i32.const 0x40000))
+    (then (call $fail (i32.const 6))))
+  (block $done
+    (loop $next
+      (br_if $done (i32.ge_u (global.get $len) (local.get $n)))
+      (i32.store (call $slot (global.get $len)) (i32.const 0))
+      (global.set $len (i32.add (global.get $len) (i32.const 1)))
+      (br $next))))
+
+(func $load (param $i i32)
+  (call $push
+    (i32.load (call $slot (local.get $i)))
+    (i64.load offset=8 (call $slot (local.get $i)))))
+
+(func $store (param $i i32) (local $tag i32) (local $payload i64)
+  (call $pop)
+  (local.set $payload)
+  (local.set $tag)
+  (call $grow (i32.add (local.get $i) (i32.const 1)))
+  (i32.store (call $slot (local.get $i)) (local.get $tag))
+  (i64.store offset=8 (call $slot (local.get $i)) (local.get $payload)))
+
+;; calls
+
+;; calls the function on the stack, returning the block it starts at. A new
+;; frame starts right above every slot in use
+(func $call (param $return i32) (result i32) (local $tag i32) (local $payload i64)
+  (call $pop)
+  (local.set $payload)
+  (local.set $tag)
+  (if (i32.ne (local.get $tag) (i32.const 6))
+    (then
+      (if (i32.eq (local.get $tag) (i32.const 7))
+        (then (call $unsupported (i32.wrap_i64 (local.get $payload)))))
+      (call $fail (i32.const 1))))
+  (if (i32.ge_u (global.get $csp) (i32.const 0x190000))
+    (then (call $fail (i32.const 5))))
+  (i32.store (global.get $csp) (local.get $return))
+  (i32.store offset=4 (global.get $csp) (global.get $fp))
+  (global.set $csp (i32.add (global.get $csp) (i32.const 8)))
+  (global.set $fp (global.get $len))
+  (i32.wrap_i64 (local.get $payload)))
+
+;; leaves the current frame, returning the block to go back to
+(func $return (result i32)
+  (global.set $len (global.get $fp))
+  (global.set $csp (i32.sub (global.get $csp) (i32.const 8)))
+  (global.set $fp (i32.load offset=4 (global.get $csp)))
+  (i32.load (global.get $csp)))
+
+;; errors
+
+(func $big
+  (call $unsupported (i32.const 0x120)))
+
+;; the operands don't go together, which is only fine for the VMs when one of
+;; them is something this module can't handle anyway
+(func $mismatch (param $lt i32) (param $l i64) (param $rt i32) (param $r i64)
+  (if (i32.eq (local.get $lt) (i32.const 7))
+    (then (call $unsupported (i32.wrap_i64 (local.get $l)))))
+  (if (i32.eq (local.get $rt) (i32.const 7))
+    (then (call $unsupported (i32.wrap_i64 (local.get $r)))))
+  (call $fail (i32.const 0)))
+
+(func $ints (param $lt i32) (param $l i64) (param $rt i32) (param $r i64)
+  (if (i32.eqz (i32.and (i32.eq (local.get $lt) (i32.const 3)) (i32.eq (local.get $rt) (i32.const 3))))
+    (then (call $mismatch (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)))))
+
+;; values
+
+(func $truthy (param $tag i32) (param $payload i64) (result i32)
+  (if (i32.le_u (local.get $tag) (i32.const 1))
+    (then (return (i32.const 0))))
+  (if (i32.eq (local.get $tag) (i32.const 2))
+    (then (return (i32.wrap_i64 (local.get $payload)))))
+  (if (i32.eq (local.get $tag) (i32.const 3))
+    (then (return (i64.ne (local.get $payload) (i64.const 0)))))
+  (if (i32.eq (local.get $tag) (i32.const 4))
+    (then (return (f64.ne (f64.reinterpret_i64 (local.get $payload)) (f64.const 0)))))
+  (if (i32.eq (local.get $tag) (i32.const 5))
+    (then (return (i32.ne (i32.load (i32.wrap_i64 (local.get $payload))) (i32.const 0)))))
+  (i32.const 1))
+
+;; a number as a float
+(func $float (param $tag i32) (param $payload i64) (result f64)
+  (if (i32.eq (local.get $tag) (i32.const 3))
+    (then (return (f64.convert_i64_s (local.get $payload)))))
+  (if (i32.eq (local.get $tag) (i32.const 4))
+    (then (return (f64.reinterpret_i64 (local.get $payload)))))
+  (call $mismatch (local.get $tag) (local.get $payload) (i32.const 3) (i64.const 0))
+  (f64.const 0))
+
+(func $both_ints (param $lt i32) (param $rt i32) (result i32)
+  (i32.and (i32.eq (local.get $lt) (i32.const 3)) (i32.eq (local.get $rt) (i32.const 3))))
+
+(func $either_string (param $lt i32) (param $rt i32) (result i32)
+  (i32.or (i32.eq (local.get $lt) (i32.const 5)) (i32.eq (local.get $rt) (i32.const 5))))
+
+(func $float_value (param $f f64) (result i32 i64)
+  (i32.const 4)
+  (i64.reinterpret_f64 (local.get $f)))
+
+(func $bool_value (param $b i32) (result i32 i64)
+  (i32.const 2)
+  (i64.extend_i32_u (local.get $b)))
+
+;; arithmetic, ints that no longer fit in 64 bits would become big ints
+
+(func $add (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (local $sum i64)
+  (if (call $both_ints (local.get $lt) (local.get $rt))
+    (then
+      (local.set $sum (i64.add (local.get $l) (local.get $r)))
+      (if (i64.lt_s
+            (i64.and
+              (i64.xor (local.get $l) (local.get $sum))
+              (i64.xor (local.get $r) (local.get $sum)))
+            (i64.const 0))
+        (then (call $big)))
+      (return (i32.const 3) (local.get $sum))))
+  (if (call $either_string (local.get $lt) (local.get $rt))
+    (then (return (call $concat (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)))))
+  (call $float_value
+    (f64.add
+      (call $float (local.get $lt) (local.get $l))
+      (call $float (local.get $rt) (local.get $r)))))
+
+(func $sub (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (local $difference i64)
+  (if (call $both_ints (local.get $lt) (local.get $rt))
+    (then
+      (local.set $difference (i64.sub (local.get $l) (local.get $r)))
+      (if (i64.lt_s
+            (i64.and
+              (i64.xor (local.get $l) (local.get $r))
+              (i64.xor (local.get $l) (local.get $difference)))
+            (i64.const 0))
+        (then (call $big)))
+      (return (i32.const 3) (local.get $difference))))
+  (call $float_value
+    (f64.sub
+      (call $float (local.get $lt) (local.get $l))
+      (call $float (local.get $rt) (local.get $r)))))
+
+;; the product, unless it overflows
+(func $checked_mul (param $l i64) (param $r i64) (result i64)
+  (local $product i64)
+  (local.set $product (i64.mul (local.get $l) (local.get $r)))
+  (if (i64.eq (local.get $l) (i64.const -1))
+    (then
+      (if (i64.eq (local.get $r) (i64.const -9223372036854775808))
+        (then (call $big))))
+    (else
+      (if (i64.ne (local.get $l) (i64.const 0))
+        (then
+          (if (i64.ne (i64.div_s (local.get $product) (local.get $l)) (local.get $r))
+            (then (call $big)))))))
+  (local.get $product))
+
+(func $mul (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (if (call $both_ints (local.get $lt) (local.get $rt))
+    (then (return (i32.const 3) (call $checked_mul (local.get $l) (local.get $r)))))
+  (call $float_value
+    (f64.mul
+      (call $float (local.get $lt) (local.get $l))
+      (call $float (local.get $rt) (local.get $r)))))
+
+;; `/` always divides exactly
+(func $div (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $float_value
+    (f64.div
+      (call $float (local.get $lt) (local.get $l))
+      (call $float (local.get $rt) (local.get $r)))))
+
+(func $floor_div (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (local $quotient i64)
+  (if (call $both_ints (local.get $lt) (local.get $rt))
+    (then
+      (if (i64.eqz (local.get $r))
+        (then (call $fail (i32.const 2))))
+      (if (i32.and
+            (i64.eq (local.get $l) (i64.const -9223372036854775808))
+            (i64.eq (local.get $r) (i64.const -1)))
+        (then (call $big)))
+      (local.set $quotient (i64.div_s (local.get $l) (local.get $r)))
+      ;; rounds down rather than towards zero
+      (if (i32.and
+            (i64.ne (i64.rem_s (local.get $l) (local.get $r)) (i64.const 0))
+            (i64.lt_s (i64.xor (local.get $l) (local.get $r)) (i64.const 0)))
+        (then (local.set $quotient (i64.sub (local.get $quotient) (i64.const 1)))))
+      (return (i32.const 3) (local.get $quotient))))
+  (call $float_value
+    (f64.floor
+      (f64.div
+        (call $float (local.get $lt) (local.get $l))
+        (call $float (local.get $rt) (local.get $r))))))
+
+;; the remainder of `~/`, which takes the sign of the divisor
+(func $mod (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (local $rem i64) (local $x f64) (local $y f64)
+  (if (call $both_ints (local.get $lt) (local.get $rt))
+    (then
+      (if (i64.eqz (local.get $r))
+        (then (call $fail (i32.const 2))))
+      (local.set $rem (i64.rem_s (local.get $l) (local.get $r)))
+      (if (i32.and
+            (i64.ne (local.get $rem) (i64.const 0))
+            (i64.lt_s (i64.xor (local.get $rem) (local.get $r)) (i64.const 0)))
+        (then (local.set $rem (i64.add (local.get $rem) (local.get $r)))))
+      (return (i32.const 3) (local.get $rem))))
+  (local.set $y (call $float (local.get $rt) (local.get $r)))
+  (local.set $x (call $host_fmod (call $float (local.get $lt) (local.get $l)) (local.get $y)))
+  (if (f64.eq (local.get $x) (f64.const 0))
+    (then (local.set $x (f64.copysign (f64.const 0) (local.get $y))))
+    (else
+      (if (i32.ne (f64.lt (local.get $x) (f64.const 0)) (f64.lt (local.get $y) (f64.const 0)))
+        (then (local.set $x (f64.add (local.get $x) (local.get $y)))))))
+  (call $float_value (local.get $x)))
+
+(func $pow (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (local $base i64) (local $exponent i64) (local $result i64)
+  (if (call $both_ints (local.get $lt) (local.get $rt))
+    (then
+      ;; a negative power of an integer is a fraction
+      (if (i64.lt_s (local.get $r) (i64.const 0))
+        (then
+          (return (call $float_value
+            (call $host_powf
+              (f64.convert_i64_s (local.get $l))
+              (f64.convert_i64_s (local.get $r)))))))
+      (if (i64.gt_s (local.get $r) (i64.const 0xffffffff))
+        (then (call $big)))
+      ;; squares only while there are bits of the exponent left, so it only
+      ;; overflows when the result does
+      (local.set $base (local.get $l))
+      (local.set $exponent (local.get $r))
+      (local.set $result (i64.const 1))
+      (if (i64.eqz (local.get $exponent))
+        (then (return (i32.const 3) (i64.const 1))))
+      (block $done
+        (loop $next
+          (br_if $done (i64.le_u (local.get $exponent) (i64.const 1)))
+          (if (i64.ne (i64.and (local.get $exponent) (i64.const 1)) (i64.const 0))
+            (then (local.set $result (call $checked_mul (local.get $result) (local.get $base)))))
+          (local.set $exponent (i64.shr_u (local.get $exponent) (i64.const 1)))
+          (local.set $base (call $checked_mul (local.get $base) (local.get $base)))
+          (br $next)))
+      (return (i32.const 3) (call $checked_mul (local.get $result) (local.get $base)))))
+  (call $float_value
+    (call $host_powf
+      (call $float (local.get $lt) (local.get $l))
+      (call $float (local.get $rt) (local.get $r)))))
+
+(func $neg (param $tag i32) (param $payload i64) (result i32 i64)
+  (if (i32.eq (local.get $tag) (i32.const 3))
+    (then
+      (if (i64.eq (local.get $payload) (i64.const -9223372036854775808))
+        (then (call $big)))
+      (return (i32.const 3) (i64.sub (i64.const 0) (local.get $payload)))))
+  (if (i32.eq (local.get $tag) (i32.const 4))
+    (then (return (call $float_value (f64.neg (f64.reinterpret_i64 (local.get $payload)))))))
+  (call $mismatch (local.get $tag) (local.get $payload) (i32.const 3) (i64.const 0))
+  (i32.const 0)
+  (i64.const 0))
+
+(func $not (param $tag i32) (param $payload i64) (result i32 i64)
+  (call $bool_value (i32.eqz (call $truthy (local.get $tag) (local.get $payload)))))
+
+;; bitwise operators only work on ints
+
+(func $bit_not (param $tag i32) (param $payload i64) (result i32 i64)
+  (call $ints (local.get $tag) (local.get $payload) (i32.const 3) (i64.const 0))
+  (i32.const 3)
+  (i64.xor (local.get $payload) (i64.const -1)))
+
+(func $bit_and (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $ints (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))
+  (i32.const 3)
+  (i64.and (local.get $l) (local.get $r)))
+
+(func $bit_or (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $ints (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))
+  (i32.const 3)
+  (i64.or (local.get $l) (local.get $r)))
+
+(func $bit_xor (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $ints (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))
+  (i32.const 3)
+  (i64.xor (local.get $l) (local.get $r)))
+
+(func $shl (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $ints (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))
+  (if (i64.lt_s (local.get $r) (i64.const 0))
+    (then (call $fail (i32.const 7))))
+  (if (i32.or
+        (i64.ge_s (local.get $r) (i64.const 64))
+        (i64.ne
+          (i64.shr_s (i64.shl (local.get $l) (local.get $r)) (local.get $r))
+          (local.get $l)))
+    (then (call $big)))
+  (i32.const 3)
+  (i64.shl (local.get $l) (local.get $r)))
+
+(func $shr (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $ints (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))
+  (if (i64.lt_s (local.get $r) (i64.const 0))
+    (then (call $fail (i32.const 7))))
+  ;; shifting out every bit leaves just the sign
+  (if (i64.gt_s (local.get $r) (i64.const 63))
+    (then (local.set $r (i64.const 63))))
+  (i32.const 3)
+  (i64.shr_s (local.get $l) (local.get $r)))
+
+;; comparisons
+
+;; -1, 0 or 1 as an int is less than, equal to or greater than a float, 2
+;; when the float is NaN. Exact, rather than rounding the int to a float
+(func $cmp_int_float (param $i i64) (param $f f64) (result i32)
+  (local $floor f64) (local $whole i64)
+  (if (f64.ne (local.get $f) (local.get $f))
+    (then (return (i32.const 2))))
+  (if (f64.ge (local.get $f) (f64.const 9223372036854775808))
+    (then (return (i32.const -1))))
+  (if (f64.lt (local.get $f) (f64.const -9223372036854775808))
+    (then (return (i32.const 1))))
+  (local.set $floor (f64.floor (local.get $f)))
+  (local.set $whole (i64.trunc_f64_s (local.get $floor)))
+  (if (i64.lt_s (local.get $i) (local.get $whole))
+    (then (return (i32.const -1))))
+  (if (i64.gt_s (local.get $i) (local.get $whole))
+    (then (return (i32.const 1))))
+  (if (f64.gt (local.get $f) (local.get $floor))
+    (then (return (i32.const -1))))
+  (i32.const 0))
+
+(func $cmp_strings (param $a i32) (param $b i32) (result i32)
+  (local $alen i32) (local $blen i32) (local $i i32) (local $x i32) (local $y i32)
+  (local.set $alen (i32.load (local.get $a)))
+  (local.set $blen (i32.load (local.get $b)))
+  (block $done
+    (loop $next
+      (br_if $done (i32.ge_u (local.get $i) (local.get $alen)))
+      (br_if $done (i32.ge_u (local.get $i) (local.get $blen)))
+      (local.set $x (i32.load8_u offset=4 (i32.add (local.get $a) (local.get $i))))
+      (local.set $y (i32.load8_u offset=4 (i32.add (local.get $b) (local.get $i))))
+      (if (i32.lt_u (local.get $x) (local.get $y))
+        (then (return (i32.const -1))))
+      (if (i32.gt_u (local.get $x) (local.get $y))
+        (then (return (i32.const 1))))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br $next)))
+  (if (i32.lt_u (local.get $alen) (local.get $blen))
+    (then (return (i32.const -1))))
+  (i32.gt_u (local.get $alen) (local.get $blen)))
+
+;; orders two values for `<`, `>`, `<=` and `>=`, like $cmp_int_float
+(func $order (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32)
+  (local $x f64) (local $y f64) (local $ordering i32)
+  (if (call $both_ints (local.get $lt) (local.get $rt))
+    (then
+      (return (i32.sub
+        (i64.gt_s (local.get $l) (local.get $r))
+        (i64.lt_s (local.get $l) (local.get $r))))))
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 4)) (i32.eq (local.get $rt) (i32.const 4)))
+    (then
+      (local.set $x (f64.reinterpret_i64 (local.get $l)))
+      (local.set $y (f64.reinterpret_i64 (local.get $r)))
+      (if (i32.or (f64.ne (local.get $x) (local.get $x)) (f64.ne (local.get $y) (local.get $y)))
+        (then (return (i32.const 2))))
+      (return (i32.sub
+        (f64.gt (local.get $x) (local.get $y))
+        (f64.lt (local.get $x) (local.get $y))))))
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 3)) (i32.eq (local.get $rt) (i32.const 4)))
+    (then (return (call $cmp_int_float (local.get $l) (f64.reinterpret_i64 (local.get $r))))))
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 4)) (i32.eq (local.get $rt) (i32.const 3)))
+    (then
+      (local.set $ordering (call $cmp_int_float (local.get $r) (f64.reinterpret_i64 (local.get $l))))
+      (if (i32.eq (local.get $ordering) (i32.const 2))
+        (then (return (i32.const 2))))
+      (return (i32.sub (i32.const 0) (local.get $ordering)))))
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 5)) (i32.eq (local.get $rt) (i32.const 5)))
+    (then
+      (return (call $cmp_strings (i32.wrap_i64 (local.get $l)) (i32.wrap_i64 (local.get $r))))))
+  (call $mismatch (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))
+  (i32.const 2))
+
+(func $lt (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $bool_value
+    (i32.eq (call $order (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)) (i32.const -1))))
+
+(func $gt (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $bool_value
+    (i32.eq (call $order (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)) (i32.const 1))))
+
+(func $lte (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (local $ordering i32)
+  (local.set $ordering (call $order (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)))
+  (call $bool_value
+    (i32.or (i32.eq (local.get $ordering) (i32.const -1)) (i32.eqz (local.get $ordering)))))
+
+(func $gte (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (local $ordering i32)
+  (local.set $ordering (call $order (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)))
+  (call $bool_value
+    (i32.or (i32.eq (local.get $ordering) (i32.const 1)) (i32.eqz (local.get $ordering)))))
+
+;; an int equals a float with no fractional part of the same value
+(func $int_equals_float (param $i i64) (param $f f64) (result i32)
+  (if (i32.or
+        (f64.ne (f64.floor (local.get $f)) (local.get $f))
+        (i32.or
+          (f64.ge (local.get $f) (f64.const 9223372036854775808))
+          (f64.lt (local.get $f) (f64.const -9223372036854775808))))
+    (then (return (i32.const 0))))
+  (i64.eq (i64.trunc_f64_s (local.get $f)) (local.get $i)))
+
+(func $equal (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32)
+  (local $x f64) (local $y f64)
+  (if (i32.eq (local.get $lt) (local.get $rt))
+    (then
+      (if (i32.le_u (local.get $lt) (i32.const 1))
+        (then (return (i32.const 1))))
+      (if (i32.eq (local.get $lt) (i32.const 4))
+        (then
+          (local.set $x (f64.reinterpret_i64 (local.get $l)))
+          (local.set $y (f64.reinterpret_i64 (local.get $r)))
+          ;; NaN equals itself, so values can be looked up by
+          (return (i32.or
+            (f64.eq (local.get $x) (local.get $y))
+            (i32.and (f64.ne (local.get $x) (local.get $x)) (f64.ne (local.get $y) (local.get $y)))))))
+      (if (i32.eq (local.get $lt) (i32.const 5))
+        (then
+          (return (i32.eqz
+            (call $cmp_strings (i32.wrap_i64 (local.get $l)) (i32.wrap_i64 (local.get $r)))))))
+      (if (i32.eq (local.get $lt) (i32.const 7))
+        (then (call $unsupported (i32.wrap_i64 (local.get $l)))))
+      (return (i64.eq (local.get $l) (local.get $r)))))
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 3)) (i32.eq (local.get $rt) (i32.const 4)))
+    (then (return (call $int_equals_float (local.get $l) (f64.reinterpret_i64 (local.get $r))))))
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 4)) (i32.eq (local.get $rt) (i32.const 3)))
+    (then (return (call $int_equals_float (local.get $r) (f64.reinterpret_i64 (local.get $l))))))
+  ;; a big int may equal a float
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 7)) (i32.eq (local.get $rt) (i32.const 4)))
+    (then (call $unsupported (i32.wrap_i64 (local.get $l)))))
+  (if (i32.and (i32.eq (local.get $lt) (i32.const 4)) (i32.eq (local.get $rt) (i32.const 7)))
+    (then (call $unsupported (i32.wrap_i64 (local.get $r)))))
+  (i32.const 0))
+
+(func $eq (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $bool_value (call $equal (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))))
+
+(func $neq (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $bool_value
+    (i32.eqz (call $equal (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)))))
+
+;; strings
+
+;; makes sure memory goes up to `n` bytes past the end of the heap
+(func $reserve (param $n i32)
+  (local $end i32)
+  (local.set $end (i32.add (global.get $heap) (local.get $n)))
+  (if (i32.gt_u (local.get $end) (i32.shl (memory.size) (i32.const 16)))
+    (then
+      (if (i32.eq
+            (memory.grow
+              (i32.sub
+                (i32.shr_u (i32.add (local.get $end) (i32.const 0xffff)) (i32.const 16))
+                (memory.size)))
+            (i32.const -1))
+        (then (call $fail (i32.const 8)))))))
+
+;; starts a new string at the end of the heap, $append adds to it until
+;; $finish
+(func $begin
+  (call $reserve (i32.const 4))
+  (global.set $building (global.get $heap))
+  (global.set $heap (i32.add (global.get $heap) (i32.const 4))))
+
+(func $append (param $at i32) (param $len i32)
+  (call $reserve (local.get $len))
+  (memory.copy (global.get $heap) (local.get $at) (local.get $len))
+  (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+
+(func $finish (result i32 i64)
+  (i32.store
+    (global.get $building)
+    (i32.sub (i32.sub (global.get $heap) (global.get $building)) (i32.const 4)))
+  (i32.const 5)
+  (i64.extend_i32_u (global.get $building)))
+
+(func $append_int (param $i i64)
+  (local $n i64) (local $at i32)
+  (local.set $n (local.get $i))
+  (if (i64.lt_s (local.get $i) (i64.const 0))
+    (then (local.set $n (i64.sub (i64.const 0) (local.get $i)))))
+  (local.set $at (i32.const 0x1020))
+  (loop $digits
+    (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+    (i32.store8
+      (local.get $at)
+      (i32.wrap_i64 (i64.add (i64.rem_u (local.get $n) (i64.const 10)) (i64.const 48))))
+    (local.set $n (i64.div_u (local.get $n) (i64.const 10)))
+    (br_if $digits (i64.ne (local.get $n) (i64.const 0))))
+  (if (i64.lt_s (local.get $i) (i64.const 0))
+    (then
+      (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+      (i32.store8 (local.get $at) (i32.const 45))))
+  (call $append (local.get $at) (i32.sub (i32.const 0x1020) (local.get $at))))
+
+;; adds a value to the string being made, the way the VM shows it
+(func $append_value (param $tag i32) (param $payload i64)
+  (local $at i32)
+  (if (i32.eqz (local.get $tag))
+    (then (return (call $append (i32.const 0x100) (i32.const 4)))))
+  (if (i32.eq (local.get $tag) (i32.const 1))
+    (then (return (call $append (i32.const 0x104) (i32.const 4)))))
+  (if (i32.eq (local.get $tag) (i32.const 2))
+    (then
+      (if (i64.eqz (local.get $payload))
+        (then (return (call $append (i32.const 0x10c) (i32.const 5)))))
+      (return (call $append (i32.const 0x108) (i32.const 4)))))
+  (if (i32.eq (local.get $tag) (i32.const 3))
+    (then (return (call $append_int (local.get $payload)))))
+  (if (i32.eq (local.get $tag) (i32.const 4))
+    (then
+      (call $reserve (i32.const 64))
+      (global.set $heap
+        (i32.add
+          (global.get $heap)
+          (call $host_show_float (f64.reinterpret_i64 (local.get $payload)) (global.get $heap))))
+      (return)))
+  (if (i32.eq (local.get $tag) (i32.const 5))
+    (then
+      (local.set $at (i32.wrap_i64 (local.get $payload)))
+      (return (call $append (i32.add (local.get $at) (i32.const 4)) (i32.load (local.get $at))))))
+  (if (i32.eq (local.get $tag) (i32.const 6))
+    (then
+      (call $append (i32.const 0x111) (i32.const 13))
+      (call $append_int (i64.shr_u (local.get $payload) (i64.const 32)))
+      (return (call $append (i32.const 0x11e) (i32.const 1)))))
+  (call $unsupported (i32.wrap_i64 (local.get $payload))))
+
+(func $concat (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (call $begin)
+  (call $append_value (local.get $lt) (local.get $l))
+  (call $append_value (local.get $rt) (local.get $r))
+  (call $finish))
+
+;; `..`, where at least one side has to be a string
+(func $join (param $lt i32) (param $l i64) (param $rt i32) (param $r i64) (result i32 i64)
+  (if (i32.eqz (call $either_string (local.get $lt) (local.get $rt)))
+    (then (call $mismatch (local.get $lt) (local.get $l) (local.get $rt) (local.get $r))))
+  (call $concat (local.get $lt) (local.get $l) (local.get $rt) (local.get $r)))
+
+;; pops `n` values into one string
+(func $join_many (param $n i32)
+  (call $begin)
+  (block $done
+    (loop $next
+      (br_if $done (i32.eqz (local.get $n)))
+      (call $append_value (call $pop))
+      (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+      (br $next)))
+  (call $push (call $finish)))
+
+;; `item[index]`, the character at `index` of a string. The index is below
+;; the string on the stack
+(func $index (param $it i32) (param $i i64) (param $st i32) (param $s i64) (result i32 i64)
+  (local $string i32) (local $len i32) (local $at i32) (local $start i32) (local $n i64)
+  (if (i32.ne (local.get $st) (i32.const 5))
+    (then (call $mismatch (local.get $st) (local.get $s) (local.get $it) (local.get $i))))
+  (if (i32.ne (local.get $it) (i32.const 3))
+    (then (call $mismatch (local.get $it) (local.get $i) (i32.const 3) (i64.const 0))))
+  (if (i64.lt_s (local.get $i) (i64.const 0))
+    (then (call $fail (i32.const 3))))
+  (local.set $string (i32.add (i32.wrap_i64 (local.get $s)) (i32.const 4)))
+  (local.set $len (i32.load (i32.wrap_i64 (local.get $s))))
+  ;; counts the bytes that start a character until the one after the index
+  (local.set $start (i32.const -1))
+  (local.set $n (i64.const -1))
+  (block $done
+    (loop $next
+      (if (i32.ge_u (local.get $at) (local.get $len))
+        (then
+          (br_if $done (i64.eq (local.get $n) (local.get $i)))
+          (call $fail (i32.const 3))))
+      (if (i32.ne
+            (i32.and (i32.load8_u (i32.add (local.get $string) (local.get $at))) (i32.const 0xc0))
+            (i32.const 0x80))
+        (then
+          (br_if $done (i64.eq (local.get $n) (local.get $i)))
+          (local.set $n (i64.add (local.get $n) (i64.const 1)))
+          (local.set $start (local.get $at))))
+      (local.set $at (i32.add (local.get $at) (i32.const 1)))
+      (br $next)))
+  (call $begin)
+  (call $append
+    (i32.add (local.get $string) (local.get $start))
+    (i32.sub (local.get $at) (local.get $start)))
+  (call $finish))
+
+;; native functions
+
+(func $stdout (local $tag i32) (local $payload i64)
+  (call $pop)
+  (local.set $payload)
+  (local.set $tag)
+  (if (i32.ne (local.get $tag) (i32.const 5))
+    (then
+      (call $begin)
+      (call $append_value (local.get $tag) (local.get $payload))
+      (call $finish)
+      (local.set $payload)
+      (local.set $tag)))
+  (call $host_stdout
+    (i32.add (i32.wrap_i64 (local.get $payload)) (i32.const 4))
+    (i32.load (i32.wrap_i64 (local.get $payload))))
+  (call $push (i32.const 1) (i64.const 0)))
+
+(func $time
+  (call $push (i32.const 3) (call $host_time)))
+
+(func $flushout
+  (call $push (i32.const 1) (i64.const 0)))
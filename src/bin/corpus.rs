@@ -0,0 +1,222 @@
+// Runs every script in a directory on the stack VM, on the stack VM without
+// superinstructions, on the register VM, on both VMs collecting garbage on
+// every allocation and as a standalone binary, checking that each prints
+// exactly what the `.out` file next to the script expects.
+// With the `wasm` feature it also lowers each script to WebAssembly and runs
+// that in-process. A script that uses something wasm can't do yet says so on
+// a line of its own, `// wasm: no big integers`, and is skipped there.
+// The scripts run on the `main` binary next to this one, which has to be
+// built with the same features, and the corpus stops if it wasn't
+//
+//     cargo build && cargo run --bin corpus -- corpus
+//     cargo build --features jit,wasm && cargo run --features jit,wasm --bin corpus -- corpus
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command, Output},
+};
+
+#[cfg(feature = "wasm")]
+use glass::backend::wasm::host::{self, Trap};
+
+fn run(main: &Path, script: &Path, flags: &[&str]) -> Output {
+    let output = Command::new(main)
+        .args(flags)
+        .arg(script)
+        .output()
+        .unwrap_or_else(|e| panic!("could not run {}: {}", main.display(), e));
+
+    // the disassembly written next to the script
+    let _ = fs::remove_file(format!("{}.out", script.display()));
+    output
+}
+
+// builds the script into a standalone binary and runs that from somewhere
+// without any scripts around
+fn standalone(main: &Path, script: &Path) -> Output {
+    let dir = env::temp_dir();
+    let exe = dir.join(format!("glass-corpus-{}", process::id()));
+    let build = run(
+        main,
+        script,
+        &["build", "--standalone", "-o", exe.to_str().unwrap()],
+    );
+    if !build.status.success() {
+        return build;
+    }
+
+    let output = Command::new(&exe)
+        .current_dir(&dir)
+        .output()
+        .unwrap_or_else(|e| panic!("could not run {}: {}", exe.display(), e));
+    let _ = fs::remove_file(&exe);
+    output
+}
+
+// what printing the same thing means for each of the ways to run a script:
+// what it printed, and why it stopped early if it did
+type Run<'a> = (&'a str, Vec<u8>, Result<(), String>);
+
+// what the script says wasm can't do yet, from its `// wasm: no ...` line
+#[cfg(feature = "wasm")]
+fn unsupported(script: &Path) -> Option<String> {
+    let code = fs::read_to_string(script).ok()?;
+    code.lines()
+        .find_map(|line| line.trim().strip_prefix("// wasm: no "))
+        .map(str::to_owned)
+}
+
+// lowers the script to a module and runs it. A script using what it says the
+// module can't do only has to print the same thing up to there, and is
+// skipped. Running into anything else it can't do, or running fine while
+// saying it can't, fails
+#[cfg(feature = "wasm")]
+fn wasm(main: &Path, script: &Path, expected: &[u8]) -> (Option<Run<'static>>, Option<String>) {
+    let module = env::temp_dir().join(format!("glass-corpus-{}.wasm", process::id()));
+    let build = run(
+        main,
+        script,
+        &["build", "--wasm", "-o", module.to_str().unwrap()],
+    );
+    if !build.status.success() {
+        let error = String::from_utf8_lossy(&build.stderr).into_owned();
+        return (Some(("wasm", build.stdout, Err(error))), None);
+    }
+
+    let wasm = fs::read(&module).unwrap();
+    let _ = fs::remove_file(&module);
+    let unsupported = unsupported(script);
+    match host::run(&wasm) {
+        (stdout, Err(Trap::Unsupported(what)))
+            if unsupported.as_ref() == Some(&what) && expected.starts_with(&stdout) =>
+        {
+            (None, Some(what))
+        }
+        (stdout, Ok(())) if unsupported.is_some() => {
+            let error = format!(
+                "ran in wasm, but the script says it has no {}",
+                unsupported.unwrap()
+            );
+            (Some(("wasm", stdout, Err(error))), None)
+        }
+        (stdout, result) => {
+            let result = result.map_err(|trap| trap.to_string());
+            (Some(("wasm", stdout, result)), None)
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+fn wasm(_: &Path, _: &Path, _: &[u8]) -> (Option<Run<'static>>, Option<String>) {
+    (None, None)
+}
+
+fn main() {
+    let dir = env::args().nth(1).unwrap_or_else(|| "corpus".to_string());
+    let main = env::current_exe()
+        .unwrap()
+        .with_file_name(format!("main{}", env::consts::EXE_SUFFIX));
+
+    let version = Command::new(&main)
+        .arg("--version")
+        .output()
+        .unwrap_or_else(|e| panic!("could not run {}: {}", main.display(), e));
+    let version = String::from_utf8_lossy(&version.stdout);
+    if version.trim_end() != glass::version() {
+        eprintln!(
+            "{} is `{}`, not `{}`, build it with the same features as the corpus",
+            main.display(),
+            version.trim_end(),
+            glass::version()
+        );
+        process::exit(1);
+    }
+
+    let mut scripts: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("could not read {}: {}", dir, e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rv"))
+        .collect();
+    scripts.sort();
+
+    let mut failed = 0;
+    let mut skipped = 0;
+    for script in &scripts {
+        let golden = script.with_extension("out");
+        let Ok(expected) = fs::read(&golden) else {
+            failed += 1;
+            println!("FAIL {} (no {})", script.display(), golden.display());
+            continue;
+        };
+
+        let (wasm, unsupported) = wasm(&main, script, &expected);
+        let runs: Vec<Run> = [
+            ("stack", run(&main, script, &[])),
+            ("unfused", run(&main, script, &["--no-fuse"])),
+            ("register", run(&main, script, &["--register"])),
+            ("gc stress", run(&main, script, &["--gc-stress"])),
+            (
+                "register gc stress",
+                run(&main, script, &["--register", "--gc-stress"]),
+            ),
+            ("standalone", standalone(&main, script)),
+        ]
+        .into_iter()
+        .map(|(name, output)| {
+            let result = match output.status.success() {
+                true => Ok(()),
+                false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+            };
+            (name, output.stdout, result)
+        })
+        .chain(wasm)
+        .collect();
+
+        if let Some((name, output, result)) = runs
+            .iter()
+            .find(|(_, output, result)| *output != expected || result.is_err())
+        {
+            failed += 1;
+            println!("FAIL {} ({})", script.display(), name);
+            if let Err(error) = result {
+                println!("  {}", error.trim_end());
+            }
+            if *output == expected {
+                continue;
+            }
+
+            let expected = String::from_utf8_lossy(&expected);
+            let output = String::from_utf8_lossy(output);
+            let line = expected
+                .lines()
+                .zip(output.lines())
+                .position(|(a, b)| a != b)
+                .unwrap_or_else(|| expected.lines().count().min(output.lines().count()));
+            println!("  line {}", line + 1);
+            println!(
+                "  expected:   {:?}",
+                expected.lines().nth(line).unwrap_or("<end>")
+            );
+            println!(
+                "  {:<11} {:?}",
+                format!("{}:", name),
+                output.lines().nth(line).unwrap_or("<end>")
+            );
+        } else if let Some(what) = unsupported {
+            skipped += 1;
+            println!("ok   {} (skipped in wasm, no {})", script.display(), what);
+        } else {
+            println!("ok   {}", script.display());
+        }
+    }
+
+    print!("{} passed, {} failed", scripts.len() - failed, failed);
+    if cfg!(feature = "wasm") {
+        print!(", {} skipped in wasm", skipped);
+    }
+    println!();
+    if failed > 0 {
+        process::exit(1);
+    }
+}
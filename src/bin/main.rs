@@ -1,44 +1,170 @@
-use std::fs::{read_to_string, File};
-use std::io::Write;
-use std::path;
+use std::{env, fs, path, process};
 
-use glass::backend::instruction::Instr;
+use glass::backend::image;
+use glass::backend::instruction::{Instr, Program};
+use glass::backend::module::{Loader, ModuleError};
+use glass::backend::register;
 use glass::backend::vm::VM;
 
-use glass::frontend::parser;
+fn main() {
+    // let s = std::time::Instant::now();
 
-fn write_program(program: &[Instr], path: &path::Path) {
-    let mut file = File::create(path).unwrap();
-    write!(file, "ln#\topcode    \toffset/value\n").unwrap();
-    write!(file, "-------------------------\n").unwrap();
-    for (i, instruction) in program.iter().enumerate() {
-        write!(file, "{}:\t{:?}\n", i, instruction).unwrap();
+    // a binary made by `glass build --standalone` runs the program it carries,
+    // one that can't read itself carries none as far as it can tell
+    if let Ok(Some(bytes)) = env::current_exe().and_then(|exe| image::embedded(&exe)) {
+        let program = image::decode(&bytes).expect("Embedded program is corrupt");
+        run(program, false);
+        return;
     }
-    write!(file, "-------------------------\n").unwrap();
-}
 
-fn main() {
-    // let s = std::time::Instant::now();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--version") {
+        println!("{}", glass::version());
+        return;
+    }
+    if args.first().map(String::as_str) == Some("build") {
+        args.remove(0);
+        build(args);
+        return;
+    }
 
-    let code = read_to_string(path::Path::new("src/bin/test.rv")).unwrap();
-    let ast = parser::parse_code(&code).unwrap();
-    let (program, prog_start) = Instr::compile(ast);
+    let mut flag = |name: &str| match args.iter().position(|arg| arg == name) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    // `--register` runs the program on the register VM instead
+    let register = flag("--register");
+    // `--profile` counts what the stack VM dispatched, `--no-fuse` leaves
+    // out superinstructions to compare against
+    let profile = flag("--profile");
+    let no_fuse = flag("--no-fuse");
+    // `--gc-stress` collects garbage on every allocation
+    let gc_stress = flag("--gc-stress");
 
-    write_program(
-        &program[prog_start..],
-        // &program,
-        path::Path::new("src/bin/test.rv.out"),
-    );
+    let file = args
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| "src/bin/test.rv".to_string());
+    let path = path::Path::new(&file);
+    let out = format!("{}.out", file);
 
-    let mut vm = VM::new();
-    vm.program = program;
+    let mut loader = Loader::from_env();
+    loader.fuse = !no_fuse;
+
+    if register {
+        let (program, prog_start) = compiled(register::Instr::compile_file(path, &mut loader));
+        fs::write(out, program.disassemble(prog_start)).unwrap();
+
+        let mut vm = register::vm::VM::new();
+        vm.heap.stress = gc_stress;
+        vm.load(program);
+        vm.run();
+        return;
+    }
+
+    let (program, prog_start) = compiled(Instr::compile_file(path, &mut loader));
+    fs::write(out, program.disassemble(prog_start)).unwrap();
+
+    if profile {
+        let mut vm = VM::new();
+        vm.heap.stress = gc_stress;
+        vm.load(program);
+        eprint!("{}", vm.profile());
+        return;
+    }
 
     // println!("Took {:?}μs", s.elapsed().as_micros());
 
-    vm.run();
+    run(program, gc_stress);
     // vm.debug();
     // println!("Took {:?}ms", s.elapsed().as_millis());
 
     // println!("{:?}", vm.heap.0);
     // println!("{:?}", vm.scopes[0].0);
 }
+
+fn run(program: Program, gc_stress: bool) {
+    let mut vm = VM::new();
+    vm.heap.stress = gc_stress;
+    vm.load(program);
+    vm.run();
+}
+
+/// `glass build --standalone foo.rv -o foo` writes a copy of this binary that
+/// carries foo.rv compiled, so it runs without any `.rv` files around.
+/// `glass build --wasm foo.rv -o foo.wasm` lowers it to a WebAssembly module
+/// instead, when built with the `wasm` feature.
+fn build(mut args: Vec<String>) {
+    let usage = || -> ! {
+        eprintln!("usage: glass build (--standalone | --wasm) <file.rv> -o <out>");
+        process::exit(1);
+    };
+
+    let Some(i) = args
+        .iter()
+        .position(|arg| arg == "--standalone" || arg == "--wasm")
+    else {
+        usage()
+    };
+    let wasm = args.remove(i) == "--wasm";
+    let Some(i) = args.iter().position(|arg| arg == "-o") else {
+        usage()
+    };
+    if i + 1 >= args.len() {
+        usage()
+    }
+    let out = args.remove(i + 1);
+    args.remove(i);
+    let [file] = args.as_slice() else { usage() };
+
+    let (program, start) = compiled(Instr::compile_file(
+        path::Path::new(file),
+        &mut Loader::from_env(),
+    ));
+    let written = if wasm {
+        fs::write(&out, lower(&program, start, file))
+    } else {
+        // the runner is a copy of this binary
+        let exe = env::current_exe()
+            .and_then(|exe| fs::File::open(&exe).map(|_| exe))
+            .unwrap_or_else(|e| {
+                eprintln!("Could not read the glass binary to copy: {}", e);
+                process::exit(1);
+            });
+        image::embed(&exe, &image::encode(&program), path::Path::new(&out))
+    };
+    written.unwrap_or_else(|e| {
+        eprintln!("Could not write {}: {}", out, e);
+        process::exit(1);
+    });
+}
+
+/// The module, warning about anything `file` uses that it will trap on.
+#[cfg(feature = "wasm")]
+fn lower(program: &Program, start: usize, file: &str) -> Vec<u8> {
+    let unsupported = glass::backend::wasm::unsupported(program, start);
+    if !unsupported.is_empty() {
+        eprintln!(
+            "warning: {} uses {}, which wasm doesn't support yet. The module traps when it gets to them",
+            file,
+            unsupported.join(", ")
+        );
+    }
+    glass::backend::wasm::lower(program)
+}
+
+#[cfg(not(feature = "wasm"))]
+fn lower(_: &Program, _: usize, _: &str) -> Vec<u8> {
+    eprintln!("glass was built without the `wasm` feature");
+    process::exit(1);
+}
+
+fn compiled<T>(result: Result<T, ModuleError>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
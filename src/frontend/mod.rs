@@ -1,18 +1,38 @@
+use std::fmt;
+
+use num_bigint::BigInt;
+
+pub mod resolver;
 pub mod second;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
-    // Comment(String),
+    Int(i64),
+    BigInt(BigInt),
     Number(f64),
     String(String),
     FormatString(Vec<Expr>),
+    // an interpolation with a format spec, `{value:spec}`
+    Formatted(Box<Expr>, FormatSpec),
     Bool(bool),
     None,
 
-    Identifier(String),
+    Identifier(String, Pos),
 
     Declaration(String, Box<Expr>),
-    Assignment(Box<Expr>, Box<Expr>),
+    Const(String, Box<Expr>),
+    Import {
+        path: String,
+        alias: Option<String>,
+        pos: Pos,
+    },
+    FromImport {
+        path: String,
+        names: Vec<String>,
+        pos: Pos,
+    },
+    // the position is the assignee's
+    Assignment(Box<Expr>, Box<Expr>, Pos),
 
     Index {
         item: Box<Expr>,
@@ -27,23 +47,42 @@ pub enum Expr {
     // Walrus(String, Box<Expr>),
     Function {
         name: String,
-        args: Vec<String>,
+        args: Vec<(String, Pos)>,
         body: Vec<Expr>,
+        // the `///` comments right above it
+        doc: Option<String>,
     },
-    Lambda(Vec<String>, Vec<Expr>),
+    Lambda(Vec<(String, Pos)>, Vec<Expr>),
+    Enum {
+        name: String,
+        variants: Vec<(String, Vec<String>)>,
+    },
+    Member(Box<Expr>, String),
+    // `item?.name`, none when `item` is none
+    OptionalMember(Box<Expr>, String),
     Call(Box<Expr>, Vec<Expr>),
     NativeCall(String, Vec<Expr>),
 
     Join(Box<Expr>, Box<Expr>),
 
     Op(Op, Box<Expr>, Box<Expr>),
+    // `a < b < c`, three or more operands of comparisons that chain
+    Chain(Box<Expr>, Vec<(Op, Expr)>),
     // Error(String),
     If {
         condition: Box<Expr>,
         then: Vec<Expr>,
         otherwise: Vec<Expr>,
     },
-    // While(Box<Expr>, Box<Expr>),
+    Match {
+        value: Box<Expr>,
+        arms: Vec<(Pattern, Vec<Expr>)>,
+    },
+    While {
+        condition: Box<Expr>,
+        body: Vec<Expr>,
+    },
+    Block(Vec<Expr>),
     Return(Box<Expr>),
     // Break,
     // Continue,
@@ -55,6 +94,7 @@ pub enum Op {
     Sub,
     Mul,
     Div,
+    FloorDiv,
     Mod,
     Pow,
     Eq,
@@ -65,85 +105,390 @@ pub enum Op {
     Gte,
     And,
     Or,
+    Coalesce,
     Not,
     Neg,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Wildcard,
+    Binding(String),
+    Literal(Expr),
+    Variant {
+        enum_name: String,
+        name: String,
+        fields: Vec<Pattern>,
+        pos: Pos,
+    },
+}
+
+/// The name `import "path" as alias` binds, the file's stem when there is no
+/// alias.
+pub fn import_name(path: &str, alias: Option<&str>) -> String {
+    match alias {
+        Some(alias) => alias.to_owned(),
+        None => std::path::Path::new(path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+/// `[[fill]align][+][#][0][width][.precision][kind]`, the part after the
+/// colon of an f-string interpolation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatSpec {
+    pub fill: char,
+    pub align: Option<Align>,
+    pub sign: bool,
+    pub alternate: bool,
+    pub zero: bool,
+    pub width: usize,
+    pub precision: Option<usize>,
+    pub kind: FormatKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatKind {
+    Display,
+    Debug,
+    LowerHex,
+    UpperHex,
+    Binary,
+    Octal,
+}
+
+impl Default for FormatSpec {
+    fn default() -> Self {
+        FormatSpec {
+            fill: ' ',
+            align: None,
+            sign: false,
+            alternate: false,
+            zero: false,
+            width: 0,
+            precision: None,
+            kind: FormatKind::Display,
+        }
+    }
+}
+
+impl fmt::Display for FormatSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(align) = self.align {
+            let align = match align {
+                Align::Left => '<',
+                Align::Right => '>',
+                Align::Center => '^',
+            };
+            write!(f, "{}{}", self.fill, align)?;
+        }
+        if self.sign {
+            write!(f, "+")?;
+        }
+        if self.alternate {
+            write!(f, "#")?;
+        }
+        if self.zero {
+            write!(f, "0")?;
+        }
+        if self.width > 0 {
+            write!(f, "{}", self.width)?;
+        }
+        if let Some(precision) = self.precision {
+            write!(f, ".{}", precision)?;
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl fmt::Display for FormatKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            FormatKind::Display => "",
+            FormatKind::Debug => "?",
+            FormatKind::LowerHex => "x",
+            FormatKind::UpperHex => "X",
+            FormatKind::Binary => "b",
+            FormatKind::Octal => "o",
+        };
+        write!(f, "{}", kind)
+    }
 }
 
 pub type AST = Vec<Node>;
+/// byte offset into the source
+pub type Pos = usize;
 pub type Node = Expr;
 
-fn fix_str(s: Vec<char>) -> String {
-    let mut f = Vec::new();
-    let mut rep = false;
-    for c in s {
-        if c == '\\' {
-            rep = true;
-        } else {
-            if rep {
-                f.push(match c {
-                    'n' => '\n',
-                    'r' => '\r',
-                    't' => '\t',
-                    '\\' => '\\',
-                    _ => c,
-                });
-                rep = false;
-            } else {
-                f.push(c);
+/// A piece of a string literal, kept apart until indentation is stripped so
+/// that escaped whitespace is never mistaken for indentation.
+enum Piece {
+    Char(char),
+    Escaped(char),
+    Expr(Expr),
+}
+
+fn text(pieces: Vec<Piece>) -> String {
+    pieces
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Char(c) | Piece::Escaped(c) => Some(c),
+            Piece::Expr(_) => None,
+        })
+        .collect()
+}
+
+/// Groups the characters between interpolations of a format string.
+fn parts(pieces: Vec<Piece>) -> Vec<Expr> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Char(c) | Piece::Escaped(c) => current.push(c),
+            Piece::Expr(e) => {
+                if !current.is_empty() {
+                    parts.push(Expr::String(std::mem::take(&mut current)));
+                }
+                parts.push(e);
             }
         }
     }
+    if !current.is_empty() {
+        parts.push(Expr::String(current));
+    }
+    parts
+}
 
-    f.into_iter().collect()
+/// Strips the indentation shared by every line of a `"""` string, along with
+/// the line break after the opening quotes and the line holding the closing
+/// ones when they are blank.
+fn dedent(pieces: Vec<Piece>) -> Vec<Piece> {
+    let mut lines = vec![vec![]];
+    for piece in pieces {
+        match piece {
+            Piece::Char('\n') => lines.push(vec![]),
+            piece => lines.last_mut().unwrap().push(piece),
+        }
+    }
+
+    let indentation = |piece: &Piece| matches!(piece, Piece::Char(' ' | '\t'));
+    let blank = |line: &Vec<Piece>| {
+        line.iter()
+            .all(|piece| indentation(piece) || matches!(piece, Piece::Char('\r')))
+    };
+
+    if lines.len() > 1 && blank(&lines[0]) {
+        lines.remove(0);
+    }
+    if lines.len() > 1 && blank(&lines[lines.len() - 1]) {
+        lines.pop();
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|line| !blank(line))
+        .map(|line| line.iter().take_while(|piece| indentation(piece)).count())
+        .min()
+        .unwrap_or(0);
+
+    let mut out = vec![];
+    for (i, line) in lines.into_iter().enumerate() {
+        if i > 0 {
+            out.push(Piece::Char('\n'));
+        }
+        out.extend(line.into_iter().skip(indent));
+    }
+    out
+}
+
+/// Builds a comparison from its operands, only chaining when there are more
+/// than two.
+fn chain(first: Expr, mut rest: Vec<(Op, Expr)>) -> Expr {
+    match rest.len() {
+        0 => first,
+        1 => {
+            let (op, rhs) = rest.pop().unwrap();
+            Expr::Op(op, Box::new(first), Box::new(rhs))
+        }
+        _ => Expr::Chain(Box::new(first), rest),
+    }
+}
+
+/// Parses the digits of an integer literal, with any `_` separators.
+fn int_literal(sign: &str, digits: &str, radix: u32) -> Result<Expr, &'static str> {
+    let digits: String = sign
+        .chars()
+        .chain(digits.chars().filter(|c| *c != '_'))
+        .collect();
+
+    match i64::from_str_radix(&digits, radix) {
+        Ok(int) => Ok(Expr::Int(int)),
+        Err(_) => BigInt::parse_bytes(digits.as_bytes(), radix)
+            .map(Expr::BigInt)
+            .ok_or("digits"),
+    }
 }
 
 peg::parser!(
     pub grammar parser() for str {
 
+        // `///` lines are only kept as docs right above a `fn`, anywhere else
+        // they are plain comments
         rule whitespace()
-        = [' '| '\t' | '\n' | '\r' |'\u{A}']
+        = trivia() / !docs() "///" [^'\n']*
+
+        rule trivia()
+        = [' '| '\t' | '\n' | '\r' |'\u{A}'] / line_comment() / block_comment()
+
+        rule docs()
+        = ("///" !"/" [^'\n']* trivia()*)+ "fn" !ident_char()
+
+        // `///` is a doc comment, but `////` is back to a plain comment
+        rule line_comment()
+        = "////" [^'\n']* / "//" !"/" [^'\n']*
+
+        rule block_comment()
+        = "/*" (block_comment() / !"*/" [_])* "*/"
+
+        rule doc_comment() -> &'input str
+        = _ "///" !"/" text:$([^'\n']*) { text.strip_prefix(' ').unwrap_or(text) }
         rule _
         = whitespace()*
         rule __
         = whitespace()+
 
+        rule hex_digit()
+        = quiet!{ ['0'..='9' | 'a'..='f' | 'A'..='F'] } / expected!("hex digit")
+
+        rule escape() -> char
+        = "\\" c:(
+            quiet!{
+                "n" { '\n' } / "r" { '\r' } / "t" { '\t' } / "0" { '\0' }
+                / "\\" { '\\' } / "\"" { '"' } / "'" { '\'' }
+            }
+            / quiet!{ "x" } h:$(hex_digit()*<2>) { char::from(u8::from_str_radix(h, 16).unwrap()) }
+            / quiet!{ "u{" } h:$(hex_digit()*<1,6>) "}" {?
+                u32::from_str_radix(h, 16).ok().and_then(char::from_u32).ok_or("valid unicode code point")
+            }
+            / expected!("valid escape sequence")
+        ) { c }
+
+        rule string_char() -> Piece
+        = c:escape() { Piece::Escaped(c) } / !['"' | '\\'] c:[_] { Piece::Char(c) }
+
+        rule single_quoted_char() -> Piece
+        = c:escape() { Piece::Escaped(c) } / !['\'' | '\\'] c:[_] { Piece::Char(c) }
+
+        rule long_string_char() -> Piece
+        = c:escape() { Piece::Escaped(c) } / !("\"\"\"" / "\\") c:[_] { Piece::Char(c) }
+
         rule string() -> String
-        = quiet!{ "\"" s:(doubleQuotedCharacter()*) "\"" { fix_str(s) }}
+        = quiet!{ "r\"\"\"" } s:(!"\"\"\"" c:[_] { Piece::Char(c) })* "\"\"\"" { text(dedent(s)) }
+        / quiet!{ "r\"" } s:$([^'"']*) "\"" { s.to_owned() }
+        / quiet!{ "\"\"\"" } s:(long_string_char()*) "\"\"\"" { text(dedent(s)) }
+        / quiet!{ "\"" } s:(string_char()*) "\"" { text(s) }
+        / quiet!{ "r'" } s:$([^'\'']*) "'" { s.to_owned() }
+        / quiet!{ "'" } s:(single_quoted_char()*) "'" { text(s) }
         / expected!("string")
 
-        rule doubleQuotedCharacter() -> char
-          = !("\"") c:([_]) { c }
-          / "\\u{" value:$(['0'..='9' | 'a'..='f' | 'A'..='F']+) "}" { char::from_u32(u32::from_str_radix(value, 16).unwrap()).unwrap() }
-          / expected!("valid escape sequence")
+        rule interpolation() -> Piece
+        = "{" _ e:expr() _ spec:(":" s:quiet!{ format_spec() } { s })? "}" {
+            Piece::Expr(match spec {
+                Some(spec) => Expr::Formatted(Box::new(e), spec),
+                None => e,
+            })
+        }
+
+        rule align() -> Align
+        = "<" { Align::Left } / ">" { Align::Right } / "^" { Align::Center }
+
+        rule count() -> usize
+        = n:$(['0'..='9']+) {? n.parse().or(Err("count")) }
+
+        rule format_spec() -> FormatSpec
+        = fill_align:(fill:(c:[^'}'] &align() { c })? align:align() { (fill.unwrap_or(' '), align) })?
+          sign:"+"? alternate:"#"? zero:"0"? width:count()? precision:("." p:count() { p })?
+          kind:(
+            "?" { FormatKind::Debug } / "x" { FormatKind::LowerHex } / "X" { FormatKind::UpperHex }
+            / "b" { FormatKind::Binary } / "o" { FormatKind::Octal }
+          )?
+        {
+            FormatSpec {
+                fill: fill_align.map(|(fill, _)| fill).unwrap_or(' '),
+                align: fill_align.map(|(_, align)| align),
+                sign: sign.is_some(),
+                alternate: alternate.is_some(),
+                zero: zero.is_some(),
+                width: width.unwrap_or(0),
+                precision,
+                kind: kind.unwrap_or(FormatKind::Display),
+            }
+        }
+
+        // `{{` and `}}` are literal braces
+        rule brace() -> Piece
+        = "{{" { Piece::Escaped('{') } / "}}" { Piece::Escaped('}') }
+
+        rule format_char() -> Piece
+        = brace() / interpolation() / c:escape() { Piece::Escaped(c) } / !['"' | '\\' | '{' | '}'] c:[_] { Piece::Char(c) }
 
-        rule doubleQuotedCharacterNoBrac() -> char
-        = !(&"{" / &"}" / "\"") c:([_]) { c }
-        / "\\u{" value:$(['0'..='9' | 'a'..='f' | 'A'..='F']+) "}" { char::from_u32(u32::from_str_radix(value, 16).unwrap()).unwrap() }
-        / expected!("valid escape sequence")
+        rule single_quoted_format_char() -> Piece
+        = brace() / interpolation() / c:escape() { Piece::Escaped(c) } / !['\'' | '\\' | '{' | '}'] c:[_] { Piece::Char(c) }
 
-        rule f() -> Expr
-        = "{" _ e:expr() _ "}" {e} /
-        e:(doubleQuotedCharacterNoBrac()+) {Expr::String(fix_str(e))}
+        rule long_format_char() -> Piece
+        = brace() / interpolation() / c:escape() { Piece::Escaped(c) } / !("\"\"\"" / ['\\' | '{' | '}']) c:[_] { Piece::Char(c) }
 
         rule format_string() -> Expr
-        = "f\"" s:(f() *) "\"" {
-            Expr::FormatString(s)
-        }
+        = "f\"\"\"" s:(long_format_char()*) "\"\"\"" { Expr::FormatString(parts(dedent(s))) }
+        / "f\"" s:(format_char()*) "\"" { Expr::FormatString(parts(s)) }
+        / "f'" s:(single_quoted_format_char()*) "'" { Expr::FormatString(parts(s)) }
 
         rule symbol() -> String
         = quiet!{ _ n:$(['a'..='z' | 'A'..='Z' | '_']['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) _ { n.to_owned() } }
         / expected!("identifier")
 
-        rule integer() -> f64
-        = quiet!{ _ i:$("-"?['0'..='9']+) _ { i.parse().unwrap() } }
+        // words that start a construct, so `if` and `else` are never read as
+        // variables
+        rule keyword()
+        = ("if" / "else" / "match" / "while" / "fn" / "let" / "const" / "return"
+          / "enum" / "import" / "from" / "true" / "false" / "none") !ident_char()
+
+        rule identifier() -> Expr
+        = _ pos:position!() !keyword() n:symbol() { Expr::Identifier(n, pos) }
+
+        rule param() -> (String, Pos)
+        = _ pos:position!() n:symbol() { (n, pos) }
+
+        rule integer() -> Expr
+        = quiet!{ _ sign:$("-"?) i:(
+            "0x" d:$(['0'..='9' | 'a'..='f' | 'A'..='F' | '_']+) { (d, 16) }
+            / "0o" d:$(['0'..='7' | '_']+) { (d, 8) }
+            / "0b" d:$(['0' | '1' | '_']+) { (d, 2) }
+            / d:$(['0'..='9']['0'..='9' | '_']*) { (d, 10) }
+        ) _ {? int_literal(sign, i.0, i.1) } }
         / expected!("integer")
 
         rule float() -> f64
-        = quiet!{ _ i:$("-"?['0'..='9']+ "." !"." ['0'..='9']*) _ { i.parse().unwrap() } }
+        = quiet!{ _ i:$("-"?['0'..='9']['0'..='9' | '_']* "." !"." ['0'..='9' | '_']*) _ { i.replace('_', "").parse().unwrap() } }
         / expected!("float")
 
-        rule number() -> f64
-        = float() / integer()
+        rule number() -> Expr
+        = f:float() { Expr::Number(f) } / integer()
 
 
         // rule index() -> Expr
@@ -158,7 +503,7 @@ peg::parser!(
 
         #[cache_left_rec]
         rule index() -> Expr
-        = n:(i:index(){i} / s:call(){s} / s:string(){Expr::String(s)} / s:format_string(){s} / s:symbol(){Expr::Identifier(s)})
+        = n:(i:index(){i} / s:call(){s} / m:member(){m} / s:string(){Expr::String(s)} / s:format_string(){s} / s:identifier(){s})
         "[" _ i:value() _ "]" { Expr::Index{item: Box::new(n.clone()), index: Box::new(i.clone())} }
 
 
@@ -171,33 +516,77 @@ peg::parser!(
         / expected!("none")
 
         rule function() -> Expr
-        = _ "fn" __ name:symbol() _
-        "(" args:(symbol() ** ",") ")" _
+        = docs:(doc_comment()*) _ "fn" __ name:symbol() _
+        "(" args:(param() ** ",") ")" _
         body:block() _
-        { Expr::Function {name, args, body} }
+        {
+            let doc = if docs.is_empty() { None } else { Some(docs.join("\n")) };
+            Expr::Function {name, args, body, doc}
+        }
 
         rule lambda() -> Expr
-        = _ "(" params:(symbol() ** ",") ")" _ "=>" _
+        = _ "(" params:(param() ** ",") ")" _ "=>" _
         code:(code:block() {code} / n:value() {vec![Expr::Return(Box::new(n))]}) _
         { Expr::Lambda(params, code)}
 
+        #[cache_left_rec]
+        rule member() -> Expr
+        = n:(m:member(){m} / c:call(){c} / i:index(){i} / s:identifier(){s}) "." !"." name:symbol()
+        { Expr::Member(Box::new(n), name) }
+        / n:(m:member(){m} / c:call(){c} / i:index(){i} / s:identifier(){s}) "?." name:symbol()
+        { Expr::OptionalMember(Box::new(n), name) }
+
         #[cache_left_rec]
         rule call() -> Expr
-        = _ name:( c:call() / i:index() / s:symbol(){Expr::Identifier(s)} ) _ "(" args:((_ e:value() _ {e})  ** ",") ")" _
+        = _ name:( c:call() / m:member() / i:index() / s:identifier(){s} ) _ "(" args:((_ e:value() _ {e})  ** ",") ")" _
         { Expr::Call(Box::new(name), args) }
 
         rule native_call() -> Expr
         = _ "#" name:symbol() _ "(" args:((_ e:value() _ {e})  ** ",") ")" _
         { Expr::NativeCall(name, args) }
 
+        rule enum_variant() -> (String, Vec<String>)
+        = name:symbol() fields:("(" f:(symbol() ** ",") ")" _ { f })? { (name, fields.unwrap_or_default()) }
+
+        rule enum_declaration() -> Expr
+        = _ "enum" __ name:symbol() _ "{" _ variants:(enum_variant() ** ("," _)) ","? _ "}" _
+        { Expr::Enum { name, variants } }
+
+        rule ident_char()
+        = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
+
+        rule pattern() -> Pattern
+        = _ "_" !ident_char() _ { Pattern::Wildcard }
+        / _ pos:position!() enum_name:symbol() "." name:symbol() fields:("(" f:(pattern() ** ",") ")" _ { f })?
+        { Pattern::Variant { enum_name, name, fields: fields.unwrap_or_default(), pos } }
+        / _ n:number() _ { Pattern::Literal(n) }
+        / _ s:string() _ { Pattern::Literal(Expr::String(s)) }
+        / _ b:bool() !ident_char() _ { Pattern::Literal(Expr::Bool(b)) }
+        / _ none() !ident_char() _ { Pattern::Literal(Expr::None) }
+        / n:symbol() { Pattern::Binding(n) }
+
+        rule match_arm() -> (Pattern, Vec<Expr>)
+        = _ p:pattern() _ "=>" _ body:(b:block() { b } / v:value() { vec![v] }) _ ","? _ { (p, body) }
+
+        rule _match() -> Expr
+        = _ "match" _ value:value() _ "{" arms:(match_arm()*) _ "}" _
+        { Expr::Match { value: Box::new(value), arms } }
+
         rule _return() -> Expr
         = _ "return" e:(__ e:value() _ { e } / _ { Expr::None }) {Expr::Return(Box::new(e))}
 
+        rule import() -> Expr
+        = _ pos:position!() "import" __ path:string() _ alias:("as" __ a:symbol() { a })? { Expr::Import { path, alias, pos } }
+        / _ pos:position!() "from" __ path:string() _ "import" __ names:(symbol() ++ ",") { Expr::FromImport { path, names, pos } }
+
         rule declaration() -> Expr
         = _ "let" __ name:symbol() _ value:(("=" _ value:value() _ { value }) / { Expr::None }) { Expr::Declaration(name, Box::new(value)) }
 
+        rule constant() -> Expr
+        = _ "const" __ name:symbol() _ "=" _ value:value() _ { Expr::Const(name, Box::new(value)) }
+
         rule assignment() -> Expr
-        = _ name:(i:index(){i} / s:call(){s} / s:symbol(){Expr::Identifier(s)}) _ "=" _ value:value() _ { Expr::Assignment(Box::new(name), Box::new(value)) };
+        = _ pos:position!() name:(i:index(){i} / s:call(){s} / s:identifier(){s}) _ "=" _ value:value() _ { Expr::Assignment(Box::new(name), Box::new(value), pos) };
 
         // #[cache_left_rec]
         // rule join() -> Expr
@@ -216,106 +605,145 @@ peg::parser!(
         rule else_elif() -> Vec<Expr>
         = "else" _ res:(_else() / _elif()) {res}
         rule if_condition() -> Expr
-        = _ "if" _ condition:operation() _ then:block() _ otherwise:(else_elif())? _ {
+        = _ "if" __ "let" __ p:pattern() _ "=" _ value:value() _ then:block() _ otherwise:(else_elif())? _ {
+            Expr::Match { value: Box::new(value), arms: vec![(p, then), (Pattern::Wildcard, otherwise.unwrap_or_default())] }
+        }
+        / _ "if" _ condition:operation() _ then:block() _ otherwise:(else_elif())? _ {
             Expr::If{ condition: Box::new(condition), then, otherwise: otherwise.unwrap_or(vec![])}
         }
 
 
 
+        rule while_loop() -> Expr
+        = _ "while" _ condition:operation() _ body:block() _ {
+            Expr::While { condition: Box::new(condition), body }
+        }
+
         rule item() -> Expr
-        = i:index(){i} / s:call(){s} / s:symbol(){Expr::Identifier(s)}
+        = i:index(){i} / s:call(){s} / m:member(){m} / s:identifier(){s}
 
         #[cache_left_rec]
         rule arithmetic() -> Expr
         = precedence! {
-            _ "(" _ x:arithmetic() _ ")" _ { x }
+            _ "(" _ x:operation() _ ")" _ { x }
+            --
+            _ pos:position!() x:item() _ "++" _ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Add, Box::new(x), Box::new(Expr::Int(1)))), pos)}
+            _ pos:position!() x:item() _ "--" _ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Sub, Box::new(x), Box::new(Expr::Int(1)))), pos)}
+            _ pos:position!() x:item() _ "+=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Add, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "-=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Sub, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "*=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Mul, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "~/=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::FloorDiv, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "/=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Div, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "%=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Mod, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "**=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Pow, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "&=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::BitAnd, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "|=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::BitOr, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "^=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::BitXor, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ "<<=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Shl, Box::new(x), Box::new(y))), pos)}
+            _ pos:position!() x:item() _ ">>=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Shr, Box::new(x), Box::new(y))), pos)}
             --
-            x:item() _ "++" _ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Add, Box::new(x), Box::new(Expr::Number(1.0)))))}
-            x:item() _ "--" _ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Sub, Box::new(x), Box::new(Expr::Number(1.0)))))}
-            x:item() _ "+=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Add, Box::new(x), Box::new(y))))}
-            x:item() _ "-=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Sub, Box::new(x), Box::new(y))))}
-            x:item() _ "*=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Mul, Box::new(x), Box::new(y))))}
-            x:item() _ "/=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Div, Box::new(x), Box::new(y))))}
-            x:item() _ "%=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Mod, Box::new(x), Box::new(y))))}
-            x:item() _ "**=" _ y:@ {Expr::Assignment(Box::new(x.clone()), Box::new(Expr::Op(Op::Pow, Box::new(x), Box::new(y))))}
+            x:(@) _ "|" !"|" _  y:@ {Expr::Op(Op::BitOr, Box::new(x), Box::new(y))}
+            --
+            x:(@) _ "^" _  y:@ {Expr::Op(Op::BitXor, Box::new(x), Box::new(y))}
+            --
+            x:(@) _ "&" !"&" _  y:@ {Expr::Op(Op::BitAnd, Box::new(x), Box::new(y))}
+            --
+            x:(@) _ "<<" _  y:@ {Expr::Op(Op::Shl, Box::new(x), Box::new(y))}
+            x:(@) _ ">>" _  y:@ {Expr::Op(Op::Shr, Box::new(x), Box::new(y))}
             --
             x:(@) _ ".." _  y:@ {Expr::Join(Box::new(x), Box::new(y))}
             x:(@) _ "+" _  y:@ {Expr::Op(Op::Add, Box::new(x), Box::new(y))}
             x:(@) _ "-" _  y:@ {Expr::Op(Op::Sub, Box::new(x), Box::new(y))}
             --
             x:(@) _ "*" _  y:@ {Expr::Op(Op::Mul, Box::new(x), Box::new(y))}
+            x:(@) _ "~/" _  y:@ {Expr::Op(Op::FloorDiv, Box::new(x), Box::new(y))}
             x:(@) _ "/" _  y:@ {Expr::Op(Op::Div, Box::new(x), Box::new(y))}
             x:(@) _ "%" _  y:@ {Expr::Op(Op::Mod, Box::new(x), Box::new(y))}
             --
             x:(@) _ "**" _  y:@ {Expr::Op(Op::Pow, Box::new(x), Box::new(y))}
             --
-            x:operation() { x }
+            "-" _ x:@ { Expr::Op(Op::Neg, Box::new(x), Box::new(Expr::None)) }
+            "~" _ x:@ { Expr::Op(Op::BitNot, Box::new(x), Box::new(Expr::None)) }
+            --
             x:value_end() { x }
-            "-" _ e:arithmetic() { Expr::Op(Op::Neg, Box::new(e), Box::new(Expr::None)) }
         }
 
         #[cache_left_rec]
         rule operation() -> Expr
         = precedence! {
-            "(" _ x:operation() _ ")" _ { x }
+            x:(@) _ "??" _  y:@ { Expr::Op(Op::Coalesce, Box::new(x), Box::new(y)) }
             --
-            x:(@) _ "&&" _  y:@ { Expr::Op(Op::And, Box::new(x), Box::new(y)) }
             x:(@) _ "||" _  y:@ { Expr::Op(Op::Or, Box::new(x), Box::new(y)) }
             --
-            x:(@) _ "==" _  y:@ { Expr::Op(Op::Eq, Box::new(x), Box::new(y)) }
-            x:(@) _ "!=" _  y:@ { Expr::Op(Op::Neq, Box::new(x), Box::new(y)) }
-            --
-            x:(@) _ ">=" _  y:@ { Expr::Op(Op::Gte, Box::new(x), Box::new(y)) }
-            x:(@) _ "<=" _  y:@ { Expr::Op(Op::Lte, Box::new(x), Box::new(y)) }
-            --
-            x:(@) _ "<" _  y:@ { Expr::Op(Op::Lt, Box::new(x), Box::new(y)) }
-            x:(@) _ ">" _  y:@ { Expr::Op(Op::Gt, Box::new(x), Box::new(y)) }
+            x:(@) _ "&&" _  y:@ { Expr::Op(Op::And, Box::new(x), Box::new(y)) }
             --
-            x:arithmetic() { x }
-            x:value_end() { x }
+            x:equality() { x }
+        }
 
-            "!" _  x:operation() {
-                if let Expr::Bool(b) = x {
-                    return Expr::Bool(!b)
-                }
-                Expr::Op(Op::Not, Box::new(x), Box::new(Expr::None))
+        // comparisons chain, `a < b <= c` and `a == b == c` compare each
+        // operand with its neighbours
+        rule equality() -> Expr
+        = first:comparison() rest:(_ op:equality_op() _ y:comparison() { (op, y) })* { chain(first, rest) }
+
+        rule equality_op() -> Op
+        = "==" { Op::Eq } / "!=" { Op::Neq }
+
+        rule comparison() -> Expr
+        = first:unary() rest:(_ op:comparison_op() _ y:unary() { (op, y) })* { chain(first, rest) }
+
+        rule comparison_op() -> Op
+        = "<=" { Op::Lte } / ">=" { Op::Gte } / "<" !"<" { Op::Lt } / ">" !">" { Op::Gt }
+
+        rule unary() -> Expr
+        = "!" _ x:unary() {
+            if let Expr::Bool(b) = x {
+                return Expr::Bool(!b)
             }
+            Expr::Op(Op::Not, Box::new(x), Box::new(Expr::None))
         }
+        / x:arithmetic() { x }
+        / x:value_end() { x }
 
         #[cache_left_rec]
         rule value_end() -> Expr
         = precedence!{
             n:lambda() { n }
+            n:if_condition() { n }
+            n:_match() { n }
+            b:block() { Expr::Block(b) }
             --
             n:index() { n }
             n:native_call() { n }
             c:call() { c }
+            m:member() { m }
             --
             n:bool() { Expr::Bool(n) }
             n:none() { Expr::None }
-            n:number() { Expr::Number(n) }
+            n:number() { n }
             s:format_string() { s }
             s:string() { Expr::String(s) }
-            n:symbol() { Expr::Identifier(n) }
+            n:identifier() { n }
         }
 
         #[cache_left_rec]
         rule value() -> Expr
         = precedence!{
+            n:lambda() { n }
+            --
             n:operation() { n }
             n:arithmetic() { n }
-            n:lambda() { n }
             --
             n:index() { n }
             n:native_call() { n }
             c:call() { c }
+            m:member() { m }
             --
             n:bool() { Expr::Bool(n) }
             n:none() { Expr::None }
-            n:number() { Expr::Number(n) }
+            n:number() { n }
             s:format_string() { s }
             s:string() { Expr::String(s) }
-            n:symbol() { Expr::Identifier(n) }
+            n:identifier() { n }
         }
 
         rule expr() -> Expr
@@ -324,28 +752,34 @@ peg::parser!(
             // --
             n:_return() { n }
             --
+            n:import() { n }
             n:declaration() { n }
+            n:constant() { n }
             --
             n:assignment() { n }
             --
             n:lambda() { n }
             n:function() { n }
+            n:enum_declaration() { n }
             --
-            n:if_condition() { n }
+            n:while_loop() { n }
             --
+            // `if`, `match` and blocks come through here too, so they can
+            // be operands at the start of a statement
             n:operation() { n }
             n:arithmetic() { n }
             --
             n:native_call() { n }
             n:call() { n }
+            n:member() { n }
             n:index() { n }
             --
             n:bool() { Expr::Bool(n) }
             n:none() { Expr::None }
-            n:number() { Expr::Number(n) }
+            n:number() { n }
             s:format_string() { s }
             s:string() { Expr::String(s) }
-            n:symbol() { Expr::Identifier(n) }
+            n:identifier() { n }
         }
 
         rule parse() -> Expr =
@@ -356,3 +790,188 @@ peg::parser!(
 
     }
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(code: &str) -> AST {
+        parser::parse_code(code).unwrap_or_else(|e| panic!("{:?} failed to parse: {}", code, e))
+    }
+
+    fn value(code: &str) -> Expr {
+        let mut ast = parse(code);
+        assert_eq!(ast.len(), 1, "{:?} is not a single expression", code);
+        ast.pop().unwrap()
+    }
+
+    fn op(op: Op, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Op(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(value("1_000"), Expr::Int(1000));
+        assert_eq!(value("0xff"), Expr::Int(255));
+        assert_eq!(value("0o17"), Expr::Int(15));
+        assert_eq!(value("0b1010"), Expr::Int(10));
+        assert_eq!(value("0x7fff_ffff_ffff_ffff"), Expr::Int(i64::MAX));
+        assert_eq!(
+            value("9223372036854775808"),
+            Expr::BigInt(BigInt::from(1u64 << 63))
+        );
+        assert_eq!(value("2.5"), Expr::Number(2.5));
+        assert_eq!(
+            value("7 ~/ 2"),
+            op(Op::FloorDiv, Expr::Int(7), Expr::Int(2))
+        );
+        assert_eq!(
+            value("1 + 2 * 3"),
+            op(
+                Op::Add,
+                Expr::Int(1),
+                op(Op::Mul, Expr::Int(2), Expr::Int(3))
+            )
+        );
+    }
+
+    #[test]
+    fn enums_and_patterns() {
+        assert_eq!(
+            value("enum Shape { Circle(r), Empty }"),
+            Expr::Enum {
+                name: "Shape".into(),
+                variants: vec![
+                    ("Circle".into(), vec!["r".into()]),
+                    ("Empty".into(), vec![])
+                ],
+            }
+        );
+
+        let Expr::Match { arms, .. } = value("match s { Shape.Circle(r) => r, _ => { 0 } }") else {
+            panic!("not a match");
+        };
+        let patterns: Vec<Pattern> = arms.into_iter().map(|(pattern, _)| pattern).collect();
+        assert_eq!(
+            patterns,
+            [
+                Pattern::Variant {
+                    enum_name: "Shape".into(),
+                    name: "Circle".into(),
+                    fields: vec![Pattern::Binding("r".into())],
+                    pos: 10,
+                },
+                Pattern::Wildcard,
+            ]
+        );
+    }
+
+    #[test]
+    fn expression_operands() {
+        let two_or_three = Expr::If {
+            condition: Box::new(Expr::Bool(true)),
+            then: vec![Expr::Int(2)],
+            otherwise: vec![Expr::Int(3)],
+        };
+        assert_eq!(
+            value("1 + if true { 2 } else { 3 }"),
+            op(Op::Add, Expr::Int(1), two_or_three.clone())
+        );
+        assert_eq!(
+            value("if true { 2 } else { 3 } * 4"),
+            op(Op::Mul, two_or_three, Expr::Int(4))
+        );
+        assert_eq!(
+            value("{ 2 } + 1"),
+            op(Op::Add, Expr::Block(vec![Expr::Int(2)]), Expr::Int(1))
+        );
+        assert!(matches!(
+            value("(match 1 { _ => 5 }) - 1"),
+            Expr::Op(Op::Sub, lhs, _) if matches!(*lhs, Expr::Match { .. })
+        ));
+        // keywords are never variables
+        assert!(parser::parse_code("let x = 1 + else").is_err());
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(
+            parse("1 // one\n/* two /* nested */ */ 2"),
+            vec![Expr::Int(1), Expr::Int(2)]
+        );
+
+        let Expr::Function { name, doc, .. } = value("/// Says hi.\n///  twice\nfn hi() {}") else {
+            panic!("not a function");
+        };
+        assert_eq!(name, "hi");
+        assert_eq!(doc.as_deref(), Some("Says hi.\n twice"));
+
+        // `///` that documents no `fn` is a plain comment
+        assert_eq!(parse("7 /// note\n2"), vec![Expr::Int(7), Expr::Int(2)]);
+        assert_eq!(parse("/// stray\n1"), vec![Expr::Int(1)]);
+        let Expr::Function { doc, .. } = value("/// one\n// plain\n/// two\nfn f() {}") else {
+            panic!("not a function");
+        };
+        assert_eq!(doc.as_deref(), Some("one\ntwo"));
+
+        assert_eq!(parse("//========== section\n1"), vec![Expr::Int(1)]);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            value(r#""a\"b\0\x41\u{263A}\\""#),
+            Expr::String("a\"b\0A\u{263A}\\".into())
+        );
+        assert_eq!(value(r#"r"\n""#), Expr::String("\\n".into()));
+        assert_eq!(
+            value("\"\"\"\n    one\n      two\n    \"\"\""),
+            Expr::String("one\n  two".into())
+        );
+        assert_eq!(
+            value(r#"'it\'s "quoted"\t\x41\u{263A}\\'"#),
+            Expr::String("it's \"quoted\"\tA\u{263A}\\".into())
+        );
+        assert_eq!(value(r"r'\n'"), Expr::String("\\n".into()));
+        assert_eq!(
+            value(r#"f'{1}\'{{'"#),
+            Expr::FormatString(vec![Expr::Int(1), Expr::String("'{".into())])
+        );
+        assert!(parser::parse_code(r"'\q'").is_err());
+        assert!(parser::parse_code(r"'unterminated").is_err());
+        assert!(parser::parse_code(r#""\q""#).is_err());
+        assert!(parser::parse_code(r#""trailing\""#).is_err());
+    }
+
+    #[test]
+    fn format_strings() {
+        let spec = FormatSpec {
+            fill: '*',
+            align: Some(Align::Center),
+            width: 9,
+            precision: Some(2),
+            ..FormatSpec::default()
+        };
+        assert_eq!(
+            value(r#"f"x{1:*^9.2}{{""#),
+            Expr::FormatString(vec![
+                Expr::String("x".into()),
+                Expr::Formatted(Box::new(Expr::Int(1)), spec),
+                Expr::String("{".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn comparisons_chain() {
+        let a = || Expr::Identifier("a".into(), 0);
+        assert_eq!(value("a < 2"), op(Op::Lt, a(), Expr::Int(2)));
+        assert_eq!(
+            value("a < 2 <= 3"),
+            Expr::Chain(
+                Box::new(a()),
+                vec![(Op::Lt, Expr::Int(2)), (Op::Lte, Expr::Int(3))]
+            )
+        );
+    }
+}
@@ -0,0 +1,718 @@
+use std::{fmt, rc::Rc};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use super::{import_name, Expr, Pattern, Pos, AST};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub pos: Pos,
+    pub message: String,
+    pub help: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error: {}", self.message)?;
+        if let Some(help) = &self.help {
+            write!(f, "\n  help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum Binding {
+    Variable,
+    Const,
+    Function,
+    // an enum, with how many fields each of its variants has
+    Enum(Rc<Vec<(String, usize)>>),
+    Import,
+    // `import "path" as name`, with what the module exports
+    Module(Rc<FxHashSet<String>>),
+}
+
+struct Scope {
+    names: FxHashMap<String, Binding>,
+    // how many functions deep this scope is, 0 being the module's top level
+    function: usize,
+}
+
+/// Checks every name in `ast` before any code is generated, mirroring the
+/// scoping rules of `Instr::build`. `globals` are the names already visible
+/// to the module (the prelude functions), which can not be reassigned.
+/// `modules` are the exports of the modules it imports, by import path.
+pub fn resolve<'a>(
+    ast: &AST,
+    globals: impl IntoIterator<Item = &'a String>,
+    modules: FxHashMap<String, FxHashSet<String>>,
+) -> Vec<Diagnostic> {
+    let mut resolver = Resolver {
+        scopes: vec![Scope {
+            names: globals
+                .into_iter()
+                .map(|name| (name.clone(), Binding::Function))
+                .collect(),
+            function: 0,
+        }],
+        modules: modules
+            .into_iter()
+            .map(|(path, exports)| (path, Rc::new(exports)))
+            .collect(),
+        diagnostics: vec![],
+    };
+
+    resolver.block(ast);
+    resolver.diagnostics
+}
+
+/// The names declared at the top level of `ast`, which are what it exports
+/// as a module.
+pub fn exports(ast: &AST) -> FxHashSet<String> {
+    let mut names = FxHashSet::default();
+    for expr in ast {
+        match expr {
+            Expr::Declaration(name, _)
+            | Expr::Const(name, _)
+            | Expr::Function { name, .. }
+            | Expr::Enum { name, .. } => {
+                names.insert(name.clone());
+            }
+            Expr::Import { path, alias, .. } => {
+                names.insert(import_name(path, alias.as_deref()));
+            }
+            Expr::FromImport {
+                names: imported, ..
+            } => names.extend(imported.iter().cloned()),
+            _ => {}
+        }
+    }
+    names
+}
+
+struct Resolver {
+    scopes: Vec<Scope>,
+    modules: FxHashMap<String, Rc<FxHashSet<String>>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    fn function(&self) -> usize {
+        self.scopes.last().map(|s| s.function).unwrap_or(0)
+    }
+
+    fn push_scope(&mut self, function: usize) {
+        self.scopes.push(Scope {
+            names: FxHashMap::default(),
+            function,
+        });
+    }
+
+    fn declare(&mut self, name: &str, binding: Binding) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.names.insert(name.to_owned(), binding);
+        }
+    }
+
+    fn scoped(&mut self, code: &[Expr]) {
+        self.push_scope(self.function());
+        self.block(code);
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, code: &[Expr]) {
+        for expr in code {
+            self.expr(expr);
+        }
+    }
+
+    /// Reports `name` if it isn't visible from the current scope, returning
+    /// how it was bound otherwise.
+    fn check(&mut self, name: &str, pos: Pos, undefined: &str) -> Option<Binding> {
+        let current = self.function();
+        let found = self.scopes.iter().rev().find_map(|scope| {
+            scope
+                .names
+                .get(name)
+                .map(|binding| (binding.clone(), scope.function))
+        });
+
+        match found {
+            Some((binding, function)) if function == 0 || function == current => {
+                return Some(binding)
+            }
+            Some(_) => self.diagnostics.push(Diagnostic {
+                pos,
+                message: format!("cannot capture `{}` from an enclosing function", name),
+                help: Some(format!("pass `{}` in as an argument instead", name)),
+            }),
+            None => self.diagnostics.push(Diagnostic {
+                pos,
+                message: format!("{} `{}`", undefined, name),
+                help: self
+                    .suggest(name)
+                    .map(|suggestion| format!("did you mean `{}`?", suggestion)),
+            }),
+        }
+
+        None
+    }
+
+    /// Finds the visible name closest to `name`, if any is close enough.
+    fn suggest(&self, name: &str) -> Option<String> {
+        closest(
+            name,
+            self.scopes.iter().flat_map(|scope| scope.names.keys()),
+        )
+    }
+
+    /// Reports an import anywhere but the top level of a module.
+    fn top_level(&mut self, pos: Pos) {
+        if self.scopes.len() > 1 {
+            self.diagnostics.push(Diagnostic {
+                pos,
+                message: "imports are only allowed at the top level of a module".to_owned(),
+                help: Some("move it out to the top level".to_owned()),
+            });
+        }
+    }
+
+    /// Reports `module.name` when the module doesn't export `name`, and
+    /// `enum_name.name` when the enum has no such variant.
+    fn member(&mut self, item: &Expr, name: &str) {
+        let Expr::Identifier(module, pos) = item else {
+            return self.expr(item);
+        };
+
+        match self.check(module, *pos, "undefined variable") {
+            Some(Binding::Module(exports)) if !exports.contains(name) => {
+                self.diagnostics.push(Diagnostic {
+                    pos: *pos,
+                    message: format!("module `{}` has no export named `{}`", module, name),
+                    help: closest(name, exports.iter())
+                        .map(|suggestion| format!("did you mean `{}`?", suggestion)),
+                });
+            }
+            Some(Binding::Enum(variants)) => {
+                self.variant(module, &variants, name, *pos);
+            }
+            _ => {}
+        }
+    }
+
+    /// Reports `enum_name.name` when the enum has no variant `name`,
+    /// returning whether it has.
+    fn variant(
+        &mut self,
+        enum_name: &str,
+        variants: &[(String, usize)],
+        name: &str,
+        pos: Pos,
+    ) -> bool {
+        if variants.iter().any(|(variant, _)| variant == name) {
+            return true;
+        }
+
+        self.diagnostics.push(Diagnostic {
+            pos,
+            message: format!("enum `{}` has no variant `{}`", enum_name, name),
+            help: closest(name, variants.iter().map(|(variant, _)| variant))
+                .map(|suggestion| format!("did you mean `{}`?", suggestion)),
+        });
+        false
+    }
+
+    /// Reports an assignment to anything that isn't a plain variable.
+    fn reassign(&mut self, name: &str, pos: Pos, binding: Option<Binding>) {
+        let help = match binding {
+            None | Some(Binding::Variable) => return,
+            Some(Binding::Const | Binding::Enum(_)) => "declare it with `let` to make it mutable",
+            Some(Binding::Function) => "functions are constant, shadow it with `let` instead",
+            Some(Binding::Import | Binding::Module(_)) => {
+                "imported names are constant, shadow it with `let` instead"
+            }
+        };
+
+        self.diagnostics.push(Diagnostic {
+            pos,
+            message: format!("cannot assign to constant `{}`", name),
+            help: Some(help.to_owned()),
+        });
+    }
+
+    /// Reports an assignment to something that isn't a name, like `f() = 1`.
+    fn invalid_assignee(&mut self, assignee: &Expr, pos: Pos) {
+        let what = match assignee {
+            Expr::Call(..) => "a function call",
+            Expr::Index { .. } => "an index",
+            Expr::Member(..) | Expr::OptionalMember(..) => "a member",
+            _ => "this expression",
+        };
+
+        self.diagnostics.push(Diagnostic {
+            pos,
+            message: format!("cannot assign to {}", what),
+            help: Some("only variables can be assigned to".to_owned()),
+        });
+    }
+
+    /// Reports `enum_name.name` given another number of fields than the
+    /// variant has, when `enum_name` is an enum declared in this module.
+    /// `call` is whether the fields are passed to it as a constructor. An
+    /// unknown enum or variant has been reported already by then.
+    fn fields(&mut self, enum_name: &str, name: &str, pos: Pos, given: usize, call: bool) {
+        let variants = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.names.get(enum_name));
+        let Some(Binding::Enum(variants)) = variants else {
+            return;
+        };
+        let Some(&(_, fields)) = variants.iter().find(|(variant, _)| variant == name) else {
+            return;
+        };
+
+        let (message, help) = if call && fields == 0 {
+            (
+                format!(
+                    "`{}.{}` has no fields to construct it with",
+                    enum_name, name
+                ),
+                Some(format!("use `{}.{}` without calling it", enum_name, name)),
+            )
+        } else if given != fields {
+            let count = |n| match n {
+                0 => "no fields".to_owned(),
+                1 => "1 field".to_owned(),
+                n => format!("{} fields", n),
+            };
+            (
+                format!(
+                    "`{}.{}` has {} but is given {}",
+                    enum_name,
+                    name,
+                    count(fields),
+                    given
+                ),
+                None,
+            )
+        } else {
+            return;
+        };
+
+        self.diagnostics.push(Diagnostic { pos, message, help });
+    }
+
+    fn params(&mut self, params: &[(String, Pos)]) {
+        let mut seen = FxHashSet::default();
+        for (param, pos) in params {
+            if !seen.insert(param) {
+                self.diagnostics.push(Diagnostic {
+                    pos: *pos,
+                    message: format!("duplicate parameter `{}`", param),
+                    help: None,
+                });
+            }
+            self.declare(param, Binding::Variable);
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Binding(name) => self.declare(name, Binding::Variable),
+            Pattern::Variant {
+                enum_name,
+                name,
+                fields,
+                pos,
+            } => {
+                if let Some(Binding::Enum(variants)) = self.check(enum_name, *pos, "undefined enum")
+                {
+                    if self.variant(enum_name, &variants, name, *pos) {
+                        self.fields(enum_name, name, *pos, fields.len(), false);
+                    }
+                }
+                for field in fields {
+                    self.pattern(field);
+                }
+            }
+            Pattern::Wildcard | Pattern::Literal(_) => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Int(_)
+            | Expr::BigInt(_)
+            | Expr::Number(_)
+            | Expr::String(_)
+            | Expr::Bool(_)
+            | Expr::None => {}
+            Expr::FormatString(parts) => self.block(parts),
+            Expr::Formatted(value, _) => self.expr(value),
+            Expr::Identifier(name, pos) => {
+                self.check(name, *pos, "undefined variable");
+            }
+            Expr::Declaration(name, value) => {
+                self.expr(value);
+                self.declare(name, Binding::Variable);
+            }
+            Expr::Const(name, value) => {
+                self.expr(value);
+                self.declare(name, Binding::Const);
+            }
+            Expr::Import { path, alias, pos } => {
+                self.top_level(*pos);
+                let binding = match self.modules.get(path) {
+                    Some(exports) => Binding::Module(exports.clone()),
+                    None => Binding::Import,
+                };
+                self.declare(&import_name(path, alias.as_deref()), binding);
+            }
+            Expr::FromImport { names, pos, .. } => {
+                self.top_level(*pos);
+                for name in names {
+                    self.declare(name, Binding::Import);
+                }
+            }
+            Expr::Assignment(assignee, value, pos) => {
+                match assignee.as_ref() {
+                    Expr::Identifier(name, pos) => {
+                        let binding = self.check(name, *pos, "assignment to undeclared variable");
+                        self.reassign(name, *pos, binding);
+                    }
+                    assignee => {
+                        self.expr(assignee);
+                        self.invalid_assignee(assignee, *pos);
+                    }
+                }
+                self.expr(value);
+            }
+            Expr::Index { item, index } => {
+                self.expr(item);
+                self.expr(index);
+            }
+            Expr::Slice { item, start, end } => {
+                self.expr(item);
+                self.expr(start);
+                self.expr(end);
+            }
+            Expr::Function {
+                name, args, body, ..
+            } => {
+                self.declare(name, Binding::Function);
+                self.push_scope(self.function() + 1);
+                self.params(args);
+                self.block(body);
+                self.scopes.pop();
+            }
+            Expr::Lambda(args, body) => {
+                self.push_scope(self.function() + 1);
+                self.params(args);
+                self.block(body);
+                self.scopes.pop();
+            }
+            Expr::Enum { name, variants } => {
+                let variants = variants
+                    .iter()
+                    .map(|(variant, fields)| (variant.clone(), fields.len()))
+                    .collect();
+                self.declare(name, Binding::Enum(Rc::new(variants)));
+            }
+            Expr::Member(item, name) | Expr::OptionalMember(item, name) => self.member(item, name),
+            Expr::Call(callee, args) => {
+                self.block(args);
+                self.expr(callee);
+                if let Expr::Member(item, name) = callee.as_ref() {
+                    if let Expr::Identifier(enum_name, pos) = item.as_ref() {
+                        self.fields(enum_name, name, *pos, args.len(), true);
+                    }
+                }
+            }
+            Expr::NativeCall(_, args) => self.block(args),
+            Expr::Join(lhs, rhs) | Expr::Op(_, lhs, rhs) => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Expr::Chain(first, rest) => {
+                self.expr(first);
+                for (_, operand) in rest {
+                    self.expr(operand);
+                }
+            }
+            Expr::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.expr(condition);
+                self.scoped(then);
+                self.scoped(otherwise);
+            }
+            Expr::Match { value, arms } => {
+                self.expr(value);
+                for (pattern, body) in arms {
+                    self.push_scope(self.function());
+                    self.pattern(pattern);
+                    self.block(body);
+                    self.scopes.pop();
+                }
+            }
+            Expr::While { condition, body } => {
+                self.expr(condition);
+                self.scoped(body);
+            }
+            Expr::Block(code) => self.scoped(code),
+            Expr::Return(value) => self.expr(value),
+        }
+    }
+}
+
+/// The candidate closest to `name`, if any is close enough to be a typo.
+/// Names shorter than three characters are one edit away from too many
+/// others for a guess to mean anything, so they get none.
+fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Option<String> {
+    let len = name.chars().count();
+    if len < 3 {
+        return None;
+    }
+    let max = len / 3;
+
+    candidates
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(d, _)| *d <= max)
+        .min_by(|(d1, c1), (d2, c2)| d1.cmp(d2).then_with(|| c1.cmp(c2)))
+        .map(|(_, candidate)| candidate.clone())
+}
+
+/// Edit distance between two names, counting a swap of two adjacent
+/// characters as a single edit.
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::parser;
+
+    fn messages(code: &str) -> Vec<String> {
+        messages_with(code, FxHashMap::default())
+    }
+
+    fn messages_with(code: &str, modules: FxHashMap<String, FxHashSet<String>>) -> Vec<String> {
+        let ast = parser::parse_code(code).unwrap();
+        resolve(&ast, &["print".to_owned()], modules)
+            .into_iter()
+            .map(|d| match d.help {
+                Some(help) => format!("{} ({})", d.message, help),
+                None => d.message,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn undefined_names() {
+        assert_eq!(
+            messages("let count = 1\nprint(coutn)"),
+            ["undefined variable `coutn` (did you mean `count`?)"]
+        );
+        assert_eq!(
+            messages("x = 1\nprint(y)"),
+            [
+                "assignment to undeclared variable `x`",
+                "undefined variable `y`"
+            ]
+        );
+        assert!(messages("let x = 1\nfn f(x) { return x }\nprint(f(x))").is_empty());
+    }
+
+    #[test]
+    fn short_names_get_no_suggestion() {
+        assert_eq!(
+            messages("fn f() {}\nlet ab = 1\nz = 3\nprint(ac)"),
+            [
+                "assignment to undeclared variable `z`",
+                "undefined variable `ac`"
+            ]
+        );
+        assert_eq!(
+            messages("let abc = 1\nprint(abd)"),
+            ["undefined variable `abd` (did you mean `abc`?)"]
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        assert_eq!(
+            messages("{ let inner = 1 }\nprint(inner)"),
+            ["undefined variable `inner`"]
+        );
+        assert_eq!(
+            messages("fn f() { let a = 1\nfn g() { return a } }"),
+            ["cannot capture `a` from an enclosing function (pass `a` in as an argument instead)"]
+        );
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(messages("fn f(a, b, a) {}"), ["duplicate parameter `a`"]);
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(
+            messages("const C = 1\nC = 2\nprint = 3"),
+            [
+                "cannot assign to constant `C` (declare it with `let` to make it mutable)",
+                "cannot assign to constant `print` (functions are constant, shadow it with `let` instead)"
+            ]
+        );
+        assert!(messages("const C = 1\n{ let C = 2\nC = 3 }").is_empty());
+    }
+
+    #[test]
+    fn assignees() {
+        assert_eq!(
+            messages(
+                "fn f() {}\nlet xs = \"ab\"\nf() = 1\nxs[0] = 1\nxs.len += 1\nlet x = 1\nx += 1"
+            ),
+            [
+                "cannot assign to a function call (only variables can be assigned to)",
+                "cannot assign to an index (only variables can be assigned to)",
+                "cannot assign to a member (only variables can be assigned to)"
+            ]
+        );
+
+        let ast = parser::parse_code("let x = 1\n  xs[0] = 1").unwrap();
+        let diagnostics = resolve(&ast, &[], FxHashMap::default());
+        assert_eq!(diagnostics[1].pos, "let x = 1\n  ".len());
+    }
+
+    #[test]
+    fn variant_fields() {
+        let shape = "enum Shape { Circle(r), Rect(w, h), Empty }\n";
+        assert_eq!(
+            messages(&format!(
+                "{}Shape.Rect(1)\nShape.Circle(1, 2)\nShape.Empty()",
+                shape
+            )),
+            [
+                "`Shape.Rect` has 2 fields but is given 1",
+                "`Shape.Circle` has 1 field but is given 2",
+                "`Shape.Empty` has no fields to construct it with (use `Shape.Empty` without calling it)"
+            ]
+        );
+        assert_eq!(
+            messages(&format!(
+                "{}match Shape.Empty {{ Shape.Rect(w) => w, Shape.Empty(e) => e, _ => 0 }}",
+                shape
+            )),
+            [
+                "`Shape.Rect` has 2 fields but is given 1",
+                "`Shape.Empty` has no fields but is given 1"
+            ]
+        );
+        assert!(messages(&format!(
+            "{}if let Shape.Rect(w, _) = Shape.Rect(1, 2) {{ print(w) }}",
+            shape
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn unknown_variants() {
+        let shape = "enum Shape { Circle(r), Rect(w, h), Empty }\n";
+        assert_eq!(
+            messages(&format!(
+                "{}match Shape.Empty {{ Shpae.Rect(w, h) => w, Nope.X(v) => v, Shape.Rcet => 0 }}",
+                shape
+            )),
+            [
+                "undefined enum `Shpae` (did you mean `Shape`?)",
+                "undefined enum `Nope`",
+                "enum `Shape` has no variant `Rcet` (did you mean `Rect`?)"
+            ]
+        );
+        assert_eq!(
+            messages(&format!(
+                "{}print(Shape.Square(1))
+print(Shape.Emtpy)",
+                shape
+            )),
+            [
+                "enum `Shape` has no variant `Square`",
+                "enum `Shape` has no variant `Emtpy` (did you mean `Empty`?)"
+            ]
+        );
+    }
+
+    #[test]
+    fn imports() {
+        assert_eq!(
+            messages("fn f() { import \"utils.rv\" }\nif true { from \"utils.rv\" import area }"),
+            [
+                "imports are only allowed at the top level of a module (move it out to the top level)",
+                "imports are only allowed at the top level of a module (move it out to the top level)"
+            ]
+        );
+
+        let exports = ["area", "UNIT"].map(str::to_owned).into_iter().collect();
+        let modules = [("lib/utils.rv".to_owned(), exports)].into_iter().collect();
+        assert_eq!(
+            messages_with(
+                "import \"lib/utils.rv\"\nprint(utils.UNIT)\nprint(utils.aera)\nprint(utils?.missing)\nlet utils = 1\nprint(utils.missing)",
+                modules
+            ),
+            [
+                "module `utils` has no export named `aera` (did you mean `area`?)",
+                "module `utils` has no export named `missing`"
+            ]
+        );
+    }
+
+    #[test]
+    fn module_exports() {
+        let ast = parser::parse_code(
+            "let a = 1\nconst B = 2\nfn c() {}\nenum D { E }\nimport \"lib/f.rv\"\nimport \"g.rv\" as h\nfrom \"i.rv\" import j, k\n{ let hidden = 1 }",
+        )
+        .unwrap();
+        let mut names: Vec<String> = exports(&ast).into_iter().collect();
+        names.sort();
+        assert_eq!(names, ["B", "D", "a", "c", "f", "h", "j", "k"]);
+    }
+
+    #[test]
+    fn edit_distance() {
+        assert_eq!(distance("count", "count"), 0);
+        assert_eq!(distance("count", "coutn"), 1);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+}
@@ -1,36 +1,198 @@
-use super::{Expr, Node, AST};
+use fxhash::FxHashMap;
 
-fn optimize_ast(ast: AST, initial: bool) -> AST {
-    ast.into_iter().filter_map(|n| pass(n, initial)).collect()
+use super::{import_name, Expr, Node, Pattern, AST};
+
+/// Names visible in each scope, mapped to the literal they stand for when
+/// they are a constant that can be inlined.
+type Consts = Vec<FxHashMap<String, Option<Expr>>>;
+
+pub fn optimize(ast: AST) -> AST {
+    let mut consts = vec![FxHashMap::default()];
+    optimize_ast(ast, true, &mut consts)
+}
+
+fn optimize_ast(ast: AST, initial: bool, consts: &mut Consts) -> AST {
+    // the last expression is kept, it is the value of its block
+    let last = ast.len().saturating_sub(1);
+    ast.into_iter()
+        .enumerate()
+        .filter_map(|(i, n)| pass(n, initial && i != last, consts))
+        .collect()
+}
+
+fn scoped(ast: AST, consts: &mut Consts, names: Vec<String>) -> AST {
+    consts.push(names.into_iter().map(|name| (name, None)).collect());
+    let ast = optimize_ast(ast, true, consts);
+    consts.pop();
+    ast
+}
+
+fn bind(consts: &mut Consts, name: &str, value: Option<Expr>) {
+    if let Some(scope) = consts.last_mut() {
+        scope.insert(name.to_owned(), value);
+    }
+}
+
+fn lookup(consts: &Consts, name: &str) -> Option<Expr> {
+    consts
+        .iter()
+        .rev()
+        .find_map(|scope| scope.get(name))
+        .cloned()
+        .flatten()
+}
+
+fn is_literal(node: &Node) -> bool {
+    matches!(
+        node,
+        Expr::Int(_)
+            | Expr::BigInt(_)
+            | Expr::Number(_)
+            | Expr::String(_)
+            | Expr::Bool(_)
+            | Expr::None
+    )
 }
 
-fn pass(node: Node, initial: bool) -> Option<Node> {
+fn bindings(pattern: &Pattern, names: &mut Vec<String>) {
+    match pattern {
+        Pattern::Binding(name) => names.push(name.clone()),
+        Pattern::Variant { fields, .. } => {
+            for field in fields {
+                bindings(field, names);
+            }
+        }
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+    }
+}
+
+macro_rules! pass {
+    ($node:expr, $consts:expr) => {
+        Box::new(pass(*$node, false, $consts).unwrap())
+    };
+}
+
+fn pass(node: Node, initial: bool, consts: &mut Consts) -> Option<Node> {
     match node {
-        Expr::Declaration(_, _) => todo!(),
-        Expr::Assignment(_, _) => todo!(),
-        Expr::Index { item, index } => todo!(),
-        // Expr::Slice { item, start, end } => todo!(),
-        Expr::Function { name, args, body } => todo!(),
-        Expr::Lambda(_, _) => todo!(),
-        Expr::Call(_, _) => todo!(),
-        Expr::NativeCall(_, _) => todo!(),
-        Expr::Join(_, _) => todo!(),
-        Expr::Op(_, _, _) => todo!(),
+        Expr::Declaration(name, value) => {
+            let value = pass!(value, consts);
+            bind(consts, &name, None);
+            Some(Expr::Declaration(name, value))
+        }
+        Expr::Const(name, value) => {
+            let value = pass!(value, consts);
+            let literal = Some(*value.clone()).filter(is_literal);
+            bind(consts, &name, literal);
+            Some(Expr::Const(name, value))
+        }
+        Expr::Import { path, alias, pos } => {
+            bind(consts, &import_name(&path, alias.as_deref()), None);
+            Some(Expr::Import { path, alias, pos })
+        }
+        Expr::FromImport { path, names, pos } => {
+            for name in &names {
+                bind(consts, name, None);
+            }
+            Some(Expr::FromImport { path, names, pos })
+        }
+        // the assignee is never a constant, the resolver rejects that
+        Expr::Assignment(assignee, value, pos) => {
+            let assignee = match *assignee {
+                Expr::Identifier(name, pos) => Box::new(Expr::Identifier(name, pos)),
+                assignee => pass!(Box::new(assignee), consts),
+            };
+            Some(Expr::Assignment(assignee, pass!(value, consts), pos))
+        }
+        Expr::Index { item, index } => Some(Expr::Index {
+            item: pass!(item, consts),
+            index: pass!(index, consts),
+        }),
+        Expr::Slice { item, start, end } => Some(Expr::Slice {
+            item: pass!(item, consts),
+            start: pass!(start, consts),
+            end: pass!(end, consts),
+        }),
+        Expr::Function {
+            name,
+            args,
+            body,
+            doc,
+        } => {
+            bind(consts, &name, None);
+            let names = args.iter().map(|(arg, _)| arg.clone()).collect();
+            let body = scoped(body, consts, names);
+            Some(Expr::Function {
+                name,
+                args,
+                body,
+                doc,
+            })
+        }
+        Expr::Lambda(args, body) => {
+            let names = args.iter().map(|(arg, _)| arg.clone()).collect();
+            let body = scoped(body, consts, names);
+            Some(Expr::Lambda(args, body))
+        }
+        Expr::Enum { name, variants } => {
+            bind(consts, &name, None);
+            Some(Expr::Enum { name, variants })
+        }
+        Expr::Member(item, name) => Some(Expr::Member(pass!(item, consts), name)),
+        Expr::OptionalMember(item, name) => Some(Expr::OptionalMember(pass!(item, consts), name)),
+        Expr::Call(callee, args) => Some(Expr::Call(
+            pass!(callee, consts),
+            optimize_ast(args, false, consts),
+        )),
+        Expr::NativeCall(name, args) => {
+            Some(Expr::NativeCall(name, optimize_ast(args, false, consts)))
+        }
+        Expr::Join(lhs, rhs) => Some(Expr::Join(pass!(lhs, consts), pass!(rhs, consts))),
+        Expr::Op(op, lhs, rhs) => Some(Expr::Op(op, pass!(lhs, consts), pass!(rhs, consts))),
+        Expr::Chain(first, rest) => Some(Expr::Chain(
+            pass!(first, consts),
+            rest.into_iter()
+                .map(|(op, operand)| (op, pass(operand, false, consts).unwrap()))
+                .collect(),
+        )),
         Expr::If {
             condition,
             then,
             otherwise,
-        } => todo!(),
-        Expr::Return(r) => Some(Expr::Return(Box::new(pass(*r, false).unwrap()))),
-        Expr::FormatString(nodes) => Some(Expr::FormatString(optimize_ast(nodes, false))),
+        } => Some(Expr::If {
+            condition: pass!(condition, consts),
+            then: scoped(then, consts, vec![]),
+            otherwise: scoped(otherwise, consts, vec![]),
+        }),
+        Expr::Match { value, arms } => Some(Expr::Match {
+            value: pass!(value, consts),
+            arms: arms
+                .into_iter()
+                .map(|(pattern, body)| {
+                    let mut names = vec![];
+                    bindings(&pattern, &mut names);
+                    (pattern, scoped(body, consts, names))
+                })
+                .collect(),
+        }),
+        Expr::While { condition, body } => Some(Expr::While {
+            condition: pass!(condition, consts),
+            body: scoped(body, consts, vec![]),
+        }),
+        Expr::Block(code) => Some(Expr::Block(scoped(code, consts, vec![]))),
+        Expr::Return(r) => Some(Expr::Return(pass!(r, consts))),
+        Expr::FormatString(nodes) => Some(Expr::FormatString(optimize_ast(nodes, false, consts))),
+        Expr::Formatted(value, spec) => Some(Expr::Formatted(pass!(value, consts), spec)),
 
-        Expr::Identifier(_) => if_initial(node, initial),
+        Expr::Identifier(name, pos) => match lookup(consts, &name) {
+            Some(literal) => if_initial(literal, initial),
+            None => if_initial(Expr::Identifier(name, pos), initial),
+        },
+        Expr::Int(_) => if_initial(node, initial),
+        Expr::BigInt(_) => if_initial(node, initial),
         Expr::Number(_) => if_initial(node, initial),
         Expr::String(_) => if_initial(node, initial),
         Expr::Bool(_) => if_initial(node, initial),
         Expr::None => if_initial(node, initial),
-
-        _ => Some(node),
     }
 }
 
@@ -41,3 +203,40 @@ fn if_initial(node: Node, initial: bool) -> Option<Node> {
         Some(node)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::parser;
+
+    #[test]
+    fn inlines_constants() {
+        let ast =
+            optimize(parser::parse_code("const C = 2\nprint(C)\nlet c = 3\nprint(c)").unwrap());
+        let args: Vec<&Expr> = ast
+            .iter()
+            .filter_map(|expr| match expr {
+                Expr::Call(_, args) => args.first(),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(args[0], &Expr::Int(2));
+        assert!(matches!(args[1], Expr::Identifier(name, _) if name == "c"));
+    }
+
+    #[test]
+    fn shadowing_stops_inlining() {
+        let ast = optimize(
+            parser::parse_code("const C = 2\nfn f(C) { return C }\n{ let C = 3\nprint(C) }")
+                .unwrap(),
+        );
+        let Expr::Block(block) = &ast[2] else {
+            panic!("block was optimized away");
+        };
+        let Expr::Call(_, args) = &block[1] else {
+            panic!("not a call");
+        };
+        assert!(matches!(&args[0], Expr::Identifier(name, _) if name == "C"));
+    }
+}
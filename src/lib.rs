@@ -1,3 +1,22 @@
 pub mod backend;
 pub mod frontend;
 pub mod stdlib;
+
+/// What `glass --version` prints, along with the optional features this
+/// build has, so the corpus can tell a `main` built without them.
+pub fn version() -> String {
+    let features: &[&str] = &[
+        #[cfg(feature = "jit")]
+        "jit",
+        #[cfg(feature = "wasm")]
+        "wasm",
+    ];
+    match features {
+        [] => format!("glass {}", env!("CARGO_PKG_VERSION")),
+        features => format!(
+            "glass {} ({})",
+            env!("CARGO_PKG_VERSION"),
+            features.join(", ")
+        ),
+    }
+}
@@ -1,17 +1,43 @@
-use std::fs::read_to_string;
+use std::borrow::Cow;
 use std::io::Write;
-use std::path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use fxhash::FxHashMap;
 
-use crate::backend::instruction::Instr;
-use crate::backend::stack::StackValue;
+use crate::backend::instruction::{Instr, Program};
+use crate::backend::memory::Stats;
 use crate::backend::{instruction::Type, vm::VM};
-use crate::frontend::parser;
+use crate::frontend::{parser, AST};
 
+/// What a native function runs against, either of the VMs.
+pub trait Host {
+    /// The next argument.
+    fn arg(&mut self) -> Cow<'_, Type>;
+
+    /// Collects garbage now, returning how many objects were freed.
+    fn collect(&mut self) -> usize;
+
+    fn gc_stats(&self) -> Stats;
+}
+
+impl Host for VM {
+    fn arg(&mut self) -> Cow<'_, Type> {
+        Cow::Owned(self.pop_stack())
+    }
+
+    fn collect(&mut self) -> usize {
+        self.heap.collect(self.stack.values())
+    }
+
+    fn gc_stats(&self) -> Stats {
+        self.heap.stats()
+    }
+}
+
+// `$vm` names the host in the bodies, for functions that need more than
+// their arguments
 macro_rules! native {
-    ($(fn $name:ident ( $($args:ident)*  ) $func:block)*) => {
+    ($vm:ident; $(fn $name:ident ( $($args:ident)*  ) $func:block)*) => {
         #[allow(non_camel_case_types)]
         #[derive(Debug, Clone, PartialEq)]
         pub enum NativeFunction {
@@ -32,50 +58,62 @@ macro_rules! native {
                 }
             }
 
-            pub fn call(&self, vm: &mut VM) {
-                let result = match self {
+            /// Runs the function, taking its arguments from `vm` in order.
+            pub fn call(&self, $vm: &mut impl Host) -> Type {
+                match self {
                     $(NativeFunction::$name => {
-                        $(let $args = vm.pop_stack();
+                        $(let $args = $vm.arg();
                         let $args = $args.as_ref();)*
 
                         $func
                     },)*
-                };
-                vm.stack.push(StackValue::Literal(result));
+                }
             }
         }
     };
 }
 
-native!(
+native!(vm;
     fn stdout(_str) {
-        print!("{}", _str.to_string());
+        print!("{}", _str);
         Type::None
     }
 
     fn stdin() {
         let mut input = String::new();
-        std::io::stdin().read_line(&mut input).ok().expect("Failed to read line");
-        Type::String(input)
+        std::io::stdin().read_line(&mut input).expect("Failed to read line");
+        Type::String(input.into())
     }
 
     fn flushout() {
-        std::io::stdout().flush().ok().expect("Failed to flush stdout");
+        std::io::stdout().flush().expect("Failed to flush stdout");
         Type::None
     }
 
     fn time() {
-        Type::Number(SystemTime::now().duration_since(UNIX_EPOCH).expect("Failed to get time").as_millis() as f64)
+        Type::Int(SystemTime::now().duration_since(UNIX_EPOCH).expect("Failed to get time").as_millis() as i64)
+    }
+
+    fn gc() {
+        Type::Int(vm.collect() as i64)
+    }
+
+    fn gc_stats() {
+        Type::String(vm.gc_stats().to_string().into())
     }
 );
 
 pub fn add_std(
-    program: &mut Vec<Instr>,
+    program: &mut Program,
     state: &mut FxHashMap<String, (usize, usize)>,
     depth: usize,
     next: &mut usize,
 ) {
-    let code = read_to_string(path::Path::new("src/stdlib/std.rv")).unwrap();
-    let ast = parser::parse_code(&code).unwrap();
-    Instr::iter_build(program, ast, state, depth, next);
+    Instr::iter_build(program, prelude(), state, depth, next);
+}
+
+/// The parsed code of `std.rv`, which every program starts with. It is built
+/// into the binary, so programs run from anywhere.
+pub fn prelude() -> AST {
+    parser::parse_code(include_str!("std.rv")).unwrap()
 }